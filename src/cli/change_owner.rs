@@ -0,0 +1,73 @@
+//! The `change-owner` sub-command.
+
+use std::path::PathBuf;
+
+use ckb_types::prelude::*;
+use clap::Parser;
+
+use crate::{
+    components::Storage,
+    prelude::*,
+    result::{Error, Result},
+};
+
+#[derive(Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    pub(crate) common: super::CommonArgs,
+
+    /// The directory, which stores all cached data.
+    #[arg(long)]
+    pub(crate) data_dir: PathBuf,
+
+    #[clap(flatten)]
+    pub(crate) ckb: super::CkbRoArgs,
+
+    #[clap(flatten)]
+    pub(crate) spv_owner: super::SpvOwner,
+}
+
+impl Args {
+    pub fn execute(&self) -> Result<()> {
+        log::info!("Change the owner of Bitcoin SPV cells");
+
+        self.spv_owner.check_network(self.ckb.network)?;
+
+        let storage = Storage::new(&self.data_dir)?;
+        if !storage.is_initialized()? {
+            let msg = format!(
+                "user-provided data directory \"{}\" is empty, please initialize it",
+                self.data_dir.display()
+            );
+            return Err(Error::other(msg));
+        }
+
+        let new_lock = self.spv_owner.lock_script();
+        if let Some(old_lock) = storage.spv_owner_lock()? {
+            if old_lock.as_slice() == new_lock.as_slice() {
+                log::info!("The owner is not changed");
+                return Ok(());
+            }
+        }
+
+        let spv_type_script = storage.spv_contract_type_script()?;
+        let instance = self.ckb.client().find_spv_cells(spv_type_script)?;
+        let total = instance.clients.len() + 1;
+        let remaining = instance
+            .clients
+            .values()
+            .map(|client| &client.cell)
+            .chain([&instance.info.cell])
+            .filter(|cell| cell.output.lock().as_slice() != new_lock.as_slice())
+            .count();
+
+        storage.save_spv_owner_lock(new_lock)?;
+        log::info!(
+            "The new owner {} is saved, {remaining} of {total} SPV cells will be rebuilt \
+            under it when they are updated by the `serve` service",
+            self.spv_owner.as_ref()
+        );
+
+        Ok(())
+    }
+}
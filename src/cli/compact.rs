@@ -0,0 +1,41 @@
+//! The `compact` sub-command.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crate::{
+    components::Storage,
+    prelude::*,
+    result::{Error, Result},
+};
+
+#[derive(Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    pub(crate) common: super::CommonArgs,
+
+    /// The directory, which stores all cached data.
+    #[arg(long)]
+    pub(crate) data_dir: PathBuf,
+}
+
+impl Args {
+    pub fn execute(&self) -> Result<()> {
+        log::info!("Compact the local storage");
+
+        let storage = Storage::new(&self.data_dir)?;
+        if !storage.is_initialized()? {
+            let msg = format!(
+                "user-provided data directory \"{}\" is empty, please initialize it",
+                self.data_dir.display()
+            );
+            return Err(Error::other(msg));
+        }
+
+        storage.compact()?;
+        log::info!("The local storage is compacted");
+
+        Ok(())
+    }
+}
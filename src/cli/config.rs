@@ -0,0 +1,126 @@
+//! Load arguments of the `serve` sub-command from a config file.
+//!
+//! The config file is a TOML table, its keys are the long names of arguments,
+//! e.g.:
+//!
+//! ```toml
+//! ckb-endpoint = "https://testnet.ckb.dev"
+//! bitcoin-endpoint = ["http://127.0.0.1:18332", "http://127.0.0.1:28332"]
+//! listen-address = "127.0.0.1:8080"
+//! reorg-require-more-work = true
+//! ```
+//!
+//! Arguments in the command line override the same ones in the config file.
+//! The private key file is never loaded from the config file, it should be
+//! provided by `--key-file` or `--key-env` in the command line.
+
+use std::{ffi::OsString, fs, path::PathBuf};
+
+use toml::{value::Table, Value};
+
+use crate::result::{Error, Result};
+
+const CONFIG_ARG: &str = "--config";
+const SUB_COMMAND: &str = "serve";
+// The arguments which are refused in the config file, for safety.
+const REFUSED_ARGS: &[&str] = &["key-file"];
+
+/// Inserts the arguments from the config file, if it's provided, right after
+/// the sub-command, so the arguments in the command line are parsed later.
+pub(crate) fn expand_args(args: Vec<OsString>) -> Result<Vec<OsString>> {
+    if args.get(1).map(|arg| arg == SUB_COMMAND) != Some(true) {
+        return Ok(args);
+    }
+    let path = match find_config_path(&args[2..]) {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+    let content = fs::read_to_string(&path).map_err(|err| {
+        let msg = format!(
+            "failed to read the config file \"{}\" since {err}",
+            path.display()
+        );
+        Error::cli(msg)
+    })?;
+    let table: Table = toml::from_str(&content).map_err(|err| {
+        let msg = format!(
+            "failed to parse the config file \"{}\" since {err}",
+            path.display()
+        );
+        Error::cli(msg)
+    })?;
+
+    let mut config_args = Vec::new();
+    for (key, value) in table {
+        let name = key.replace('_', "-");
+        if REFUSED_ARGS.contains(&name.as_str()) {
+            let msg =
+                format!("\"{key}\" is not allowed in the config file, use `--{name}` instead");
+            return Err(Error::cli(msg));
+        }
+        let flag = format!("--{name}");
+        if is_provided(&args[2..], &flag) {
+            continue;
+        }
+        match value {
+            Value::Boolean(true) => config_args.push(OsString::from(&flag)),
+            Value::Boolean(false) => {}
+            Value::Array(items) => {
+                for item in items {
+                    config_args.push(OsString::from(&flag));
+                    config_args.push(OsString::from(scalar_to_string(&key, item)?));
+                }
+            }
+            value => {
+                config_args.push(OsString::from(&flag));
+                config_args.push(OsString::from(scalar_to_string(&key, value)?));
+            }
+        }
+    }
+
+    let mut expanded = Vec::with_capacity(args.len() + config_args.len());
+    let mut args = args.into_iter();
+    expanded.extend(args.by_ref().take(2));
+    expanded.extend(config_args);
+    expanded.extend(args);
+    Ok(expanded)
+}
+
+fn find_config_path(args: &[OsString]) -> Option<PathBuf> {
+    let prefix = format!("{CONFIG_ARG}=");
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == CONFIG_ARG {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix(&prefix)) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+fn is_provided(args: &[OsString], flag: &str) -> bool {
+    let prefix = format!("{flag}=");
+    args.iter().any(|arg| {
+        arg == flag
+            || arg
+                .to_str()
+                .map(|arg| arg.starts_with(&prefix))
+                .unwrap_or(false)
+    })
+}
+
+fn scalar_to_string(key: &str, value: Value) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Float(value) => Ok(value.to_string()),
+        Value::Boolean(value) => Ok(value.to_string()),
+        Value::Datetime(value) => Ok(value.to_string()),
+        Value::Array(_) | Value::Table(_) => {
+            let msg = format!("the value of \"{key}\" in the config file should be a scalar");
+            Err(Error::cli(msg))
+        }
+    }
+}
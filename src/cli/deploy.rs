@@ -6,17 +6,13 @@ use ckb_sdk::{
     transaction::{
         builder::{CkbTransactionBuilder, SimpleTransactionBuilder},
         input::InputIterator,
-        signer::{SignContexts, TransactionSigner},
         TransactionBuilderConfiguration,
     },
-    types::{
-        Address as CkbAddress, AddressPayload as CkbAddressPayload, HumanCapacity, NetworkInfo,
-    },
-    ScriptId, SECP256K1,
+    types::{Address as CkbAddress, HumanCapacity, NetworkInfo},
+    ScriptId,
 };
 use ckb_types::{bytes::Bytes, core::Capacity, packed, prelude::*};
 use clap::Parser;
-use secp256k1::SecretKey;
 
 use crate::{
     prelude::*,
@@ -60,6 +56,8 @@ impl Args {
             let msg = "The input addresses and the selected network are not matched";
             return Err(Error::Cli(msg.to_owned()));
         }
+        self.ckb.check_fee_rate_limits()?;
+        self.ckb.confirm_network()?;
 
         let contract_data_capacity = Capacity::bytes(self.contract_data.len()).map_err(|err| {
             let msg = format!("failed to calculate the capacity for contract data since {err}");
@@ -92,14 +90,9 @@ impl Args {
             Error::other(msg)
         })?;
 
-        let (deployer, deployer_key) = SecretKey::from_slice(&self.ckb.private_key.as_ref()[..])
-            .map(|sk| {
-                let pk = sk.public_key(&SECP256K1);
-                let payload = CkbAddressPayload::from_pubkey(&pk);
-                let address = CkbAddress::new(self.ckb.network, payload, true);
-                (address, sk)
-            })?;
+        let (deployer, deployer_signer) = self.ckb.deployer()?;
         log::info!("The contract deployer is {deployer}");
+        let deployer_lock: packed::Script = (&deployer).into();
 
         let iterator = InputIterator::new_with_address(&[deployer], &network_info);
         let mut builder = SimpleTransactionBuilder::new(configuration, iterator);
@@ -109,10 +102,7 @@ impl Args {
 
         let mut tx_with_groups = builder.build(&Default::default())?;
 
-        TransactionSigner::new(&network_info).sign_transaction(
-            &mut tx_with_groups,
-            &SignContexts::new_sighash(vec![deployer_key]),
-        )?;
+        deployer_signer.sign_transaction(&mut tx_with_groups, &deployer_lock)?;
 
         let tx_json = TransactionView::from(tx_with_groups.get_tx_view().clone());
 
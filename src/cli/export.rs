@@ -0,0 +1,119 @@
+//! The `export` sub-command.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write as _},
+    path::PathBuf,
+};
+
+use bitcoin::consensus::serialize;
+use ckb_types::prelude::*;
+use clap::Parser;
+
+use crate::{
+    components::Storage,
+    prelude::*,
+    result::{Error, Result},
+};
+
+// The format of the exported file:
+// - The magic bytes, which include the version of the format.
+// - The base height and the tip height.
+// - The type script and the cell dep of the Bitcoin SPV contract,
+//   each of them is prefixed with its length.
+// - All headers from the base height to the tip height, each of them is
+//   its height and its 80-byte consensus encoding.
+//
+// All integers are in big-endian.
+pub(crate) const EXPORT_MAGIC: &[u8; 8] = b"BTCSPVH\x01";
+pub(crate) const HEADER_SIZE: usize = 80;
+
+#[derive(Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    pub(crate) common: super::CommonArgs,
+
+    /// The directory, which stores all cached data.
+    #[arg(long)]
+    pub(crate) data_dir: PathBuf,
+
+    /// The file to export the headers into, it will be overwritten if it exists.
+    #[arg(long)]
+    pub(crate) output: PathBuf,
+}
+
+impl Args {
+    pub fn execute(&self) -> Result<()> {
+        log::info!("Export all headers in the local storage");
+
+        let storage = Storage::new(&self.data_dir)?;
+        if !storage.is_initialized()? {
+            let msg = format!(
+                "user-provided data directory \"{}\" is empty, please initialize it",
+                self.data_dir.display()
+            );
+            return Err(Error::other(msg));
+        }
+
+        let (base_height, _) = storage.base_state()?;
+        let (tip_height, _) = storage.tip_state()?;
+        // The base header is never pruned, so check the header next to it.
+        if storage.is_bitcoin_header_pruned(base_height + 1)? {
+            let msg = "the storage is pruned (by `serve --prune-keep-depth`), \
+                it can't be exported since the pruned headers are deleted";
+            return Err(Error::other(msg));
+        }
+        let spv_type_script = storage.spv_contract_type_script()?;
+        let spv_cell_dep = storage.spv_contract_cell_dep()?;
+
+        let file = File::create(&self.output).map_err(|err| {
+            let msg = format!(
+                "failed to create the file \"{}\" since {err}",
+                self.output.display()
+            );
+            Error::other(msg)
+        })?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(EXPORT_MAGIC).map_err(Error::other)?;
+        writer
+            .write_all(&base_height.to_be_bytes())
+            .map_err(Error::other)?;
+        writer
+            .write_all(&tip_height.to_be_bytes())
+            .map_err(Error::other)?;
+        for data in [spv_type_script.as_slice(), spv_cell_dep.as_slice()] {
+            writer
+                .write_all(&(data.len() as u32).to_be_bytes())
+                .map_err(Error::other)?;
+            writer.write_all(data).map_err(Error::other)?;
+        }
+
+        let mut expected_height = base_height;
+        for result in storage.iter_bitcoin_headers()? {
+            let (height, header) = result?;
+            if height != expected_height {
+                let msg = format!("the header#{expected_height} is missing in the storage");
+                return Err(Error::other(msg));
+            }
+            writer
+                .write_all(&height.to_be_bytes())
+                .map_err(Error::other)?;
+            writer
+                .write_all(&serialize(&header))
+                .map_err(Error::other)?;
+            expected_height += 1;
+        }
+        if expected_height != tip_height + 1 {
+            let msg = format!("the header#{expected_height} is missing in the storage");
+            return Err(Error::other(msg));
+        }
+        writer.flush().map_err(Error::other)?;
+
+        log::info!(
+            "Exported headers from header#{base_height} to header#{tip_height} into \"{}\"",
+            self.output.display()
+        );
+
+        Ok(())
+    }
+}
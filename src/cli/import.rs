@@ -0,0 +1,153 @@
+//! The `import` sub-command.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::PathBuf,
+};
+
+use bitcoin::consensus::deserialize;
+use ckb_bitcoin_spv_verifier::types::core::Header;
+use ckb_types::{
+    packed::{CellDep, Script},
+    prelude::*,
+};
+use clap::Parser;
+
+use super::export::{EXPORT_MAGIC, HEADER_SIZE};
+use crate::{
+    components::Storage,
+    prelude::*,
+    result::{Error, Result},
+};
+
+// Append headers into the storage in batches, to limit the memory usage.
+const IMPORT_BATCH_SIZE: usize = 2000;
+
+#[derive(Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    pub(crate) common: super::CommonArgs,
+
+    /// The directory, which stores all cached data.
+    ///
+    /// It should be empty, it will be initialized with the imported headers.
+    #[arg(long)]
+    pub(crate) data_dir: PathBuf,
+
+    /// The file which is exported by the `export` sub-command.
+    #[arg(long)]
+    pub(crate) input: PathBuf,
+}
+
+impl Args {
+    pub fn execute(&self) -> Result<()> {
+        log::info!("Import headers into the local storage");
+
+        let storage = Storage::new(&self.data_dir)?;
+        if storage.is_initialized()? {
+            let msg = format!(
+                "user-provided data directory \"{}\" is not empty, \
+                please import into an empty directory",
+                self.data_dir.display()
+            );
+            return Err(Error::other(msg));
+        }
+
+        let file = File::open(&self.input).map_err(|err| {
+            let msg = format!(
+                "failed to open the file \"{}\" since {err}",
+                self.input.display()
+            );
+            Error::other(msg)
+        })?;
+        let mut reader = BufReader::new(file);
+
+        let magic = read_bytes(&mut reader, EXPORT_MAGIC.len())?;
+        if magic != EXPORT_MAGIC {
+            return Err(Error::other(
+                "the file is not exported by the `export` sub-command",
+            ));
+        }
+        let base_height = read_u32(&mut reader)?;
+        let tip_height = read_u32(&mut reader)?;
+        if tip_height < base_height {
+            let msg =
+                format!("the tip height {tip_height} is less than the base height {base_height}");
+            return Err(Error::other(msg));
+        }
+        let spv_type_script = {
+            let len = read_u32(&mut reader)? as usize;
+            let data = read_bytes(&mut reader, len)?;
+            Script::from_slice(&data).map_err(|err| {
+                let msg = format!("failed to decode the type script since {err}");
+                Error::other(msg)
+            })?
+        };
+        let spv_cell_dep = {
+            let len = read_u32(&mut reader)? as usize;
+            let data = read_bytes(&mut reader, len)?;
+            CellDep::from_slice(&data).map_err(|err| {
+                let msg = format!("failed to decode the cell dep since {err}");
+                Error::other(msg)
+            })?
+        };
+        log::info!("Import headers from header#{base_height} to header#{tip_height}");
+
+        let base_header = read_header(&mut reader, base_height)?;
+        storage.initialize_with(base_height, base_header)?;
+        let mut headers = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        for height in (base_height + 1)..=tip_height {
+            headers.push(read_header(&mut reader, height)?);
+            if headers.len() == IMPORT_BATCH_SIZE || height == tip_height {
+                // The continuity of headers is checked when they are appended.
+                let (stg_tip_height, _) = storage.append_headers(headers)?;
+                log::info!("[storage] header#{stg_tip_height:07}; imported");
+                headers = Vec::with_capacity(IMPORT_BATCH_SIZE);
+            }
+        }
+        if reader.read(&mut [0u8; 1]).map_err(Error::other)? != 0 {
+            return Err(Error::other(
+                "the file has unexpected data after the tip header",
+            ));
+        }
+
+        storage.save_cells_state(spv_type_script, spv_cell_dep)?;
+        storage.flush()?;
+        log::info!("The local storage is initialized with the imported headers");
+
+        Ok(())
+    }
+}
+
+fn read_bytes<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).map_err(|err| {
+        let msg = format!("failed to read the file since {err}");
+        Error::other(msg)
+    })?;
+    Ok(data)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut data = [0u8; 4];
+    reader.read_exact(&mut data).map_err(|err| {
+        let msg = format!("failed to read the file since {err}");
+        Error::other(msg)
+    })?;
+    Ok(u32::from_be_bytes(data))
+}
+
+// Reads a header, and checks that it's at the expected height.
+fn read_header<R: Read>(reader: &mut R, expected_height: u32) -> Result<Header> {
+    let height = read_u32(reader)?;
+    if height != expected_height {
+        let msg = format!("expect header#{expected_height} in the file, but got header#{height}");
+        return Err(Error::other(msg));
+    }
+    let data = read_bytes(reader, HEADER_SIZE)?;
+    deserialize(&data).map_err(|err| {
+        let msg = format!("failed to decode the header#{height} since {err}");
+        Error::other(msg)
+    })
+}
@@ -2,10 +2,15 @@
 
 use std::{collections::HashMap, path::PathBuf};
 
-use bitcoin::blockdata::constants::DIFFCHANGE_INTERVAL;
+use bitcoin::{blockdata::constants::DIFFCHANGE_INTERVAL, Network};
 use ckb_bitcoin_spv_verifier::{
     constants::{FLAG_CHAIN_TYPE_MAINNET, FLAG_CHAIN_TYPE_SIGNET, FLAG_CHAIN_TYPE_TESTNET},
-    types::{core::Hash as BitcoinHash, packed, prelude::Pack as VPack},
+    types::{
+        core::{Hash as BitcoinHash, Header, Target},
+        packed,
+        prelude::Pack as VPack,
+    },
+    utilities::bitcoin::calculate_next_target,
 };
 use ckb_jsonrpc_types::TransactionView;
 use ckb_sdk::{
@@ -14,14 +19,9 @@ use ckb_sdk::{
         builder::{ChangeBuilder, DefaultChangeBuilder},
         handler::HandlerContexts,
         input::InputIterator,
-        signer::{SignContexts, TransactionSigner},
         TransactionBuilderConfiguration,
     },
-    types::{
-        Address as CkbAddress, AddressPayload as CkbAddressPayload, NetworkInfo, NetworkType,
-        ScriptGroup, TransactionWithScriptGroups,
-    },
-    SECP256K1,
+    types::{HumanCapacity, NetworkInfo, NetworkType, ScriptGroup, TransactionWithScriptGroups},
 };
 use ckb_types::{
     core::{Capacity, DepType, ScriptHashType},
@@ -32,10 +32,9 @@ use ckb_types::{
     H256,
 };
 use clap::{Args as ClapArgs, Parser, ValueEnum};
-use secp256k1::SecretKey;
 
 use crate::{
-    components::Storage,
+    components::{BitcoinClient, Storage},
     prelude::*,
     result::{Error, Result},
     utilities::{calculate_type_id, value_parsers},
@@ -78,6 +77,16 @@ pub struct Args {
     #[clap(flatten)]
     pub(crate) spv_owner: super::SpvOwner,
 
+    #[clap(flatten)]
+    pub(crate) excluded_cells: super::ExcludedCells,
+
+    /// The capacity (in CKBytes) of each SPV cell, instead of the minimal occupied capacity.
+    ///
+    /// Over-provisioned SPV cells leave headroom for future updates,
+    /// e.g., when the owner is changed to a lock with bigger args.
+    #[arg(long)]
+    pub(crate) spv_cell_capacity: Option<HumanCapacity>,
+
     /// Bitcoin chain type.
     #[arg(long)]
     pub(crate) bitcoin_chain_type: BitcoinChainType,
@@ -117,14 +126,16 @@ impl Args {
             let msg = "The Bitcoin chain type is not mainnet, but the CKB network is mainnet";
             return Err(Error::other(msg));
         }
+        self.ckb.check_fee_rate_limits()?;
+        self.ckb.confirm_network()?;
 
         self.check_inputs()?;
         log::info!("The bitcoin start height is {}", self.bitcoin_start_height);
 
-        let btc_start_header = self
-            .bitcoin
-            .client()
-            .check_then_fetch_header(self.bitcoin_start_height)?;
+        let btc_cli = self.bitcoin.client();
+        self.bitcoin_chain_type.check_bitcoin_network(&btc_cli)?;
+        let btc_start_header = btc_cli.check_then_fetch_header(self.bitcoin_start_height)?;
+        self.check_start_header(&btc_cli, &btc_start_header)?;
 
         let storage = Storage::new(&self.data_dir)?;
         let spv_client = storage.initialize_with(self.bitcoin_start_height, btc_start_header)?;
@@ -137,13 +148,7 @@ impl Args {
             tmp
         };
 
-        let (deployer, deployer_key) = SecretKey::from_slice(&self.ckb.private_key.as_ref()[..])
-            .map(|sk| {
-                let pk = sk.public_key(&SECP256K1);
-                let payload = CkbAddressPayload::from_pubkey(&pk);
-                let address = CkbAddress::new(self.ckb.network, payload, true);
-                (address, sk)
-            })?;
+        let (deployer, deployer_signer) = self.ckb.deployer()?;
         log::info!("The contract deployer is {deployer}");
 
         let spv_outputs_data = {
@@ -158,7 +163,8 @@ impl Args {
             outputs_data
         };
 
-        let mut iterator = InputIterator::new_with_address(&[deployer.clone()], &network_info);
+        let iterator = InputIterator::new_with_address(&[deployer.clone()], &network_info);
+        let mut iterator = self.excluded_cells.filter(iterator);
         let mut tx_builder = TransactionBuilder::default();
 
         let spv_contract_cell_dep = CellDep::new_builder()
@@ -184,16 +190,10 @@ impl Args {
             let cells_count = usize::from(self.spv_clients_count) + 1;
             let type_id_array = calculate_type_id(input0.cell_input(), cells_count);
             let type_id = BitcoinHash::from_bytes_ref(&type_id_array);
-            let mut flags = 0u8;
-            match self.bitcoin_chain_type {
-                BitcoinChainType::Mainnet => flags |= FLAG_CHAIN_TYPE_MAINNET,
-                BitcoinChainType::Testnet => flags |= FLAG_CHAIN_TYPE_TESTNET,
-                BitcoinChainType::Signet => flags |= FLAG_CHAIN_TYPE_SIGNET,
-            }
             let args = packed::SpvTypeArgs::new_builder()
                 .type_id(type_id.pack())
                 .clients_count(self.spv_clients_count.into())
-                .flags(flags.into())
+                .flags(self.spv_flags().into())
                 .build();
             match self.spv_contract_code_hash {
                 CodeHash {
@@ -221,6 +221,7 @@ impl Args {
         };
 
         storage.save_cells_state(spv_type_script.clone(), spv_contract_cell_dep)?;
+        storage.save_spv_owner_lock(self.spv_owner.lock_script())?;
 
         let spv_outputs = {
             let spv_info_capacity = Capacity::bytes(spv_outputs_data[0].len()).map_err(|err| {
@@ -259,6 +260,14 @@ impl Args {
                     );
                     Error::other(msg)
                 })?;
+            let (spv_info, spv_client) = if let Some(ref capacity) = self.spv_cell_capacity {
+                (
+                    over_provision(spv_info, capacity.0)?,
+                    over_provision(spv_client, capacity.0)?,
+                )
+            } else {
+                (spv_info, spv_client)
+            };
             let mut outputs = vec![spv_client.clone(); usize::from(self.spv_clients_count) + 1];
             outputs[0] = spv_info;
             outputs
@@ -384,10 +393,7 @@ impl Args {
             Error::other(msg)
         })?;
 
-        TransactionSigner::new(&network_info).sign_transaction(
-            &mut tx_with_groups,
-            &SignContexts::new_sighash(vec![deployer_key]),
-        )?;
+        deployer_signer.sign_transaction(&mut tx_with_groups, &(&deployer).into())?;
 
         let tx_json = TransactionView::from(tx_with_groups.get_tx_view().clone());
         self.ckb
@@ -419,4 +425,104 @@ impl Args {
 
         Ok(())
     }
+
+    fn spv_flags(&self) -> u8 {
+        match self.bitcoin_chain_type {
+            BitcoinChainType::Mainnet => FLAG_CHAIN_TYPE_MAINNET,
+            BitcoinChainType::Testnet => FLAG_CHAIN_TYPE_TESTNET,
+            BitcoinChainType::Signet => FLAG_CHAIN_TYPE_SIGNET,
+        }
+    }
+
+    // The start header should begin a difficulty epoch on the chain of the
+    // Bitcoin node: it follows the last header of the previous epoch, and its
+    // target is the one calculated from the previous epoch.
+    fn check_start_header(&self, btc_cli: &BitcoinClient, start_header: &Header) -> Result<()> {
+        let start_height = self.bitcoin_start_height;
+        if start_height == 0 {
+            return Ok(());
+        }
+        let prev_start_height = start_height - DIFFCHANGE_INTERVAL;
+        let prev_start_header = btc_cli.get_block_header_by_height(prev_start_height)?;
+        let prev_end_header = btc_cli.get_block_header_by_height(start_height - 1)?;
+
+        if start_header.prev_blockhash != prev_end_header.block_hash() {
+            let msg = format!(
+                "the Bitcoin header#{start_height} doesn't follow the header#{}, \
+                the chain of the Bitcoin node is changing, please retry later",
+                start_height - 1
+            );
+            return Err(Error::other(msg));
+        }
+
+        let prev_target: Target = prev_end_header.bits.into();
+        let expected_bits = calculate_next_target(
+            prev_target,
+            prev_start_header.time,
+            prev_end_header.time,
+            self.spv_flags(),
+        )
+        .to_compact_lossy();
+        if expected_bits != start_header.bits {
+            let msg = format!(
+                "the Bitcoin header#{start_height} doesn't begin a difficulty epoch, \
+                its bits are {:#010x} but {:#010x} are calculated from the previous epoch",
+                start_header.bits.to_consensus(),
+                expected_bits.to_consensus()
+            );
+            // The last header of an epoch on testnet could be mined with the
+            // minimum difficulty, which the target adjustment is based on.
+            if self.bitcoin_chain_type == BitcoinChainType::Testnet {
+                log::warn!("{msg}");
+            } else {
+                return Err(Error::other(msg));
+            }
+        } else {
+            log::info!("The bitcoin header#{start_height} begins a difficulty epoch");
+        }
+
+        Ok(())
+    }
+}
+
+impl BitcoinChainType {
+    // The Bitcoin node should run on the same chain as the chain type, since the
+    // Bitcoin SPV contract checks headers with the rules of that chain.
+    pub(crate) fn check_bitcoin_network(&self, btc_cli: &BitcoinClient) -> Result<Network> {
+        let expected = match self {
+            BitcoinChainType::Mainnet => Network::Bitcoin,
+            BitcoinChainType::Testnet => Network::Testnet,
+            BitcoinChainType::Signet => Network::Signet,
+        };
+        let actual = btc_cli.get_network()?;
+        if actual == expected {
+            return Ok(actual);
+        }
+        let msg = if actual == Network::Regtest {
+            "the Bitcoin node runs on regtest, which is not supported \
+            by the Bitcoin SPV contract"
+                .to_owned()
+        } else {
+            format!(
+                "the Bitcoin chain type is \"{}\", but the Bitcoin node runs on \"{}\"",
+                expected.to_core_arg(),
+                actual.to_core_arg()
+            )
+        };
+        Err(Error::other(msg))
+    }
+}
+
+fn over_provision(output: CellOutput, capacity: u64) -> Result<CellOutput> {
+    let occupied: u64 = output.capacity().unpack();
+    if capacity < occupied {
+        let msg = format!(
+            "the SPV cell capacity {} is less than its occupied capacity {}",
+            HumanCapacity::from(capacity),
+            HumanCapacity::from(occupied)
+        );
+        return Err(Error::cli(msg));
+    }
+    let output = output.as_builder().capacity(capacity.pack()).build();
+    Ok(output)
 }
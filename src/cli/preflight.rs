@@ -0,0 +1,264 @@
+//! The `preflight` sub-command.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ckb_sdk::types::NetworkType;
+use ckb_types::{
+    packed::{CellOutput, OutPoint},
+    prelude::*,
+    H256,
+};
+use clap::{Parser, ValueEnum};
+
+use super::init::BitcoinChainType;
+use crate::{
+    result::{Error, Result},
+    utilities::value_parsers,
+};
+
+#[derive(Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    pub(crate) common: super::CommonArgs,
+
+    /// The directory, which stores all cached data.
+    #[arg(long)]
+    pub(crate) data_dir: PathBuf,
+
+    #[clap(flatten)]
+    pub(crate) ckb: super::CkbArgs,
+
+    #[clap(flatten)]
+    pub(crate) bitcoin: super::BitcoinArgs,
+
+    /// The expected Bitcoin chain type, which the Bitcoin node should run on.
+    ///
+    /// It's required for `init`; for `serve`, if it's not provided,
+    /// the network of the Bitcoin node is not checked.
+    #[arg(long, required_if_eq("target", "init"))]
+    pub(crate) bitcoin_chain_type: Option<BitcoinChainType>,
+
+    /// The subcommand which will be run after the checks.
+    #[arg(long, value_enum)]
+    pub(crate) target: Target,
+
+    /// The out point of the Bitcoin SPV contract.
+    #[arg(long, value_parser = value_parsers::OutPointValueParser)]
+    pub(crate) spv_contract_out_point: Option<OutPoint>,
+
+    /// The expected data hash of the Bitcoin SPV contract.
+    #[arg(
+        long,
+        value_parser = value_parsers::H256ValueParser,
+        requires = "spv_contract_out_point"
+    )]
+    pub(crate) spv_contract_data_hash: Option<H256>,
+
+    /// The expected type hash of the Bitcoin SPV contract.
+    #[arg(
+        long,
+        value_parser = value_parsers::H256ValueParser,
+        requires = "spv_contract_out_point"
+    )]
+    pub(crate) spv_contract_type_hash: Option<H256>,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Target {
+    /// The data directory should not contain any database.
+    Init,
+    /// The data directory should contain a database.
+    Serve,
+}
+
+impl Args {
+    pub fn execute(&self) -> Result<()> {
+        log::info!(
+            "Check all arguments and connectivity, without sending anything or writing the storage"
+        );
+
+        let checks = vec![
+            ("deployer", self.check_deployer()),
+            ("CKB endpoint", self.check_ckb()),
+            ("Bitcoin endpoint", self.check_bitcoin()),
+            ("SPV contract", self.check_spv_contract()),
+            ("data directory", self.check_data_dir()),
+        ];
+        let mut failed = 0;
+        for (name, result) in &checks {
+            match result {
+                Ok(detail) => log::info!("[PASS] {name}: {detail}"),
+                Err(err) => {
+                    failed += 1;
+                    log::error!("[FAIL] {name}: {err}");
+                }
+            }
+        }
+
+        if failed > 0 {
+            let msg = format!("{failed} of {} checks failed", checks.len());
+            return Err(Error::other(msg));
+        }
+        log::info!("All {} checks passed", checks.len());
+
+        Ok(())
+    }
+
+    // The deployer is derived from the private key (`--key-file` or `--key-env`),
+    // or it's the fee payer which is signed for by `--signer-url`.
+    fn check_deployer(&self) -> Result<String> {
+        let (deployer, _) = self.ckb.deployer()?;
+        Ok(format!("CKBytes will be provided by {deployer}"))
+    }
+
+    fn check_ckb(&self) -> Result<String> {
+        let chain = self.ckb.client().get_blockchain_info()?.chain;
+        if NetworkType::from_raw_str(&chain) != Some(self.ckb.network) {
+            let msg = format!(
+                "the CKB chain is \"{chain}\", which doesn't match the network type \"{}\"",
+                self.ckb.network
+            );
+            return Err(Error::other(msg));
+        }
+        self.ckb.check_fee_rate_limits()?;
+        Ok(format!("connected to the CKB chain \"{chain}\""))
+    }
+
+    fn check_bitcoin(&self) -> Result<String> {
+        let btc_cli = self.bitcoin.client();
+        let tip_height = btc_cli.get_tip_height()?;
+        let network = if let Some(ref chain_type) = self.bitcoin_chain_type {
+            if *chain_type != BitcoinChainType::Mainnet && self.ckb.network == NetworkType::Mainnet
+            {
+                let msg = "the Bitcoin chain type is not mainnet, but the CKB network is mainnet";
+                return Err(Error::other(msg));
+            }
+            chain_type.check_bitcoin_network(&btc_cli)?.to_core_arg()
+        } else {
+            "unchecked"
+        };
+        Ok(format!(
+            "the tip height of Bitcoin is {tip_height} (network: {network})"
+        ))
+    }
+
+    fn check_spv_contract(&self) -> Result<String> {
+        let out_point = if let Some(ref out_point) = self.spv_contract_out_point {
+            out_point
+        } else {
+            return Ok("skipped since no out point is provided".to_owned());
+        };
+        let tx_hash = out_point.tx_hash();
+        let index: u32 = out_point.index().unpack();
+
+        let cell_with_status = self
+            .ckb
+            .client()
+            .get_live_cell(out_point.clone().into(), true)?;
+        if cell_with_status.status != "live" {
+            let msg = format!(
+                "the cell (tx-hash: {tx_hash:#x}, index: {index}) is {}",
+                cell_with_status.status
+            );
+            return Err(Error::other(msg));
+        }
+        let cell = cell_with_status.cell.ok_or_else(|| {
+            let msg = format!("remote server replied empty for cell {tx_hash:#x}-{index}");
+            Error::other(msg)
+        })?;
+
+        if let Some(ref expected) = self.spv_contract_data_hash {
+            let actual = cell.data.as_ref().map(|data| &data.hash);
+            if actual != Some(expected) {
+                let msg = format!(
+                    "the data hash of the contract is {actual:?}, but expect {expected:#x}"
+                );
+                return Err(Error::other(msg));
+            }
+        }
+        if let Some(ref expected) = self.spv_contract_type_hash {
+            let output: CellOutput = cell.output.into();
+            let actual: Option<H256> = output
+                .type_()
+                .to_opt()
+                .map(|script| script.calc_script_hash().unpack());
+            if actual.as_ref() != Some(expected) {
+                let msg = format!(
+                    "the type hash of the contract is {actual:?}, but expect {expected:#x}"
+                );
+                return Err(Error::other(msg));
+            }
+        }
+
+        Ok(format!(
+            "the cell (tx-hash: {tx_hash:#x}, index: {index}) is live"
+        ))
+    }
+
+    // RocksDB always keeps a file "CURRENT" in the directory of a database,
+    // check it rather than open the database, to avoid writing anything.
+    fn check_data_dir(&self) -> Result<String> {
+        let dir = &self.data_dir;
+        let has_database = if dir.exists() {
+            let metadata = fs::metadata(dir).map_err(Error::other)?;
+            if !metadata.is_dir() {
+                let msg = format!("\"{}\" is not a directory", dir.display());
+                return Err(Error::other(msg));
+            }
+            check_writable(dir)?;
+            dir.join("CURRENT").exists()
+        } else {
+            // The directory will be created in its nearest existing ancestor.
+            let ancestor = dir
+                .ancestors()
+                .skip(1)
+                .map(|path| {
+                    if path.as_os_str().is_empty() {
+                        Path::new(".")
+                    } else {
+                        path
+                    }
+                })
+                .find(|path| path.is_dir())
+                .ok_or_else(|| {
+                    let msg = format!("\"{}\" can't be created", dir.display());
+                    Error::other(msg)
+                })?;
+            check_writable(ancestor)?;
+            false
+        };
+        match (self.target, has_database) {
+            (Target::Init, true) => {
+                let msg = format!(
+                    "\"{}\" already contains a database, it can't be initialized again",
+                    dir.display()
+                );
+                Err(Error::other(msg))
+            }
+            (Target::Serve, false) => {
+                let msg = format!(
+                    "\"{}\" doesn't contain any database, please initialize it",
+                    dir.display()
+                );
+                Err(Error::other(msg))
+            }
+            (_, true) => Ok(format!("\"{}\" contains a database", dir.display())),
+            (_, false) => Ok(format!("\"{}\" is empty", dir.display())),
+        }
+    }
+}
+
+// The permissions only tell the mode bits, so create a temporary file to
+// check whether the current user could write the directory.
+fn check_writable(dir: &Path) -> Result<()> {
+    let probe = dir.join(format!(".preflight-{}", std::process::id()));
+    fs::File::create(&probe)
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|err| {
+            let msg = format!("\"{}\" is not writable since {err}", dir.display());
+            Error::other(msg)
+        })
+}
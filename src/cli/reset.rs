@@ -0,0 +1,144 @@
+//! The `reset` sub-command.
+
+use std::{num::NonZeroU32, path::PathBuf};
+
+use ckb_bitcoin_spv_verifier::types::core::BitcoinChainType;
+use ckb_sdk::types::HumanCapacity;
+use clap::Parser;
+
+use super::serve::{FeeEstimationMode, SpvCellsUpdater, SPV_RESET_TIP_OFFSET};
+use crate::{
+    components::{SpvReorgInput, Storage},
+    prelude::*,
+    result::{Error, Result},
+};
+
+#[derive(Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    pub(crate) common: super::CommonArgs,
+
+    /// The directory, which stores all cached data.
+    #[arg(long)]
+    pub(crate) data_dir: PathBuf,
+
+    #[clap(flatten)]
+    pub(crate) ckb: super::CkbArgs,
+
+    /// The height of the tip header of the rebuilt SPV clients.
+    ///
+    /// If it's not provided, same as the `serve` service, the height of the
+    /// current tip SPV client on chain minus 1200 will be used.
+    #[arg(long)]
+    pub(crate) spv_tip_height: Option<u32>,
+
+    /// Don't update all headers in one CKB transaction,
+    /// to avoid size limit or cycles limit.
+    #[arg(long, default_value = "10")]
+    pub(crate) spv_headers_update_limit: NonZeroU32,
+
+    /// How to decide the fee of the transaction which rebuilds SPV cells.
+    #[arg(long, value_enum, default_value = "size")]
+    pub(crate) fee_estimation_mode: FeeEstimationMode,
+
+    #[clap(flatten)]
+    pub(crate) spv_owner_opt: super::SpvOwnerOpt,
+
+    #[clap(flatten)]
+    pub(crate) excluded_cells: super::ExcludedCells,
+
+    /// The extra capacity (in CKBytes) for each rebuilt SPV cell, above its
+    /// minimal occupied capacity.
+    ///
+    /// SPV cells are only rebuilt when a new owner is provided.
+    #[arg(long)]
+    pub(crate) output_capacity_margin: Option<HumanCapacity>,
+
+    /// Allow the total capacity of rebuilt SPV cells to be decreased.
+    #[arg(long)]
+    pub(crate) allow_capacity_decrease: bool,
+
+    /// Perform all steps without sending.
+    #[arg(long, hide = true)]
+    pub(crate) dry_run: bool,
+}
+
+impl Args {
+    pub fn execute(&self) -> Result<()> {
+        log::info!("Reset all SPV clients of the Bitcoin SPV instance on CKB");
+
+        self.spv_owner_opt.check_network(self.ckb.network)?;
+        self.ckb.check_fee_rate_limits()?;
+        self.ckb.confirm_network()?;
+
+        let storage = Storage::new(&self.data_dir)?;
+        if !storage.is_initialized()? {
+            let msg = format!(
+                "user-provided data directory \"{}\" is empty, please initialize it",
+                self.data_dir.display()
+            );
+            return Err(Error::other(msg));
+        }
+
+        let spv_type_script = storage.spv_contract_type_script()?;
+        let ins = self.ckb.client().find_spv_cells(spv_type_script)?;
+        let input = SpvReorgInput::reset_all(ins)?;
+
+        let flags = input.info.get_flags()?;
+        if BitcoinChainType::Testnet != flags.into() {
+            let msg = "only the SPV instance for Bitcoin testnet could be reset";
+            return Err(Error::other(msg));
+        }
+
+        let curr_height = input.curr.client.headers_mmr_root.max_height;
+        let spv_tip_height = match self.spv_tip_height {
+            Some(height) => height,
+            None if curr_height >= SPV_RESET_TIP_OFFSET => curr_height - SPV_RESET_TIP_OFFSET,
+            None => {
+                let msg = format!(
+                    "the tip SPV client on chain (header#{curr_height}) is too low, \
+                    please provide the tip height"
+                );
+                return Err(Error::other(msg));
+            }
+        };
+        let (stg_tip_height, _) = storage.tip_state()?;
+        if spv_tip_height > stg_tip_height {
+            let msg = format!(
+                "the tip height {spv_tip_height} is higher than \
+                the tip height of the storage {stg_tip_height}"
+            );
+            return Err(Error::cli(msg));
+        }
+        log::info!(
+            "Rebuild all {} SPV clients with the tip header#{spv_tip_height}",
+            input.info.clients_count
+        );
+
+        let (spv_client, spv_update) = storage.generate_spv_client_and_spv_update(
+            spv_tip_height,
+            self.spv_headers_update_limit,
+            flags,
+        )?;
+
+        let updater = SpvCellsUpdater {
+            ckb: &self.ckb,
+            excluded_cells: &self.excluded_cells,
+            spv_owner_opt: &self.spv_owner_opt,
+            output_capacity_margin: self.output_capacity_margin.as_ref(),
+            fee_estimation_mode: self.fee_estimation_mode,
+            allow_capacity_decrease: self.allow_capacity_decrease,
+            auto_spv_headers_update_limit: false,
+            replacement_fee_rate: None,
+            weight_ratio: Default::default(),
+            dry_run: self.dry_run,
+        };
+        let sent = updater.reorg_spv_cells(&storage, input, spv_client, spv_update)?;
+        log::info!("Sent a transaction to reset SPV instance: {sent}");
+        log::info!(
+            "The `serve` service will update SPV clients to the storage tip after it's committed"
+        );
+
+        Ok(())
+    }
+}
@@ -1,14 +1,17 @@
 //! The `serve` sub-command.
 
 use std::{
+    cell::Cell,
     cmp::Ordering,
     collections::{HashMap, HashSet},
-    net::SocketAddr,
+    fmt, iter,
     num::NonZeroU32,
     path::PathBuf,
+    sync::atomic,
     thread, time,
 };
 
+use bitcoin::constants::DIFFCHANGE_INTERVAL;
 use ckb_bitcoin_spv_verifier::types::{
     core::{BitcoinChainType, SpvClient},
     packed,
@@ -17,48 +20,113 @@ use ckb_bitcoin_spv_verifier::types::{
 use ckb_jsonrpc_types::{Status, TransactionView};
 use ckb_sdk::{
     core::TransactionBuilder,
+    traits::LiveCell,
     transaction::{
         builder::{ChangeBuilder, DefaultChangeBuilder},
         handler::HandlerContexts,
         input::{InputIterator, TransactionInput},
-        signer::{SignContexts, TransactionSigner},
         TransactionBuilderConfiguration,
     },
-    types::{
-        Address as CkbAddress, AddressPayload as CkbAddressPayload, NetworkInfo, NetworkType,
-        ScriptGroup, TransactionWithScriptGroups,
-    },
-    SECP256K1,
+    types::{HumanCapacity, NetworkInfo, NetworkType, ScriptGroup, TransactionWithScriptGroups},
 };
 use ckb_types::{
-    core::{Capacity, DepType},
+    core::{Capacity, DepType, FeeRate, TransactionView as CoreTransactionView},
     packed::{
-        Byte32, Bytes as PackedBytes, BytesOpt, CellDep, CellInput, CellOutput, OutPoint,
+        Byte32, Bytes as PackedBytes, BytesOpt, CellDep, CellInput, CellOutput, OutPoint, Script,
         WitnessArgs,
     },
     prelude::*,
     H256,
 };
-use clap::Parser;
-use secp256k1::SecretKey;
+use clap::{Parser, ValueEnum};
 
 use crate::{
     components::{
-        ApiServiceConfig, SpvOperation, SpvReorgInput, SpvService, SpvUpdateInput, Storage,
+        start_metrics_server, BlockNotifier, SpvOperation, SpvReorgInput, SpvService,
+        SpvUpdateInput, Storage, SyncStatus,
     },
     constants,
     prelude::*,
     result::{Error, Result},
-    utilities::{try_raise_fd_limit, value_parsers},
+    utilities::{listen_shutdown_signal, try_raise_fd_limit, value_parsers, wait_unless_shutdown},
 };
 
-const SPV_RESET_TIP_OFFSET: u32 = 1200;
+pub(crate) const SPV_RESET_TIP_OFFSET: u32 = 1200;
+// Same as the default value of `bytes_per_cycles` in the CKB transaction pool.
+const BYTES_PER_CYCLES: f64 = 0.000_170_571_4;
+// Same as the default value of `min_rbf_rate` in the CKB transaction pool.
+const MIN_RBF_FEE_RATE: u64 = 1500;
+// The derived limit of headers only uses this percentage of the limits of
+// the CKB transaction pool, since the cycles and the size of each header vary.
+const HEADERS_UPDATE_LIMIT_SAFETY_PERCENT: u64 = 80;
+// The derived limit of headers grows at most this times in each update.
+const HEADERS_UPDATE_LIMIT_MAX_GROWTH: u32 = 2;
+
+/// A transaction which was sent to update SPV cells.
+pub(crate) struct SentSpvTransaction {
+    pub(crate) tx_hash: H256,
+    /// The tip height of the new SPV client.
+    pub(crate) tip_height: u32,
+    /// The count of Bitcoin headers in the SPV update.
+    pub(crate) headers_count: usize,
+    /// The fee, in shannons.
+    pub(crate) fee: u64,
+    /// The fee rate which the transaction was built with, in shannons per KB.
+    pub(crate) fee_rate: u64,
+    /// The estimated cycles, only when the headers update limit is derived or
+    /// the fees are paid by the cycles.
+    pub(crate) cycles: Option<u64>,
+    /// The serialized size in a block, in bytes.
+    pub(crate) size: u64,
+}
+
+/// A sent transaction which is not committed yet.
+struct PendingSpvTransaction {
+    tx_hash: H256,
+    fee_rate: u64,
+    sent_at: time::Instant,
+}
+
+/// A signed transaction to update SPV cells, which is not sent yet.
+struct BuiltSpvTransaction {
+    tx_view: CoreTransactionView,
+    tip_height: u32,
+    headers_count: usize,
+    fee: u64,
+    fee_rate: u64,
+}
+
+/// Builds and sends transactions to update SPV cells, with the arguments which
+/// are shared by the sub-commands that update SPV cells.
+pub(crate) struct SpvCellsUpdater<'a> {
+    pub(crate) ckb: &'a super::CkbArgs,
+    pub(crate) excluded_cells: &'a super::ExcludedCells,
+    pub(crate) spv_owner_opt: &'a super::SpvOwnerOpt,
+    pub(crate) output_capacity_margin: Option<&'a HumanCapacity>,
+    pub(crate) fee_estimation_mode: FeeEstimationMode,
+    pub(crate) allow_capacity_decrease: bool,
+    /// Estimate the cycles of each sent transaction.
+    pub(crate) auto_spv_headers_update_limit: bool,
+    /// The minimal fee rate, to replace a stuck transaction.
+    pub(crate) replacement_fee_rate: Option<u64>,
+    /// The ratio of the weight to the size of the previous transaction, only
+    /// when the fees are paid by the cycles.
+    pub(crate) weight_ratio: Cell<Option<f64>>,
+    pub(crate) dry_run: bool,
+}
 
 #[derive(Parser)]
 pub struct Args {
     #[clap(flatten)]
     pub(crate) common: super::CommonArgs,
 
+    /// Load arguments from a TOML file, whose keys are the long names of arguments.
+    ///
+    /// Arguments in the command line override the same ones in the file.
+    /// The private key file can't be provided in it, use `--key-file` or `--key-env`.
+    #[arg(long, value_name = "FILE")]
+    pub(crate) config: Option<PathBuf>,
+
     /// The directory, which stores all cached data.
     #[arg(long)]
     pub(crate) data_dir: PathBuf,
@@ -69,22 +137,86 @@ pub struct Args {
     #[clap(flatten)]
     pub(crate) bitcoin: super::BitcoinArgs,
 
-    /// The JSON-RPC server's listen address.
-    #[arg(long)]
-    pub(crate) listen_address: SocketAddr,
+    #[clap(flatten)]
+    pub(crate) api: super::ApiArgs,
 
     /// A interval in seconds.
     ///
     /// - When no better bitcoin blocks, waiting for several seconds.
-    /// - After a CKB transaction is sent, waiting for several seconds.
+    /// - After a CKB transaction is sent, waiting for several seconds,
+    ///   if `--on-chain-poll-interval` is not provided.
     #[arg(long, default_value = "30")]
     pub(crate) interval: u64,
 
+    /// A interval in seconds, to poll the SPV instance on chain.
+    ///
+    /// - After a CKB transaction is sent, waiting for several seconds.
+    /// - When the reorg is cooling down, waiting for several seconds.
+    ///
+    /// If it's not provided, `--interval` will be used.
+    #[arg(long, value_name = "SECONDS")]
+    pub(crate) on_chain_poll_interval: Option<u64>,
+
+    /// A interval in seconds, to compare the tip SPV client on chain with
+    /// the local storage periodically, and warn about the divergence early.
+    ///
+    /// If it's not provided, only compare them before updating.
+    #[arg(long, value_name = "SECONDS")]
+    pub(crate) compare_with_onchain: Option<u64>,
+
+    /// A interval in seconds, to check that the tip header in the local storage
+    /// chains to its parent periodically.
+    ///
+    /// If a discontinuity is found, halt updating the SPV instance until the
+    /// next check passes, since the MMR roots generated from the corrupted
+    /// storage are wrong. The APIs are still served.
+    #[arg(long, value_name = "SECONDS")]
+    pub(crate) header_chain_assert_interval: Option<u64>,
+
+    /// The minimum interval in seconds between two reorg transactions.
+    ///
+    /// When the Bitcoin chain is unstable, rapid reorgs will be coalesced
+    /// into fewer on-chain corrections.
+    /// Set it to 0 to handle each reorg immediately.
+    #[arg(long, default_value = "0")]
+    pub(crate) reorg_cooldown: u64,
+
+    /// A timeout in seconds, for the sent transaction to be committed.
+    ///
+    /// If the sent transaction is still pending after the timeout, such as the
+    /// fee rate is too low, it will be replaced by a rebuilt transaction which
+    /// spends the same SPV cells with a higher fee rate (RBF).
+    /// The fee rate is doubled each time, and increased 1500 shannons/KB at least.
+    ///
+    /// If it's not provided, wait for the sent transaction forever.
+    #[arg(long, value_name = "SECONDS")]
+    pub(crate) tx_confirmation_timeout: Option<u64>,
+
     /// Don't update all headers in one CKB transaction,
     /// to avoid size limit or cycles limit.
     #[arg(long, default_value = "10")]
     pub(crate) spv_headers_update_limit: NonZeroU32,
 
+    /// Derive the limit of headers in each update from the estimated cycles and
+    /// the size of the previous update, to make updates as large as possible but
+    /// still under the cycles limit and the size limit of the CKB transaction pool.
+    ///
+    /// `--spv-headers-update-limit` is used for the first update, and after
+    /// any update fails. The limit grows at most twice in each update.
+    #[arg(long)]
+    pub(crate) auto_spv_headers_update_limit: bool,
+
+    /// The cycles limit of a transaction, which is the `max_tx_verify_cycles`
+    /// of the CKB transaction pool, only used with `--auto-spv-headers-update-limit`.
+    ///
+    /// It's a config of the CKB node, which can't be fetched through JSON-RPC.
+    #[arg(long, default_value = "70000000")]
+    pub(crate) max_tx_verify_cycles: u64,
+
+    /// How to decide the fee of each transaction which updates SPV cells.
+    #[arg(long, value_enum, default_value = "size")]
+    pub(crate) fee_estimation_mode: FeeEstimationMode,
+
     /// The out point of the Bitcoin SPV contract.
     ///
     /// This parameter will override the value in the storage.
@@ -92,25 +224,91 @@ pub struct Args {
     #[arg(long, value_parser = value_parsers::OutPointValueParser)]
     pub(crate) spv_contract_out_point: Option<OutPoint>,
 
-    /// The batch size that how many Bitcoin headers will be downloaded at once.
-    #[arg(long, default_value = "30")]
-    pub(crate) bitcoin_headers_download_batch_size: u32,
+    #[clap(flatten)]
+    pub(crate) sync: super::SyncArgs,
+
+    /// Before serving, sync the local storage and check that the SPV instance
+    /// on chain matches it.
+    ///
+    /// The service still starts if they are diverged, and reorgs the SPV instance.
+    #[arg(long)]
+    pub(crate) reverify_on_start: bool,
+
+    /// Prune the headers in the local storage which are deeper than this
+    /// count of blocks below the tip, to bound the growth of the storage.
+    ///
+    /// Proofs for pruned headers can't be generated any more.
+    /// The headers which are required to update or reorganize the SPV instance
+    /// on chain, since the difficulty epoch of its oldest SPV client, are kept.
+    /// It should be 2016 at least, a whole difficulty epoch.
+    /// The headers are kept by default.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(2016..))]
+    pub(crate) prune_keep_depth: Option<u32>,
 
     #[clap(flatten)]
     pub(crate) spv_owner_opt: super::SpvOwnerOpt,
 
+    #[clap(flatten)]
+    pub(crate) excluded_cells: super::ExcludedCells,
+
+    /// The extra capacity (in CKBytes) for each rebuilt SPV cell, above its
+    /// minimal occupied capacity.
+    ///
+    /// SPV cells are only rebuilt when a new owner is provided.
+    #[arg(long)]
+    pub(crate) output_capacity_margin: Option<HumanCapacity>,
+
+    /// Warn when the total capacity (in CKBytes) of the address, which pays
+    /// for the SPV updates, is less than this value.
+    ///
+    /// The balance is checked periodically, with the interval to poll the SPV
+    /// instance on chain; it's also checked when the metrics are served.
+    #[arg(long)]
+    pub(crate) min_ckb_balance: Option<HumanCapacity>,
+
+    /// Allow the total capacity of rebuilt SPV cells to be decreased.
+    ///
+    /// By default, the SPV info cell is topped up from the change, to make
+    /// sure the total capacity is not decreased, which is required by the
+    /// lock of SPV cells when they are updated by non-owners.
+    #[arg(long)]
+    pub(crate) allow_capacity_decrease: bool,
+
     /// Perform all steps without sending.
     #[arg(long, hide = true)]
     pub(crate) dry_run: bool,
 }
 
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum FeeEstimationMode {
+    /// The fee rate multiplied by the size of the transaction.
+    Size,
+    /// Same as `size`, but the size equivalent of the estimated cycles is used
+    /// instead of the size, when it's larger; complex transactions, such as
+    /// reorgs, pay more fees to avoid being under-prioritized.
+    ///
+    /// Each transaction is signed only once, so its weight is estimated by the
+    /// ratio of the weight to the size of the previous transaction; the first
+    /// transaction pays by its size.
+    Cycles,
+}
+
 impl Args {
     pub fn execute(&self) -> Result<()> {
         log::info!("Starting the Bitcoin SPV service");
+        if let Some(ref config) = self.config {
+            log::info!(
+                "Loaded arguments from the config file \"{}\"",
+                config.display()
+            );
+        }
 
         self.spv_owner_opt.check_network(self.ckb.network)?;
+        self.ckb.check_fee_rate_limits()?;
+        self.ckb.confirm_network()?;
 
         try_raise_fd_limit();
+        let shutdown = listen_shutdown_signal();
 
         let storage = Storage::new(&self.data_dir)?;
         if !storage.is_initialized()? {
@@ -120,6 +318,7 @@ impl Args {
             );
             return Err(Error::other(msg));
         }
+        storage.set_header_cache_size(self.sync.header_cache_size)?;
 
         if let Some(ref spv_contract_out_point) = self.spv_contract_out_point {
             let spv_contract_cell_dep = CellDep::new_builder()
@@ -132,36 +331,175 @@ impl Args {
 
         let ckb_cli = self.ckb.client();
         let btc_cli = self.bitcoin.client();
-
+        let block_notifier = self
+            .sync
+            .bitcoin_zmq_endpoint
+            .as_deref()
+            .map(BlockNotifier::subscribe)
+            .transpose()?;
+
+        let sync_options = self.sync.options();
         let spv_service = SpvService {
             ckb_cli: ckb_cli.clone(),
             btc_cli: btc_cli.clone(),
             storage: storage.clone(),
+            reorg_in_progress: Default::default(),
+            reorg_refused: Default::default(),
+            bitcoin_tip_height: Default::default(),
+            sync_lock: Default::default(),
+            download_concurrency: self.sync.bitcoin_download_concurrency,
+            metrics: Default::default(),
         };
 
-        let _api_service = ApiServiceConfig::new(self.listen_address).start(spv_service.clone());
+        if self.reverify_on_start {
+            log::info!("Verify the SPV instance on chain before serving");
+            while !shutdown.load(atomic::Ordering::SeqCst)
+                && spv_service.sync_storage(&sync_options)? == SyncStatus::Syncing
+            {}
+            if !spv_service.compare_with_onchain()? {
+                log::error!(
+                    "The SPV instance on chain is diverged from the local storage, \
+                    proofs may fail until it is reorganized"
+                );
+            }
+        }
+
+        let mut api_servers = self.api.config().start(spv_service.clone())?;
+        if let Some(ref address) = self.api.metrics_listen_address {
+            api_servers.push(start_metrics_server(address, spv_service.clone())?);
+        }
 
-        let mut prev_tx_hash: Option<H256> = None;
+        if let Some(interval) = self.compare_with_onchain {
+            let spv_service = spv_service.clone();
+            thread::spawn(move || loop {
+                thread::sleep(time::Duration::from_secs(interval));
+                if let Err(err) = spv_service.compare_with_onchain() {
+                    log::warn!("Failed to compare SPV instance with storage: {err}");
+                }
+            });
+        }
+
+        // The balance is only checked when it's watched or exported.
+        if self.min_ckb_balance.is_some() || self.api.metrics_listen_address.is_some() {
+            let spv_service = spv_service.clone();
+            let (deployer, _) = self.ckb.deployer()?;
+            let min_balance = self.min_ckb_balance.as_ref().map(|balance| balance.0);
+            let interval = self.on_chain_poll_interval();
+            thread::spawn(move || loop {
+                match spv_service.check_ckb_balance((&deployer).into()) {
+                    Ok(balance) => {
+                        let balance_str = HumanCapacity::from(balance);
+                        match min_balance {
+                            Some(min) if balance < min => {
+                                log::warn!(
+                                    "The CKB balance of {deployer} is {balance_str}, \
+                                    which is less than {}, please top it up before \
+                                    SPV updates fail",
+                                    HumanCapacity::from(min)
+                                );
+                            }
+                            _ => log::debug!("The CKB balance of {deployer} is {balance_str}"),
+                        }
+                    }
+                    Err(err) => log::warn!("Failed to check the CKB balance of {deployer}: {err}"),
+                }
+                thread::sleep(time::Duration::from_secs(interval));
+            });
+        }
+
+        let tx_limits = if self.auto_spv_headers_update_limit {
+            let max_cycles = self.max_tx_verify_cycles;
+            let max_size: u64 = ckb_cli.tx_pool_info()?.tx_size_limit.into();
+            log::info!(
+                "The CKB transaction cycles limit is {max_cycles}, the size limit is {max_size}"
+            );
+            Some((max_cycles, max_size))
+        } else {
+            None
+        };
+
+        let mut updater = self.updater();
+        let mut prev_tx: Option<PendingSpvTransaction> = None;
+        let mut prev_reorg_time: Option<time::Instant> = None;
+        let mut prev_chain_assert_time: Option<time::Instant> = None;
+        let mut updates_halted = false;
+        let mut spv_headers_update_limit = self.spv_headers_update_limit;
+
+        // Only stop between two iterations, never in the middle of an update.
+        while !shutdown.load(atomic::Ordering::SeqCst) {
+            match spv_service.sync_storage(&sync_options)? {
+                SyncStatus::Synced => {}
+                SyncStatus::Syncing => continue,
+                SyncStatus::ReorgRefused => {
+                    // Still serve the APIs with the storage, but don't update the SPV
+                    // instance on chain with a chain which is not trusted.
+                    log::warn!("Skip updating the SPV instance since the reorg is refused");
+                    self.take_a_break(block_notifier.as_ref(), &shutdown);
+                    continue;
+                }
+            }
 
-        loop {
-            if !spv_service.sync_storage(self.bitcoin_headers_download_batch_size)? {
+            if let Some(interval) = self.header_chain_assert_interval {
+                let is_due = prev_chain_assert_time
+                    .map(|prev| prev.elapsed().as_secs() >= interval)
+                    .unwrap_or(true);
+                if is_due {
+                    // Keep serving the APIs, only halt the updates until the
+                    // storage is repaired, such as rolled back by a reorg.
+                    match storage.assert_tip_header_chained() {
+                        Ok(()) if updates_halted => {
+                            log::info!(
+                                "Resume updating SPV instance since the storage is repaired"
+                            );
+                            updates_halted = false;
+                        }
+                        Ok(()) => {}
+                        Err(err) => {
+                            log::error!("Halt updating SPV instance since {err}");
+                            updates_halted = true;
+                        }
+                    }
+                    spv_service.metrics.set_updates_halted(updates_halted);
+                    prev_chain_assert_time = Some(time::Instant::now());
+                }
+            }
+            if updates_halted {
+                self.take_a_break(block_notifier.as_ref(), &shutdown);
                 continue;
             }
 
-            if let Some(ref tx_hash) = prev_tx_hash {
+            if let Some(keep_depth) = self.prune_keep_depth {
+                prune_headers(&spv_service, keep_depth)?;
+            }
+
+            if let Some(ref prev) = prev_tx {
+                let tx_hash = &prev.tx_hash;
                 let tx_status = ckb_cli
                     .get_transaction_status(tx_hash.to_owned())?
                     .tx_status
                     .status;
 
                 match tx_status {
+                    // Proposed transactions can't be replaced.
+                    Status::Pending if self.is_tx_timed_out(prev) => {
+                        let fee_rate = bump_fee_rate(prev.fee_rate);
+                        log::warn!(
+                            "The previous transaction {tx_hash:#x} is not committed in time, \
+                            replace it with a higher fee rate {}",
+                            FeeRate(fee_rate)
+                        );
+                        updater.replacement_fee_rate = Some(fee_rate);
+                    }
                     Status::Pending | Status::Proposed => {
                         // To avoid PoolRejectedDuplicatedTransaction
                         log::debug!("Waiting for the previous transaction {tx_hash:#x}");
-                        self.take_a_break();
+                        self.wait_for_on_chain(&shutdown);
                         continue;
                     }
-                    Status::Committed | Status::Unknown | Status::Rejected => {}
+                    Status::Committed | Status::Unknown | Status::Rejected => {
+                        spv_service.set_reorg_in_progress(false);
+                        updater.replacement_fee_rate = None;
+                    }
                 }
             }
 
@@ -176,7 +514,7 @@ impl Args {
                     match stg_tip_height.cmp(&spv_tip_height) {
                         Ordering::Less | Ordering::Equal => {
                             log::info!("No updates, sleep for a while");
-                            self.take_a_break();
+                            self.take_a_break(block_notifier.as_ref(), &shutdown);
                             continue;
                         }
                         Ordering::Greater => {}
@@ -186,39 +524,69 @@ impl Args {
 
                     let (spv_client, spv_update) = storage.generate_spv_client_and_spv_update(
                         spv_tip_height,
-                        self.spv_headers_update_limit,
+                        spv_headers_update_limit,
                         input.info.get_flags()?,
                     )?;
 
-                    let tx_hash =
-                        self.update_spv_cells(&spv_service, input, spv_client, spv_update);
-
-                    if let Err(e) = &tx_hash {
-                        log::warn!("Failed to update SPV instance: {:?}", e);
+                    let sent = updater.update_spv_cells(&storage, input, spv_client, spv_update);
+
+                    match &sent {
+                        Ok(sent) => {
+                            log::info!("Sent a transaction to update SPV instance: {sent}");
+                            spv_service.metrics.inc_sent_txs("update");
+                            if let (Some((max_cycles, max_size)), Some(cycles)) =
+                                (tx_limits, sent.cycles)
+                            {
+                                if let Some(limit) = derive_headers_update_limit(
+                                    spv_headers_update_limit,
+                                    sent.headers_count,
+                                    (cycles, sent.size),
+                                    (max_cycles, max_size),
+                                ) {
+                                    log::debug!("The limit of headers in each update is {limit}");
+                                    spv_headers_update_limit = limit;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            log::warn!("Failed to update SPV instance: {:?}", e);
+                            spv_headers_update_limit = self.spv_headers_update_limit;
+                        }
                     }
 
-                    prev_tx_hash = tx_hash.ok();
+                    prev_tx = track_sent_tx(sent, prev_tx.take(), updater.replacement_fee_rate);
                 }
                 SpvOperation::Reorg(input) => {
+                    if self.is_reorg_cooling_down(prev_reorg_time) {
+                        self.wait_for_on_chain(&shutdown);
+                        continue;
+                    }
+
                     log::info!("Try to reorg SPV instance");
 
                     let spv_tip_height = input.curr.client.headers_mmr_root.max_height;
 
                     let flags = input.info.get_flags()?;
                     let limit = match flags.into() {
-                        BitcoinChainType::Testnet => self.spv_headers_update_limit,
+                        BitcoinChainType::Testnet => spv_headers_update_limit,
                         _ => NonZeroU32::MAX,
                     };
                     let (spv_client, spv_update) =
                         storage.generate_spv_client_and_spv_update(spv_tip_height, limit, flags)?;
 
-                    let tx_hash = self.reorg_spv_cells(&spv_service, input, spv_client, spv_update);
+                    let sent = updater.reorg_spv_cells(&storage, input, spv_client, spv_update);
 
-                    if let Err(e) = &tx_hash {
-                        log::warn!("Failed to reorg SPV instance: {:?}", e);
+                    match &sent {
+                        Ok(sent) => {
+                            log::info!("Sent a transaction to reorg SPV instance: {sent}");
+                            spv_service.metrics.inc_sent_txs("reorg");
+                            spv_service.set_reorg_in_progress(true);
+                            prev_reorg_time = Some(time::Instant::now());
+                        }
+                        Err(e) => log::warn!("Failed to reorg SPV instance: {:?}", e),
                     }
 
-                    prev_tx_hash = tx_hash.ok();
+                    prev_tx = track_sent_tx(sent, prev_tx.take(), updater.replacement_fee_rate);
                 }
                 SpvOperation::Reset(input) => {
                     let flags = input.info.get_flags()?;
@@ -227,6 +595,11 @@ impl Args {
                         return Err(Error::other(msg));
                     }
 
+                    if self.is_reorg_cooling_down(prev_reorg_time) {
+                        self.wait_for_on_chain(&shutdown);
+                        continue;
+                    }
+
                     log::info!(
                         "stale length: {:?}, clients count: {:?}",
                         input.stale.len(),
@@ -239,49 +612,138 @@ impl Args {
 
                     let (spv_client, spv_update) = storage.generate_spv_client_and_spv_update(
                         spv_tip_height,
-                        self.spv_headers_update_limit,
+                        spv_headers_update_limit,
                         flags,
                     )?;
 
-                    let tx_hash = self.reorg_spv_cells(&spv_service, input, spv_client, spv_update);
+                    let sent = updater.reorg_spv_cells(&storage, input, spv_client, spv_update);
 
-                    if let Err(e) = &tx_hash {
-                        log::warn!("Failed to reset SPV instance: {:?}", e);
+                    match &sent {
+                        Ok(sent) => {
+                            log::info!("Sent a transaction to reset SPV instance: {sent}");
+                            spv_service.metrics.inc_sent_txs("reset");
+                            spv_service.set_reorg_in_progress(true);
+                            prev_reorg_time = Some(time::Instant::now());
+                        }
+                        Err(e) => log::warn!("Failed to reset SPV instance: {:?}", e),
                     }
 
-                    prev_tx_hash = tx_hash.ok();
+                    prev_tx = track_sent_tx(sent, prev_tx.take(), updater.replacement_fee_rate);
                 }
             }
         }
 
-        // TODO Handle Ctrl-C and clean resources before exit.
+        log::info!("Stopping the Bitcoin SPV service");
+        for server in api_servers {
+            server.close();
+        }
+        storage.flush()?;
+        log::info!("The Bitcoin SPV service is stopped");
+
+        Ok(())
+    }
+
+    fn is_reorg_cooling_down(&self, prev_reorg_time: Option<time::Instant>) -> bool {
+        if self.reorg_cooldown == 0 {
+            return false;
+        }
+        if let Some(prev) = prev_reorg_time {
+            let elapsed = prev.elapsed().as_secs();
+            if elapsed < self.reorg_cooldown {
+                log::info!(
+                    "The previous reorg was sent {elapsed} seconds ago, \
+                    wait for the cooldown ({} seconds) to reorg again",
+                    self.reorg_cooldown
+                );
+                return true;
+            }
+        }
+        false
+    }
+
+    fn is_tx_timed_out(&self, tx: &PendingSpvTransaction) -> bool {
+        self.tx_confirmation_timeout
+            .map(|timeout| tx.sent_at.elapsed().as_secs() >= timeout)
+            .unwrap_or(false)
+    }
+
+    fn take_a_break(&self, block_notifier: Option<&BlockNotifier>, shutdown: &atomic::AtomicBool) {
+        let interval = time::Duration::from_secs(self.interval);
+        if let Some(notifier) = block_notifier {
+            if wait_unless_shutdown(shutdown, interval, |step| notifier.wait_timeout(step)) {
+                log::info!("Wake up since a new block is notified");
+            }
+        } else {
+            wait_unless_shutdown(shutdown, interval, |step| {
+                thread::sleep(step);
+                false
+            });
+        }
+    }
+
+    fn on_chain_poll_interval(&self) -> u64 {
+        self.on_chain_poll_interval.unwrap_or(self.interval)
+    }
+
+    fn wait_for_on_chain(&self, shutdown: &atomic::AtomicBool) {
+        let interval = time::Duration::from_secs(self.on_chain_poll_interval());
+        wait_unless_shutdown(shutdown, interval, |step| {
+            thread::sleep(step);
+            false
+        });
     }
 
+    fn updater(&self) -> SpvCellsUpdater<'_> {
+        SpvCellsUpdater {
+            ckb: &self.ckb,
+            excluded_cells: &self.excluded_cells,
+            spv_owner_opt: &self.spv_owner_opt,
+            output_capacity_margin: self.output_capacity_margin.as_ref(),
+            fee_estimation_mode: self.fee_estimation_mode,
+            allow_capacity_decrease: self.allow_capacity_decrease,
+            auto_spv_headers_update_limit: self.auto_spv_headers_update_limit,
+            replacement_fee_rate: None,
+            weight_ratio: Default::default(),
+            dry_run: self.dry_run,
+        }
+    }
+}
+
+impl SpvCellsUpdater<'_> {
     pub(crate) fn update_spv_cells(
         &self,
-        spv: &SpvService,
+        storage: &Storage,
+        update_input: SpvUpdateInput,
+        spv_client: SpvClient,
+        spv_update: packed::SpvUpdate,
+    ) -> Result<SentSpvTransaction> {
+        let fee_rate = self.cycles_aware_fee_rate(self.fee_rate()?);
+        let built =
+            self.build_update_spv_cells(storage, update_input, spv_client, spv_update, fee_rate)?;
+        self.send_spv_transaction(storage, built)
+    }
+
+    fn build_update_spv_cells(
+        &self,
+        storage: &Storage,
         update_input: SpvUpdateInput,
         mut spv_client: SpvClient,
         spv_update: packed::SpvUpdate,
-    ) -> Result<H256> {
+        fee_rate: u64,
+    ) -> Result<BuiltSpvTransaction> {
         let network_info =
             NetworkInfo::new(self.ckb.network, self.ckb.ckb_endpoint.as_str().to_owned());
         let configuration = {
             let mut tmp = TransactionBuilderConfiguration::new_with_network(network_info.clone())?;
-            tmp.fee_rate = self.ckb.fee_rate()?;
+            tmp.fee_rate = fee_rate;
             tmp
         };
 
-        let (deployer, deployer_key) = SecretKey::from_slice(&self.ckb.private_key.as_ref()[..])
-            .map(|sk| {
-                let pk = sk.public_key(&SECP256K1);
-                let payload = CkbAddressPayload::from_pubkey(&pk);
-                let address = CkbAddress::new(self.ckb.network, payload, true);
-                (address, sk)
-            })?;
+        let (deployer, deployer_signer) = self.ckb.deployer()?;
         log::debug!("The SPV cells will be updated by {deployer}");
 
         let iterator = InputIterator::new_with_address(&[deployer.clone()], &network_info);
+        let iterator = self.excluded_cells.filter(iterator);
         let mut tx_builder = TransactionBuilder::default();
 
         let spv_inputs = {
@@ -295,7 +757,7 @@ impl Args {
         };
         tx_builder.inputs(spv_inputs);
 
-        let spv_contract_cell_dep = spv.storage.spv_contract_cell_dep()?;
+        let spv_contract_cell_dep = storage.spv_contract_cell_dep()?;
         tx_builder.cell_dep(spv_contract_cell_dep);
         let spv_client_curr_cell_dep = CellDep::new_builder()
             .out_point(update_input.curr.cell.out_point)
@@ -327,6 +789,19 @@ impl Args {
             }
         }
 
+        let next_tip_client_id = update_input.info.next_tip_client_id();
+        if update_input.next.client.id != next_tip_client_id {
+            let msg = format!(
+                "the id of the next SPV client is unexpected, expect {next_tip_client_id} \
+                but got {}",
+                update_input.next.client.id
+            );
+            return Err(Error::other(msg));
+        }
+
+        let tip_height = spv_client.headers_mmr_root.max_height;
+        let headers_count = spv_update.headers().len();
+
         let spv_outputs_data = {
             spv_client.id = update_input.next.client.id;
             let mut spv_info = update_input.info.info;
@@ -335,56 +810,9 @@ impl Args {
             let packed_spv_client: packed::SpvClient = spv_client.pack();
             vec![packed_spv_info.as_bytes(), packed_spv_client.as_bytes()]
         };
-        let spv_outputs: Vec<CellOutput> = if let Some(lock_script) =
-            self.spv_owner_opt.lock_script()
-        {
-            let spv_info_capacity = Capacity::bytes(spv_outputs_data[0].len()).map_err(|err| {
-                let msg = format!(
-                    "failed to calculate the capacity for Bitcoin SPV info cell since {err}"
-                );
-                Error::other(msg)
-            })?;
-            let spv_client_capacity =
-                Capacity::bytes(spv_outputs_data[1].len()).map_err(|err| {
-                    let msg = format!(
-                        "failed to calculate the capacity for Bitcoin SPV client cell since {err}"
-                    );
-                    Error::other(msg)
-                })?;
-            let info_output = update_input
-                .info
-                .cell
-                .output
-                .clone()
-                .as_builder()
-                .lock(lock_script.clone())
-                .build_exact_capacity(spv_info_capacity)
-                .map_err(|err| {
-                    let msg = format!(
-                        "failed to sum the total capacity for Bitcoin SPV info cell since {err}"
-                    );
-                    Error::other(msg)
-                })?;
-            let client_output = update_input
-                .next
-                .cell
-                .output
-                .clone()
-                .as_builder()
-                .lock(lock_script)
-                .build_exact_capacity(spv_client_capacity)
-                .map_err(|err| {
-                    let msg = format!(
-                        "failed to sum the total capacity for Bitcoin SPV client cell since {err}"
-                    );
-                    Error::other(msg)
-                })?;
-            vec![info_output, client_output]
-        } else {
-            vec![
-                update_input.info.cell.output.clone(),
-                update_input.next.cell.output.clone(),
-            ]
+        let spv_outputs = {
+            let inputs = [&update_input.info.cell, &update_input.next.cell];
+            self.spv_outputs(storage, &inputs, &spv_outputs_data)?
         };
         tx_builder.outputs(spv_outputs);
         tx_builder.outputs_data(spv_outputs_data.iter().map(Pack::pack));
@@ -444,6 +872,8 @@ impl Args {
             let _ = change_builder.check_balance(spv_info_input, &mut tx_builder);
             let _ = change_builder.check_balance(spv_client_input, &mut tx_builder);
         };
+        let mut inputs_capacity =
+            total_capacity(&[&update_input.info.cell, &update_input.next.cell]);
         let contexts = HandlerContexts::default();
 
         let mut tx_with_groups = {
@@ -468,6 +898,7 @@ impl Args {
                     .input_indices
                     .push(input_index);
 
+                inputs_capacity += total_capacity(&[&input.live_cell]);
                 if change_builder.check_balance(input, &mut tx_builder) {
                     let mut script_groups: Vec<ScriptGroup> = lock_groups
                         .into_values()
@@ -499,45 +930,58 @@ impl Args {
             Error::other(msg)
         })?;
 
-        TransactionSigner::new(&network_info).sign_transaction(
-            &mut tx_with_groups,
-            &SignContexts::new_sighash(vec![deployer_key]),
+        let fee = check_tx_balance(
+            &tx_with_groups,
+            inputs_capacity,
+            spv_outputs_data.len(),
+            &(&deployer).into(),
         )?;
 
-        let tx_json = TransactionView::from(tx_with_groups.get_tx_view().clone());
-        let tx_hash = self
-            .ckb
-            .client()
-            .send_transaction_ext(tx_json, self.dry_run)?;
+        deployer_signer.sign_transaction(&mut tx_with_groups, &(&deployer).into())?;
 
-        Ok(tx_hash)
+        Ok(BuiltSpvTransaction {
+            tx_view: tx_with_groups.get_tx_view().clone(),
+            tip_height,
+            headers_count,
+            fee,
+            fee_rate,
+        })
     }
 
     pub(crate) fn reorg_spv_cells(
         &self,
-        spv: &SpvService,
+        storage: &Storage,
+        reorg_input: SpvReorgInput,
+        spv_client: SpvClient,
+        spv_update: packed::SpvUpdate,
+    ) -> Result<SentSpvTransaction> {
+        let fee_rate = self.cycles_aware_fee_rate(self.fee_rate()?);
+        let built =
+            self.build_reorg_spv_cells(storage, reorg_input, spv_client, spv_update, fee_rate)?;
+        self.send_spv_transaction(storage, built)
+    }
+
+    fn build_reorg_spv_cells(
+        &self,
+        storage: &Storage,
         reorg_input: SpvReorgInput,
         mut spv_client: SpvClient,
         spv_update: packed::SpvUpdate,
-    ) -> Result<H256> {
+        fee_rate: u64,
+    ) -> Result<BuiltSpvTransaction> {
         let network_info =
             NetworkInfo::new(self.ckb.network, self.ckb.ckb_endpoint.as_str().to_owned());
         let configuration = {
             let mut tmp = TransactionBuilderConfiguration::new_with_network(network_info.clone())?;
-            tmp.fee_rate = self.ckb.fee_rate()?;
+            tmp.fee_rate = fee_rate;
             tmp
         };
 
-        let (deployer, deployer_key) = SecretKey::from_slice(&self.ckb.private_key.as_ref()[..])
-            .map(|sk| {
-                let pk = sk.public_key(&SECP256K1);
-                let payload = CkbAddressPayload::from_pubkey(&pk);
-                let address = CkbAddress::new(self.ckb.network, payload, true);
-                (address, sk)
-            })?;
+        let (deployer, deployer_signer) = self.ckb.deployer()?;
         log::debug!("The SPV cells will be updated by {deployer}");
 
         let iterator = InputIterator::new_with_address(&[deployer.clone()], &network_info);
+        let iterator = self.excluded_cells.filter(iterator);
         let mut tx_builder = TransactionBuilder::default();
 
         let spv_inputs = {
@@ -555,7 +999,7 @@ impl Args {
         };
         tx_builder.inputs(spv_inputs);
 
-        let spv_contract_cell_dep = spv.storage.spv_contract_cell_dep()?;
+        let spv_contract_cell_dep = storage.spv_contract_cell_dep()?;
         tx_builder.cell_dep(spv_contract_cell_dep);
         let spv_client_curr_cell_dep = CellDep::new_builder()
             .out_point(reorg_input.curr.cell.out_point)
@@ -593,6 +1037,9 @@ impl Args {
             }
         }
 
+        let tip_height = spv_client.headers_mmr_root.max_height;
+        let headers_count = spv_update.headers().len();
+
         let spv_outputs_data = {
             let mut spv_info = reorg_input.info.info.clone();
             spv_info.tip_client_id = reorg_input.info.next_tip_client_id();
@@ -605,58 +1052,12 @@ impl Args {
             }
             outputs_data
         };
-        let spv_outputs = if let Some(lock_script) = self.spv_owner_opt.lock_script() {
-            let spv_info_capacity = Capacity::bytes(spv_outputs_data[0].len()).map_err(|err| {
-                let msg = format!(
-                    "failed to calculate the capacity for Bitcoin SPV info cell since {err}"
-                );
-                Error::other(msg)
-            })?;
-            let spv_client_capacity =
-                Capacity::bytes(spv_outputs_data[1].len()).map_err(|err| {
-                    let msg = format!(
-                        "failed to calculate the capacity for Bitcoin SPV client cell since {err}"
-                    );
-                    Error::other(msg)
-                })?;
-            let info_output = reorg_input
-                .info
-                .cell
-                .output
-                .clone()
-                .as_builder()
-                .lock(lock_script.clone())
-                .build_exact_capacity(spv_info_capacity)
-                .map_err(|err| {
-                    let msg = format!(
-                        "failed to sum the total capacity for Bitcoin SPV info cell since {err}"
-                    );
-                    Error::other(msg)
-                })?;
-            let mut outputs = vec![info_output];
-            for client in &reorg_input.stale {
-                let client_output = client
-                    .cell
-                    .output
-                    .clone()
-                    .as_builder()
-                    .lock(lock_script.clone())
-                    .build_exact_capacity(spv_client_capacity)
-                    .map_err(|err| {
-                        let msg = format!(
-                            "failed to sum the total capacity for Bitcoin SPV client cell since {err}"
-                        );
-                        Error::other(msg)
-                    })?;
-                outputs.push(client_output);
-            }
-            outputs
-        } else {
-            let mut outputs = vec![reorg_input.info.cell.output.clone()];
-            for client in &reorg_input.stale {
-                outputs.push(client.cell.output.clone());
-            }
-            outputs
+        let spv_outputs = {
+            let inputs = [&reorg_input.info.cell]
+                .into_iter()
+                .chain(reorg_input.stale.iter().map(|client| &client.cell))
+                .collect::<Vec<_>>();
+            self.spv_outputs(storage, &inputs, &spv_outputs_data)?
         };
         tx_builder.outputs(spv_outputs);
         tx_builder.outputs_data(spv_outputs_data.iter().map(Pack::pack));
@@ -720,6 +1121,11 @@ impl Args {
                 let _ = change_builder.check_balance(spv_client_input, &mut tx_builder);
             }
         };
+        let mut inputs_capacity = {
+            let mut cells = vec![&reorg_input.info.cell];
+            cells.extend(reorg_input.stale.iter().map(|client| &client.cell));
+            total_capacity(&cells)
+        };
         let contexts = HandlerContexts::default();
 
         let mut tx_with_groups = {
@@ -744,6 +1150,7 @@ impl Args {
                     .input_indices
                     .push(input_index);
 
+                inputs_capacity += total_capacity(&[&input.live_cell]);
                 if change_builder.check_balance(input, &mut tx_builder) {
                     let mut script_groups: Vec<ScriptGroup> = lock_groups
                         .into_values()
@@ -775,21 +1182,567 @@ impl Args {
             Error::other(msg)
         })?;
 
-        TransactionSigner::new(&network_info).sign_transaction(
-            &mut tx_with_groups,
-            &SignContexts::new_sighash(vec![deployer_key]),
+        let fee = check_tx_balance(
+            &tx_with_groups,
+            inputs_capacity,
+            spv_outputs_data.len(),
+            &(&deployer).into(),
         )?;
 
-        let tx_json = TransactionView::from(tx_with_groups.get_tx_view().clone());
+        deployer_signer.sign_transaction(&mut tx_with_groups, &(&deployer).into())?;
+
+        Ok(BuiltSpvTransaction {
+            tx_view: tx_with_groups.get_tx_view().clone(),
+            tip_height,
+            headers_count,
+            fee,
+            fee_rate,
+        })
+    }
+
+    fn send_spv_transaction(
+        &self,
+        storage: &Storage,
+        built: BuiltSpvTransaction,
+    ) -> Result<SentSpvTransaction> {
+        let size = built.tx_view.data().serialized_size_in_block() as u64;
+        let tx_json = TransactionView::from(built.tx_view);
+        let cycles = self.estimate_cycles_if_required(&tx_json)?;
+        if let Some(cycles) = cycles {
+            self.record_weight_ratio(cycles, size, built.fee_rate);
+        }
         let tx_hash = self
             .ckb
             .client()
             .send_transaction_ext(tx_json, self.dry_run)?;
+        if !self.dry_run {
+            storage.save_last_spv_tx_hash(&tx_hash)?;
+        }
+
+        Ok(SentSpvTransaction {
+            tx_hash,
+            tip_height: built.tip_height,
+            headers_count: built.headers_count,
+            fee: built.fee,
+            fee_rate: built.fee_rate,
+            cycles,
+            size,
+        })
+    }
+
+    fn fee_rate(&self) -> Result<u64> {
+        let fee_rate = self.ckb.fee_rate()?;
+        if let Some(replacement_fee_rate) = self.replacement_fee_rate {
+            if replacement_fee_rate > fee_rate {
+                log::info!(
+                    "Use the fee rate {} to replace the stuck transaction",
+                    FeeRate(replacement_fee_rate)
+                );
+                return Ok(replacement_fee_rate);
+            }
+        }
+        Ok(fee_rate)
+    }
+
+    // CKB miners order transactions by the fee rate of their weight, which is
+    // the larger of the size and the size equivalent of the cycles.
+    //
+    // The cycles could only be estimated after the transaction is signed, so
+    // the weight is estimated by the ratio of the previous transaction, then
+    // each transaction is only signed once.
+    // Returns a higher fee rate, if the cycles dominate the weight.
+    fn cycles_aware_fee_rate(&self, fee_rate: u64) -> u64 {
+        if self.fee_estimation_mode != FeeEstimationMode::Cycles {
+            return fee_rate;
+        }
+        match self.weight_ratio.get() {
+            Some(ratio) if ratio > 1.0 => {
+                let cycles_aware_fee_rate = (fee_rate as f64 * ratio).ceil() as u64;
+                log::info!(
+                    "The weight of the previous transaction is {ratio:.2} times its size, \
+                    increase the fee rate from {} to {}",
+                    FeeRate(fee_rate),
+                    FeeRate(cycles_aware_fee_rate)
+                );
+                cycles_aware_fee_rate
+            }
+            _ => fee_rate,
+        }
+    }
+
+    fn record_weight_ratio(&self, cycles: u64, size: u64, fee_rate: u64) {
+        if self.fee_estimation_mode != FeeEstimationMode::Cycles || size == 0 {
+            return;
+        }
+        let weight = ((cycles as f64 * BYTES_PER_CYCLES).ceil() as u64).max(size);
+        let ratio = weight as f64 / size as f64;
+        if ratio > self.weight_ratio.get().unwrap_or(1.0) {
+            log::warn!(
+                "The weight of the transaction is {weight}, which is larger than expected, \
+                since its size is {size} and the cycles are {cycles}; \
+                it's under-prioritized with the fee rate {}",
+                FeeRate(fee_rate)
+            );
+        }
+        self.weight_ratio.set(Some(ratio));
+    }
 
-        Ok(tx_hash)
+    // Only the provided owner rebuilds SPV cells, the owner saved in the
+    // storage is used to replace the lock of SPV cells which are not owned by it.
+    fn spv_outputs<D: AsRef<[u8]>>(
+        &self,
+        storage: &Storage,
+        inputs: &[&LiveCell],
+        outputs_data: &[D],
+    ) -> Result<Vec<CellOutput>> {
+        let inputs = inputs
+            .iter()
+            .map(|cell| cell.output.clone())
+            .collect::<Vec<_>>();
+        build_spv_outputs(
+            &inputs,
+            outputs_data,
+            self.spv_owner_opt.lock_script(),
+            storage.spv_owner_lock()?,
+            self.capacity_margin(),
+            self.allow_capacity_decrease,
+        )
+    }
+
+    fn capacity_margin(&self) -> Capacity {
+        self.output_capacity_margin
+            .map(|margin| Capacity::shannons(margin.0))
+            .unwrap_or_else(Capacity::zero)
+    }
+
+    fn estimate_cycles_if_required(&self, tx_json: &TransactionView) -> Result<Option<u64>> {
+        if !self.auto_spv_headers_update_limit
+            && self.fee_estimation_mode != FeeEstimationMode::Cycles
+        {
+            return Ok(None);
+        }
+        let cycles: u64 = self
+            .ckb
+            .client()
+            .estimate_cycles(tx_json.inner.clone())?
+            .cycles
+            .into();
+        Ok(Some(cycles))
+    }
+}
+
+// Builds the outputs of SPV cells, which are one-to-one with the inputs.
+//
+// SPV cells are rebuilt with the exact capacity (plus the margin), only when
+// an owner is provided. Otherwise, the inputs are kept as is, to keep the
+// extra capacity which was provisioned for future updates; if the owner saved
+// in the storage is different, only the lock is replaced, and the capacity is
+// increased when the new lock requires more.
+fn build_spv_outputs<D: AsRef<[u8]>>(
+    inputs: &[CellOutput],
+    outputs_data: &[D],
+    provided_owner: Option<Script>,
+    saved_owner: Option<Script>,
+    margin: Capacity,
+    allow_capacity_decrease: bool,
+) -> Result<Vec<CellOutput>> {
+    let mut outputs = Vec::with_capacity(inputs.len());
+    for (index, (input, data)) in inputs.iter().zip(outputs_data).enumerate() {
+        let name = if index == 0 { "info" } else { "client" };
+        let data_capacity = Capacity::bytes(data.as_ref().len()).map_err(|err| {
+            let msg =
+                format!("failed to calculate the capacity for Bitcoin SPV {name} cell since {err}");
+            Error::other(msg)
+        })?;
+        let output = if let Some(ref lock_script) = provided_owner {
+            let capacity = data_capacity.safe_add(margin).map_err(|err| {
+                let msg = format!(
+                    "failed to calculate the capacity for Bitcoin SPV {name} cell since {err}"
+                );
+                Error::other(msg)
+            })?;
+            input
+                .clone()
+                .as_builder()
+                .lock(lock_script.clone())
+                .build_exact_capacity(capacity)
+                .map_err(|err| {
+                    let msg = format!(
+                        "failed to sum the total capacity for Bitcoin SPV {name} cell since {err}"
+                    );
+                    Error::other(msg)
+                })?
+        } else if let Some(lock_script) = saved_owner
+            .as_ref()
+            .filter(|lock_script| lock_script.as_slice() != input.lock().as_slice())
+        {
+            let output = input.clone().as_builder().lock(lock_script.clone()).build();
+            let occupied = output.occupied_capacity(data_capacity).map_err(|err| {
+                let msg = format!(
+                    "failed to sum the total capacity for Bitcoin SPV {name} cell since {err}"
+                );
+                Error::other(msg)
+            })?;
+            let capacity: u64 = output.capacity().unpack();
+            if capacity < occupied.as_u64() {
+                output.as_builder().capacity(occupied.pack()).build()
+            } else {
+                output
+            }
+        } else {
+            input.clone()
+        };
+        outputs.push(output);
+    }
+    if provided_owner.is_some() && !allow_capacity_decrease {
+        let inputs_capacity = inputs
+            .iter()
+            .map(|input| Unpack::<u64>::unpack(&input.capacity()))
+            .sum();
+        top_up_spv_cells_capacity(inputs_capacity, &mut outputs)?;
+    }
+    Ok(outputs)
+}
+
+// The lock of SPV cells allows non-owners to update them,
+// only if the total capacity isn't decreased.
+//
+// The first output (the SPV info cell) takes the shortfall, and the change
+// builder collects more inputs to balance it.
+fn top_up_spv_cells_capacity(inputs_capacity: u64, outputs: &mut [CellOutput]) -> Result<()> {
+    let outputs_capacity = outputs
+        .iter()
+        .map(|output| Unpack::<u64>::unpack(&output.capacity()))
+        .sum::<u64>();
+    if outputs_capacity < inputs_capacity {
+        let shortfall = inputs_capacity - outputs_capacity;
+        log::debug!(
+            "top up {} for SPV cells, since the total capacity is decreased from {} to {}",
+            HumanCapacity::from(shortfall),
+            HumanCapacity::from(inputs_capacity),
+            HumanCapacity::from(outputs_capacity)
+        );
+        let output = outputs
+            .first_mut()
+            .ok_or_else(|| Error::other("no SPV cells to top up"))?;
+        let capacity: u64 = output.capacity().unpack();
+        *output = output
+            .clone()
+            .as_builder()
+            .capacity((capacity + shortfall).pack())
+            .build();
+    }
+    Ok(())
+}
+
+// Checks the transaction before signing it, and returns the fee:
+// - the first outputs are SPV cells, and the last output is the change for the deployer;
+// - the total capacity of inputs covers the total capacity of outputs.
+fn check_tx_balance(
+    tx_with_groups: &TransactionWithScriptGroups,
+    inputs_capacity: u64,
+    spv_outputs_count: usize,
+    change_lock: &Script,
+) -> Result<u64> {
+    let tx_view = tx_with_groups.get_tx_view();
+    let outputs_count = tx_view.outputs().len();
+    if outputs_count != spv_outputs_count + 1 {
+        let msg = format!(
+            "expect {spv_outputs_count} SPV cells and 1 change in outputs, \
+            but got {outputs_count} outputs"
+        );
+        return Err(Error::other(msg));
+    }
+    let is_change_for_deployer = tx_view
+        .output(spv_outputs_count)
+        .map(|output| output.lock().as_slice() == change_lock.as_slice())
+        .unwrap_or(false);
+    if !is_change_for_deployer {
+        let msg = "the change in outputs is not for the deployer";
+        return Err(Error::other(msg));
+    }
+    let outputs_capacity = tx_view
+        .outputs_capacity()
+        .map_err(|err| {
+            let msg = format!("failed to sum the total capacity of outputs since {err}");
+            Error::other(msg)
+        })?
+        .as_u64();
+    if inputs_capacity < outputs_capacity {
+        let msg = format!(
+            "the total capacity of inputs {} is less than outputs {}",
+            HumanCapacity::from(inputs_capacity),
+            HumanCapacity::from(outputs_capacity)
+        );
+        return Err(Error::other(msg));
+    }
+    let fee = inputs_capacity - outputs_capacity;
+    log::debug!("The fee of the transaction is {}", HumanCapacity::from(fee));
+    Ok(fee)
+}
+
+// Tracks the sent transaction.
+// If it failed to replace a stuck transaction, keep tracking the stuck one,
+// and try again with a higher fee rate after another timeout.
+fn track_sent_tx(
+    sent: Result<SentSpvTransaction>,
+    prev: Option<PendingSpvTransaction>,
+    replacement_fee_rate: Option<u64>,
+) -> Option<PendingSpvTransaction> {
+    match (sent, replacement_fee_rate) {
+        (Ok(sent), _) => Some(PendingSpvTransaction {
+            tx_hash: sent.tx_hash,
+            fee_rate: sent.fee_rate,
+            sent_at: time::Instant::now(),
+        }),
+        (Err(_), Some(fee_rate)) => prev.map(|prev| PendingSpvTransaction {
+            fee_rate,
+            sent_at: time::Instant::now(),
+            ..prev
+        }),
+        (Err(_), None) => None,
+    }
+}
+
+// The replacement should pay more fee than the replaced transaction, by
+// `min_rbf_rate` at least.
+fn bump_fee_rate(fee_rate: u64) -> u64 {
+    fee_rate.saturating_add(fee_rate.max(MIN_RBF_FEE_RATE))
+}
+
+impl fmt::Display for SentSpvTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#x} (tip: header#{:07}, headers: {}, fee: {}, fee rate: {})",
+            self.tx_hash,
+            self.tip_height,
+            self.headers_count,
+            HumanCapacity::from(self.fee),
+            FeeRate(self.fee_rate)
+        )
+    }
+}
+
+// The cycles and the size grow linearly with the count of headers, plus a fixed
+// cost, so the proportional estimation never exceeds the limits.
+//
+// The limit grows at most `HEADERS_UPDATE_LIMIT_MAX_GROWTH` times of the current
+// limit, to avoid swinging up and down.
+fn derive_headers_update_limit(
+    curr_limit: NonZeroU32,
+    headers_count: usize,
+    (cycles, size): (u64, u64),
+    (max_cycles, max_size): (u64, u64),
+) -> Option<NonZeroU32> {
+    if cycles == 0 || size == 0 {
+        return None;
+    }
+    let proportional = |used: u64, max: u64| {
+        let max = max.saturating_mul(HEADERS_UPDATE_LIMIT_SAFETY_PERCENT) / 100;
+        (headers_count as u64).saturating_mul(max) / used
+    };
+    let limit = proportional(cycles, max_cycles)
+        .min(proportional(size, max_size))
+        .min(u64::from(
+            curr_limit
+                .get()
+                .saturating_mul(HEADERS_UPDATE_LIMIT_MAX_GROWTH),
+        ));
+    NonZeroU32::new(u32::try_from(limit).unwrap_or(u32::MAX))
+}
+
+// Prunes the headers deeper than the depth, but keeps the whole difficulty
+// epoch, since its first header is required to calculate the next target.
+//
+// The SPV instance on chain could fall behind, or be reorganized down to its
+// oldest SPV client, then the headers above that client are required to update
+// it; so the headers since the difficulty epoch of that client are kept too.
+//
+// The SPV instance could also be reset to `SPV_RESET_TIP_OFFSET` below its tip,
+// so the headers since there are kept as well.
+fn prune_headers(spv_service: &SpvService, keep_depth: u32) -> Result<()> {
+    let storage = &spv_service.storage;
+    let (stg_tip_height, _) = storage.tip_state()?;
+    let height = prune_height(stg_tip_height, keep_depth, iter::empty());
+    // Nothing more to prune, don't bother the CKB node.
+    if height == 0 || storage.is_bitcoin_header_pruned(height - 1)? {
+        return Ok(());
+    }
+    let spv_type_script = storage.spv_contract_type_script()?;
+    let ins = spv_service.ckb_cli.find_spv_cells(spv_type_script)?;
+    let client_heights = ins
+        .clients
+        .values()
+        .map(|cell| cell.client.headers_mmr_root.max_height);
+    let height = prune_height(stg_tip_height, keep_depth, client_heights);
+    let count = storage.prune_headers_below(height)?;
+    if count > 0 {
+        log::info!("[storage] pruned {count} headers below header#{height:07}");
+    }
+    Ok(())
+}
+
+// The headers below the returned height could be pruned, it's always the start
+// of a difficulty epoch.
+fn prune_height<I>(stg_tip_height: u32, keep_depth: u32, client_heights: I) -> u32
+where
+    I: Iterator<Item = u32> + Clone,
+{
+    let mut height = stg_tip_height.saturating_sub(keep_depth.max(SPV_RESET_TIP_OFFSET));
+    if let Some(lowest) = client_heights.clone().min() {
+        height = height.min(lowest);
+    }
+    if let Some(highest) = client_heights.max() {
+        height = height.min(highest.saturating_sub(SPV_RESET_TIP_OFFSET));
+    }
+    height / DIFFCHANGE_INTERVAL * DIFFCHANGE_INTERVAL
+}
+
+fn total_capacity(cells: &[&LiveCell]) -> u64 {
+    cells
+        .iter()
+        .map(|cell| Unpack::<u64>::unpack(&cell.output.capacity()))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use ckb_types::{
+        bytes::Bytes,
+        core::{Capacity, ScriptHashType},
+        packed::{CellOutput, Script},
+        prelude::*,
+    };
+
+    use std::num::NonZeroU32;
+
+    use super::{build_spv_outputs, derive_headers_update_limit, prune_height};
+
+    fn lock_script(arg: u8) -> Script {
+        Script::new_builder()
+            .hash_type(ScriptHashType::Type.into())
+            .args(Bytes::from(vec![arg; 20]).pack())
+            .build()
+    }
+
+    fn spv_cell(lock_script: Script, capacity: Capacity) -> CellOutput {
+        let type_script = Script::new_builder()
+            .hash_type(ScriptHashType::Type.into())
+            .args(Bytes::from(vec![0xff; 32]).pack())
+            .build();
+        CellOutput::new_builder()
+            .lock(lock_script)
+            .type_(Some(type_script).pack())
+            .capacity(capacity.pack())
+            .build()
+    }
+
+    fn capacity_of(output: &CellOutput) -> u64 {
+        output.capacity().unpack()
+    }
+
+    #[test]
+    fn test_over_provisioned_spv_cells_keep_capacity() {
+        let owner = lock_script(0x11);
+        let info_capacity = Capacity::bytes(1_000).unwrap();
+        let client_capacity = Capacity::bytes(10_000).unwrap();
+        let inputs = vec![
+            spv_cell(owner.clone(), info_capacity),
+            spv_cell(owner.clone(), client_capacity),
+        ];
+        let outputs_data = vec![vec![0u8; 50], vec![0u8; 300]];
+
+        for saved_owner in [None, Some(owner.clone())] {
+            let outputs = build_spv_outputs(
+                &inputs,
+                &outputs_data,
+                None,
+                saved_owner,
+                Capacity::zero(),
+                false,
+            )
+            .unwrap();
+            assert_eq!(outputs.len(), 2);
+            assert_eq!(outputs[0].as_slice(), inputs[0].as_slice());
+            assert_eq!(outputs[1].as_slice(), inputs[1].as_slice());
+        }
+
+        let new_owner = lock_script(0x22);
+        let outputs = build_spv_outputs(
+            &inputs,
+            &outputs_data,
+            None,
+            Some(new_owner.clone()),
+            Capacity::zero(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(outputs[0].lock().as_slice(), new_owner.as_slice());
+        assert_eq!(outputs[1].lock().as_slice(), new_owner.as_slice());
+        assert_eq!(capacity_of(&outputs[0]), info_capacity.as_u64());
+        assert_eq!(capacity_of(&outputs[1]), client_capacity.as_u64());
+    }
+
+    #[test]
+    fn test_provided_owner_rebuilds_spv_cells() {
+        let inputs = vec![
+            spv_cell(lock_script(0x11), Capacity::bytes(1_000).unwrap()),
+            spv_cell(lock_script(0x11), Capacity::bytes(10_000).unwrap()),
+        ];
+        let outputs_data = vec![vec![0u8; 50], vec![0u8; 300]];
+        let new_owner = lock_script(0x22);
+        let margin = Capacity::bytes(1).unwrap();
+        let outputs = build_spv_outputs(
+            &inputs,
+            &outputs_data,
+            Some(new_owner.clone()),
+            None,
+            margin,
+            false,
+        )
+        .unwrap();
+
+        let data_capacity = Capacity::bytes(300).unwrap().safe_add(margin).unwrap();
+        let client_capacity = outputs[1].occupied_capacity(data_capacity).unwrap();
+        assert_eq!(outputs[1].lock().as_slice(), new_owner.as_slice());
+        assert_eq!(capacity_of(&outputs[1]), client_capacity.as_u64());
+        // The total capacity isn't decreased, the info cell takes the rest.
+        let total = outputs.iter().map(capacity_of).sum::<u64>();
+        assert_eq!(total, Capacity::bytes(11_000).unwrap().as_u64());
+    }
+
+    #[test]
+    fn test_derive_headers_update_limit() {
+        let limit = |curr: u32, count: usize, used: (u64, u64)| {
+            let curr = NonZeroU32::new(curr).unwrap();
+            derive_headers_update_limit(curr, count, used, (70_000_000, 500_000))
+                .map(NonZeroU32::get)
+        };
+        // Limited by the growth: 10 * 2.
+        assert_eq!(limit(10, 10, (7_000_000, 10_000)), Some(20));
+        // Limited by cycles: 10 * 56_000_000 / 7_000_000.
+        assert_eq!(limit(100, 10, (7_000_000, 10_000)), Some(80));
+        // Limited by size: 10 * 400_000 / 100_000.
+        assert_eq!(limit(100, 10, (1_000_000, 100_000)), Some(40));
+        // The limit shrinks without the growth cap.
+        assert_eq!(limit(100, 100, (140_000_000, 10_000)), Some(40));
+        // Too large to fit even one header.
+        assert_eq!(limit(10, 1, (100_000_000, 10_000)), None);
+        assert_eq!(limit(10, 10, (0, 10_000)), None);
     }
 
-    fn take_a_break(&self) {
-        thread::sleep(time::Duration::from_secs(self.interval));
+    #[test]
+    fn test_prune_height() {
+        let height = |tip: u32, heights: &[u32]| prune_height(tip, 2016, heights.iter().copied());
+        // Only the depth.
+        assert_eq!(height(10_000, &[]), 6048);
+        assert_eq!(height(2015, &[]), 0);
+        // Down to the oldest SPV client.
+        assert_eq!(height(10_000, &[5000, 9000]), 4032);
+        // Down to the height which the SPV instance could be reset to.
+        assert_eq!(height(10_000, &[6100, 6200]), 4032);
+        assert_eq!(height(10_000, &[9000, 9500]), 6048);
+        assert_eq!(height(10_000, &[1000]), 0);
     }
 }
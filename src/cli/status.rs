@@ -0,0 +1,87 @@
+//! The `status` sub-command.
+
+use std::path::PathBuf;
+
+use ckb_bitcoin_spv_verifier::types::prelude::{Pack as VPack, Unpack as VUnpack};
+use ckb_types::prelude::*;
+use clap::Parser;
+
+use crate::{
+    components::Storage,
+    prelude::*,
+    result::{Error, Result},
+};
+
+#[derive(Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    pub(crate) common: super::CommonArgs,
+
+    /// The directory, which stores all cached data.
+    #[arg(long)]
+    pub(crate) data_dir: PathBuf,
+
+    #[clap(flatten)]
+    pub(crate) ckb: super::CkbRoArgs,
+}
+
+impl Args {
+    pub fn execute(&self) -> Result<()> {
+        log::info!("Inspect the local storage and the Bitcoin SPV instance on chain");
+
+        let storage = Storage::new(&self.data_dir)?;
+        if !storage.is_initialized()? {
+            let msg = format!(
+                "user-provided data directory \"{}\" is empty, please initialize it",
+                self.data_dir.display()
+            );
+            return Err(Error::other(msg));
+        }
+
+        let (stg_base_height, stg_base_header) = storage.base_state()?;
+        let stg_base_hash = stg_base_header.block_hash();
+        log::info!("[storage] header#{stg_base_height:07}, {stg_base_hash:#x}; base");
+        let (stg_tip_height, stg_tip_header) = storage.tip_state()?;
+        let stg_tip_hash = stg_tip_header.block_hash();
+        log::info!("[storage] header#{stg_tip_height:07}, {stg_tip_hash:#x}; tip");
+
+        let spv_type_script = storage.spv_contract_type_script()?;
+        let ins = self.ckb.client().find_spv_cells(spv_type_script)?;
+        let tip_client_cell = ins.find_tip_spv_client()?;
+        let spv_header_root = &tip_client_cell.client.headers_mmr_root;
+        log::info!(
+            "[onchain] tip SPV client (id={}) of {} clients, covers headers from {} to {}",
+            ins.info.info.tip_client_id,
+            ins.info.clients_count,
+            spv_header_root.min_height,
+            spv_header_root.max_height,
+        );
+
+        let spv_height = spv_header_root.max_height;
+        if stg_tip_height < spv_height {
+            log::warn!(
+                "[compare] skipped since the storage tip header#{stg_tip_height} \
+                is lower than the SPV tip header#{spv_height}"
+            );
+            return Ok(());
+        }
+        let packed_stg_header_root = storage.generate_headers_root(spv_height)?;
+        let packed_spv_header_root = spv_header_root.pack();
+        if packed_stg_header_root.as_slice() == packed_spv_header_root.as_slice() {
+            log::info!("[compare] the tip SPV client on chain (header#{spv_height}) is matched");
+            if stg_tip_height > spv_height {
+                log::info!(
+                    "[compare] the SPV instance on chain is {} headers behind the storage",
+                    stg_tip_height - spv_height
+                );
+            }
+        } else {
+            log::warn!("[compare] the tip SPV client on chain is diverged from the storage");
+            log::warn!("[onchain] header#{spv_height}; mmr-root {spv_header_root}");
+            let stg_header_root = packed_stg_header_root.unpack();
+            log::warn!("[storage] header#{spv_height}; mmr-root {stg_header_root}");
+        }
+
+        Ok(())
+    }
+}
@@ -2,16 +2,22 @@
 
 use std::path::PathBuf;
 
+use ckb_bitcoin_spv_verifier::types::{
+    core::SpvClient,
+    packed::SpvTypeArgsReader,
+    prelude::{Pack as VPack, Unpack as VUnpack},
+};
 use ckb_sdk::rpc::ResponseFormatGetter as _;
 use ckb_types::{
-    core::DepType,
-    packed::{CellDep, CellOutput, OutPoint},
+    core::{DepType, ScriptHashType},
+    packed::{CellDep, CellOutput, OutPoint, Script},
     prelude::*,
 };
 use clap::Parser;
+use url::Url;
 
 use crate::{
-    components::Storage,
+    components::{PeerClient, Storage},
     prelude::*,
     result::{Error, Result},
     utilities::value_parsers,
@@ -39,6 +45,21 @@ pub struct Args {
     /// An out point of any cell in the target Bitcoin SPV instance.
     #[arg(long, value_parser = value_parsers::OutPointValueParser)]
     pub(crate) spv_cell_out_point: OutPoint,
+
+    /// The JSON-RPC endpoint of another running service, to download headers from.
+    ///
+    /// Only the headers up to the tip SPV client on chain are downloaded, each
+    /// downloaded header is checked for its continuity, and the headers MMR root
+    /// is checked against the tip SPV client, which the proof of work of all
+    /// headers was verified by.
+    /// The headers above the tip SPV client are synced from the Bitcoin node by
+    /// the service.
+    #[arg(long)]
+    pub(crate) bootstrap_from_peer: Option<Url>,
+
+    /// The batch size that how many headers will be downloaded from the peer at once.
+    #[arg(long, default_value = "1000", requires = "bootstrap_from_peer")]
+    pub(crate) bootstrap_batch_size: u32,
 }
 
 impl Args {
@@ -80,6 +101,13 @@ impl Args {
             );
             Error::other(msg)
         })?;
+        check_spv_type_script(&spv_type_script).map_err(|err| {
+            let msg = format!(
+                "input cell (tx-hash: {input_tx_hash:#x}, index: {input_cell_index}) \
+                is not a SPV cell since {err}"
+            );
+            Error::other(msg)
+        })?;
 
         let tip_spv_client_cell = ckb_cli.find_best_spv_client(spv_type_script.clone(), None)?;
         let start_height = tip_spv_client_cell.client.headers_mmr_root.min_height;
@@ -96,6 +124,87 @@ impl Args {
             .build();
         storage.save_cells_state(spv_type_script, spv_contract_cell_dep)?;
 
+        if let Some(ref endpoint) = self.bootstrap_from_peer {
+            self.bootstrap_from_peer(&storage, endpoint, &tip_spv_client_cell.client)?;
+        }
+
         Ok(())
     }
+
+    fn bootstrap_from_peer(
+        &self,
+        storage: &Storage,
+        endpoint: &Url,
+        tip_spv_client: &SpvClient,
+    ) -> Result<()> {
+        let peer_cli = PeerClient::new(endpoint.clone());
+        let peer_tip_height = peer_cli.get_tip_height()?;
+        let spv_height = tip_spv_client.headers_mmr_root.max_height;
+        if peer_tip_height < spv_height {
+            let msg = format!(
+                "the peer is behind the tip SPV client on chain, \
+                its tip is header#{peer_tip_height}, but the SPV client is at header#{spv_height}"
+            );
+            return Err(Error::other(msg));
+        }
+
+        // The headers above the tip SPV client can't be checked against the chain,
+        // a peer could provide headers with any declared difficulty there.
+        let (mut stg_tip_height, _) = storage.tip_state()?;
+        log::info!("Download headers from the peer, up to header#{spv_height}");
+        let batch_size = self.bootstrap_batch_size.max(1);
+        while stg_tip_height < spv_height {
+            let start = stg_tip_height + 1;
+            let end = stg_tip_height.saturating_add(batch_size).min(spv_height);
+            let headers = peer_cli.get_headers(start, end)?;
+            // The continuity of headers is checked when they are appended.
+            (stg_tip_height, _) = storage.append_headers(headers)?;
+            log::info!("[storage] header#{stg_tip_height:07}; downloaded from the peer");
+        }
+
+        let packed_stg_header_root = storage.generate_headers_root(spv_height)?;
+        let packed_spv_header_root = tip_spv_client.headers_mmr_root.pack();
+        if packed_stg_header_root.as_slice() != packed_spv_header_root.as_slice() {
+            // Drop all downloaded headers, the storage could be synced with the Bitcoin node.
+            storage.rollback_to(None)?;
+            let stg_header_root = packed_stg_header_root.unpack();
+            let msg = format!(
+                "the headers from the peer don't match the tip SPV client on chain, \
+                the mmr-root of header#{spv_height} is {stg_header_root}, \
+                but expect {}",
+                tip_spv_client.headers_mmr_root
+            );
+            return Err(Error::other(msg));
+        }
+        log::info!("The headers from the peer match the tip SPV client on chain");
+
+        Ok(())
+    }
+}
+
+// Checks the type script as same as the `init` command creates it.
+fn check_spv_type_script(spv_type_script: &Script) -> Result<()> {
+    let hash_type: u8 = spv_type_script.hash_type().into();
+    match ScriptHashType::try_from(hash_type) {
+        Ok(ScriptHashType::Data1) | Ok(ScriptHashType::Type) => {}
+        _ => {
+            let msg = format!("the hash type ({hash_type}) of its type script is unexpected");
+            return Err(Error::other(msg));
+        }
+    }
+    let args_data = spv_type_script.args().raw_data();
+    let args = SpvTypeArgsReader::from_slice(&args_data)
+        .map_err(|err| {
+            let msg = format!("the args of its type script is invalid since {err}");
+            Error::other(msg)
+        })?
+        .unpack();
+    if args.clients_count < 3 {
+        let msg = format!(
+            "the clients count in the args of its type script should be 3 at least but got {}",
+            args.clients_count
+        );
+        return Err(Error::other(msg));
+    }
+    Ok(())
 }
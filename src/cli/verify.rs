@@ -0,0 +1,179 @@
+//! The `verify` sub-command.
+
+use std::path::PathBuf;
+
+use bitcoin::{consensus::deserialize, MerkleBlock, Txid};
+use ckb_bitcoin_spv_verifier::{
+    types::{core::HeaderDigest, packed, prelude::*},
+    utilities::mmr,
+};
+use clap::Parser;
+
+use crate::{
+    components::Storage,
+    prelude::*,
+    result::{Error, Result},
+    utilities::value_parsers,
+};
+
+#[derive(Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    pub(crate) common: super::CommonArgs,
+
+    /// The directory, which stores all cached data.
+    #[arg(long)]
+    pub(crate) data_dir: PathBuf,
+
+    /// The proof of the transaction, which is the field `proof` in the result
+    /// of the API "getTxProof".
+    #[arg(long, value_parser = value_parsers::PrefixedHexStringValueParser)]
+    pub(crate) tx_proof: String,
+
+    /// The ID of the transaction, which is expected to be proved.
+    #[arg(long)]
+    pub(crate) txid: Txid,
+
+    /// The height of the tip header of the SPV client, which the proof is
+    /// generated for.
+    ///
+    /// If it's not provided, the header proof is checked against the MMR roots
+    /// of all heights from the height of the transaction to the tip of the
+    /// local storage.
+    #[arg(long)]
+    pub(crate) spv_client_height: Option<u32>,
+}
+
+impl Args {
+    pub fn execute(&self) -> Result<()> {
+        log::info!("Verify a transaction proof against the local storage");
+
+        let storage = Storage::new(&self.data_dir)?;
+        if !storage.is_initialized()? {
+            let msg = format!(
+                "user-provided data directory \"{}\" is empty, please initialize it",
+                self.data_dir.display()
+            );
+            return Err(Error::other(msg));
+        }
+
+        let raw_proof = {
+            let hex_bytes = self.tx_proof[2..].as_bytes();
+            let mut decoded = vec![0u8; hex_bytes.len() / 2];
+            faster_hex::hex_decode(hex_bytes, &mut decoded).map_err(|err| {
+                let msg = format!("the proof is not a hex string since {err}");
+                Error::cli(msg)
+            })?;
+            decoded
+        };
+        let tx_proof = packed::TransactionProof::from_slice(&raw_proof).map_err(|err| {
+            let msg = format!("the proof is not a packed transaction proof since {err}");
+            Error::cli(msg)
+        })?;
+        let tx_index: u32 = tx_proof.tx_index().unpack();
+        let height: u32 = tx_proof.height().unpack();
+        let merkle_block: MerkleBlock = deserialize(&tx_proof.transaction_proof().raw_data())
+            .map_err(|err| {
+                let msg = format!("the tx out proof in the proof is invalid since {err}");
+                Error::cli(msg)
+            })?;
+        let block_hash = merkle_block.header.block_hash();
+        log::info!("The proof is for header#{height}, {block_hash:#x}");
+
+        // The merkle root is recomputed and checked against the header of the tx out proof.
+        let mut matches = Vec::new();
+        let mut indexes = Vec::new();
+        let tx_matched = merkle_block
+            .extract_matches(&mut matches, &mut indexes)
+            .is_ok()
+            && matches
+                .iter()
+                .zip(indexes.iter())
+                .any(|(id, index)| *id == self.txid && *index == tx_index);
+        if tx_matched {
+            log::info!(
+                "[check] the transaction {:#x} is at index {tx_index}",
+                self.txid
+            );
+        } else {
+            log::warn!(
+                "[check] the transaction {:#x} at index {tx_index} is not proved \
+                by the tx out proof",
+                self.txid
+            );
+        }
+
+        let header_matched = match storage.bitcoin_header_hash(height) {
+            Ok(stg_hash) if stg_hash == block_hash.into() => {
+                log::info!("[check] the header#{height} is matched with the local storage");
+                true
+            }
+            Ok(stg_hash) => {
+                log::warn!(
+                    "[check] the header#{height} is unmatched, \
+                    its hash in the local storage is {stg_hash:#x}"
+                );
+                false
+            }
+            Err(err) => {
+                log::warn!("[check] the header#{height} is not in the local storage since {err}");
+                false
+            }
+        };
+
+        // Verify the proof of headers against the MMR roots from the local storage,
+        // as what the SPV client does on chain.
+        let (stg_base_height, _) = storage.base_state()?;
+        let (stg_tip_height, _) = storage.tip_state()?;
+        let (start_height, end_height) = match self.spv_client_height {
+            Some(spv_client_height) => (spv_client_height, spv_client_height),
+            None => (height, stg_tip_height),
+        };
+        let mut matched_spv_client_height = None;
+        if header_matched
+            && stg_base_height <= height
+            && height <= start_height
+            && end_height <= stg_tip_height
+        {
+            let proof_items = tx_proof
+                .header_proof()
+                .into_iter()
+                .collect::<Vec<packed::HeaderDigest>>();
+            let position = mmr::lib::leaf_index_to_pos(u64::from(height - stg_base_height));
+            let digest = HeaderDigest::new_leaf(height, &merkle_block.header).pack();
+            for spv_client_height in start_height..=end_height {
+                let mmr_size = mmr::lib::leaf_index_to_mmr_size(u64::from(
+                    spv_client_height - stg_base_height,
+                ));
+                let stg_root = storage.generate_headers_root(spv_client_height)?;
+                let proof = mmr::MMRProof::new(mmr_size, proof_items.clone());
+                if proof
+                    .verify(stg_root, vec![(position, digest.clone())])
+                    .unwrap_or(false)
+                {
+                    matched_spv_client_height = Some(spv_client_height);
+                    break;
+                }
+            }
+        }
+        if let Some(spv_client_height) = matched_spv_client_height {
+            log::info!(
+                "[check] the header proof is matched with the SPV client \
+                at header#{spv_client_height}"
+            );
+        } else {
+            log::warn!(
+                "[check] the header proof is not matched with any SPV client \
+                from header#{start_height} to header#{end_height}"
+            );
+        }
+
+        if tx_matched && matched_spv_client_height.is_some() {
+            log::info!("PASS: the transaction is in header#{height}, {block_hash:#x}");
+            Ok(())
+        } else {
+            let msg = format!("FAIL: the proof is invalid for header#{height}, {block_hash:#x}");
+            Err(Error::other(msg))
+        }
+    }
+}
@@ -1,14 +1,18 @@
 //! The `watch` sub-command.
 
-use std::{net::SocketAddr, path::PathBuf, thread, time};
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    thread, time,
+};
 
 use clap::Parser;
 
 use crate::{
-    components::{ApiServiceConfig, SpvService, Storage},
+    components::{start_metrics_server, BlockNotifier, SpvService, Storage, SyncStatus},
     prelude::*,
     result::{Error, Result},
-    utilities::try_raise_fd_limit,
+    utilities::{listen_shutdown_signal, try_raise_fd_limit, wait_unless_shutdown},
 };
 
 #[derive(Parser)]
@@ -26,9 +30,8 @@ pub struct Args {
     #[clap(flatten)]
     pub(crate) bitcoin: super::BitcoinArgs,
 
-    /// The JSON-RPC server's listen address.
-    #[arg(long)]
-    pub(crate) listen_address: SocketAddr,
+    #[clap(flatten)]
+    pub(crate) api: super::ApiArgs,
 
     /// A interval in seconds.
     ///
@@ -37,9 +40,13 @@ pub struct Args {
     #[arg(long, default_value = "30")]
     pub(crate) interval: u64,
 
-    /// The batch size that how many Bitcoin headers will be downloaded at once.
-    #[arg(long, default_value = "30")]
-    pub(crate) bitcoin_headers_download_batch_size: u32,
+    #[clap(flatten)]
+    pub(crate) sync: super::SyncArgs,
+
+    /// Before serving, sync the local storage and check that the SPV instance
+    /// on chain matches it; refuse to serve if they are diverged.
+    #[arg(long)]
+    pub(crate) reverify_on_start: bool,
 }
 
 impl Args {
@@ -47,6 +54,7 @@ impl Args {
         log::info!("Starting the Bitcoin SPV service (readonly)");
 
         try_raise_fd_limit();
+        let shutdown = listen_shutdown_signal();
 
         let storage = Storage::new(&self.data_dir)?;
         if !storage.is_initialized()? {
@@ -56,28 +64,74 @@ impl Args {
             );
             return Err(Error::other(msg));
         }
+        storage.set_header_cache_size(self.sync.header_cache_size)?;
         let ckb_cli = self.ckb.client();
         let btc_cli = self.bitcoin.client();
-
+        let block_notifier = self
+            .sync
+            .bitcoin_zmq_endpoint
+            .as_deref()
+            .map(BlockNotifier::subscribe)
+            .transpose()?;
+
+        let sync_options = self.sync.options();
         let spv_service = SpvService {
             ckb_cli: ckb_cli.clone(),
             btc_cli: btc_cli.clone(),
             storage: storage.clone(),
+            reorg_in_progress: Default::default(),
+            reorg_refused: Default::default(),
+            bitcoin_tip_height: Default::default(),
+            sync_lock: Default::default(),
+            download_concurrency: self.sync.bitcoin_download_concurrency,
+            metrics: Default::default(),
         };
 
-        let _api_service = ApiServiceConfig::new(self.listen_address).start(spv_service.clone());
+        if self.reverify_on_start {
+            log::info!("Verify the SPV instance on chain before serving");
+            while !shutdown.load(Ordering::SeqCst)
+                && spv_service.sync_storage(&sync_options)? == SyncStatus::Syncing
+            {}
+            if !spv_service.compare_with_onchain()? {
+                let msg = "the SPV instance on chain is diverged from the local storage, \
+                    refuse to serve proofs";
+                return Err(Error::other(msg));
+            }
+        }
+
+        let mut api_servers = self.api.config().start(spv_service.clone())?;
+        if let Some(ref address) = self.api.metrics_listen_address {
+            api_servers.push(start_metrics_server(address, spv_service.clone())?);
+        }
 
-        loop {
-            if !spv_service.sync_storage(self.bitcoin_headers_download_batch_size)? {
+        while !shutdown.load(Ordering::SeqCst) {
+            if spv_service.sync_storage(&sync_options)? == SyncStatus::Syncing {
                 continue;
             }
-            self.take_a_break();
+            self.take_a_break(block_notifier.as_ref(), &shutdown);
         }
 
-        // TODO Handle Ctrl-C and clean resources before exit.
+        log::info!("Stopping the Bitcoin SPV service (readonly)");
+        for server in api_servers {
+            server.close();
+        }
+        storage.flush()?;
+        log::info!("The Bitcoin SPV service (readonly) is stopped");
+
+        Ok(())
     }
 
-    fn take_a_break(&self) {
-        thread::sleep(time::Duration::from_secs(self.interval));
+    fn take_a_break(&self, block_notifier: Option<&BlockNotifier>, shutdown: &AtomicBool) {
+        let interval = time::Duration::from_secs(self.interval);
+        if let Some(notifier) = block_notifier {
+            if wait_unless_shutdown(shutdown, interval, |step| notifier.wait_timeout(step)) {
+                log::info!("Wake up since a new block is notified");
+            }
+        } else {
+            wait_unless_shutdown(shutdown, interval, |step| {
+                thread::sleep(step);
+                false
+            });
+        }
     }
 }
@@ -5,14 +5,27 @@ use jsonrpc_core::{Error as RpcError, ErrorCode as RpcErrorCode};
 #[repr(i64)]
 pub enum ApiErrorCode {
     // Bitcoin: 21xxx
+    BitcoinTxNotFound = 21001,
+    BitcoinTxOutputNotFound,
+    BitcoinTxProofInvalid = 21101,
+    BitcoinTxIndexUnmatched,
+    BitcoinTxIndexOutOfRange,
     // Storage: 23xxx
     StorageTxTooNew = 23101,
     StorageTxUnconfirmed,
     StorageHeaderMissing = 23301,
     StorageHeaderUnmatched,
+    StorageHeaderPruned,
+    StorageStartupSyncTimeout = 23901,
     // Onchain: 25xxx
     OnchainTxUnconfirmed = 25101,
+    OnchainInstanceBehind = 25201,
     OnchainReorgRequired = 25901,
+    OnchainReorgInProgress,
+    OnchainInstanceDiverged,
+    // Admin: 27xxx
+    AdminApisDisabled = 27001,
+    AdminTokenIncorrect,
 }
 
 impl ApiErrorCode {
@@ -23,4 +36,15 @@ impl ApiErrorCode {
             data: None,
         }
     }
+
+    /// Suggests clients to retry after several seconds, in the error data.
+    pub fn with_desc_and_retry_after<D: fmt::Display>(
+        self,
+        desc: D,
+        retry_after_secs: u64,
+    ) -> RpcError {
+        let mut err = self.with_desc(desc);
+        err.data = Some(serde_json::json!({ "retry_after": retry_after_secs }));
+        err
+    }
 }
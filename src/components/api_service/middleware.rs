@@ -0,0 +1,227 @@
+//! The middleware for the HTTP server of JSON-RPC APIs.
+
+use jsonrpc_core::IoHandler;
+use jsonrpc_http_server::{
+    hyper::{
+        self,
+        body::HttpBody as _,
+        header::{self, HeaderValue},
+        Body, Method, Request, StatusCode, Uri,
+    },
+    RequestMiddleware, RequestMiddlewareAction, Response,
+};
+
+use crate::components::SpvService;
+
+const READY_PATH: &str = "/ready";
+const JSON_RPC_PATH: &str = "/";
+// Same as the default limit of the request body of the JSON-RPC server.
+const MAX_REQUEST_BODY_SIZE: usize = 5 * 1024 * 1024;
+
+#[derive(Default)]
+pub(crate) struct ApiMiddleware {
+    // The base path of all APIs, without the trailing slash.
+    pub(crate) base_path: Option<String>,
+    // Serves the readiness API "/ready" if it's provided.
+    pub(crate) readiness: Option<Readiness>,
+    // Serves the cross-origin requests with credentials if it's provided.
+    pub(crate) cors_credentials: Option<CorsCredentials>,
+}
+
+pub(crate) struct Readiness {
+    pub(crate) spv_service: SpvService,
+    // How many blocks the local storage could be behind the Bitcoin tip, when it's ready.
+    pub(crate) lag_threshold: u32,
+}
+
+// The JSON-RPC server never sends `Access-Control-Allow-Credentials`, so the
+// cross-origin requests from the allowed origins are served here instead.
+pub(crate) struct CorsCredentials {
+    pub(crate) io_handler: IoHandler,
+    // The exact origins, which never include "*".
+    pub(crate) allowed_origins: Vec<String>,
+}
+
+impl RequestMiddleware for ApiMiddleware {
+    fn on_request(&self, mut request: Request<Body>) -> RequestMiddlewareAction {
+        if let Some(ref base_path) = self.base_path {
+            let path = request.uri().path();
+            let relative_path = if path == base_path {
+                "/".to_owned()
+            } else if let Some(relative_path) = path
+                .strip_prefix(base_path.as_str())
+                .filter(|relative_path| relative_path.starts_with('/'))
+            {
+                relative_path.to_owned()
+            } else {
+                log::trace!("reject the request for path \"{path}\"");
+                return not_found().into();
+            };
+            let path_and_query = if let Some(query) = request.uri().query() {
+                format!("{relative_path}?{query}")
+            } else {
+                relative_path
+            };
+            match path_and_query.parse::<Uri>() {
+                Ok(uri) => *request.uri_mut() = uri,
+                Err(err) => {
+                    let msg =
+                        format!("failed to rewrite the path \"{path_and_query}\" since {err}");
+                    return Response::bad_request(msg).into();
+                }
+            }
+        }
+        if let Some(ref readiness) = self.readiness {
+            if request.uri().path() == READY_PATH {
+                return readiness.check().into();
+            }
+        }
+        if let Some(ref cors_credentials) = self.cors_credentials {
+            if let Some(origin) = cors_credentials.allowed_origin(&request) {
+                return cors_credentials.respond(origin, request);
+            }
+        }
+        request.into()
+    }
+}
+
+impl Readiness {
+    // Responds OK only if the local storage is within the lag threshold of the
+    // Bitcoin tip and no reorg is refused, otherwise, responds "Service Unavailable".
+    fn check(&self) -> Response {
+        let spv = &self.spv_service;
+        if spv.is_reorg_refused() {
+            let content = "not ready, the reorg of the local storage is refused, \
+                please check the Bitcoin node\n"
+                .to_owned();
+            return text_response(StatusCode::SERVICE_UNAVAILABLE, content);
+        }
+        let result = tokio::task::block_in_place(|| {
+            let (stg_tip_height, _) = spv
+                .storage
+                .tip_state()
+                .map_err(|err| format!("failed to read the storage tip since {err}"))?;
+            let btc_tip_height = spv
+                .btc_cli
+                .get_tip_height()
+                .map_err(|err| format!("failed to get the Bitcoin tip since {err}"))?;
+            Ok::<_, String>((stg_tip_height, btc_tip_height))
+        });
+        match result {
+            Ok((stg_tip_height, btc_tip_height)) => {
+                let lag = btc_tip_height.saturating_sub(stg_tip_height);
+                if lag <= self.lag_threshold {
+                    let content = format!(
+                        "ready, the tip header in local storage is header#{stg_tip_height}\n"
+                    );
+                    text_response(StatusCode::OK, content)
+                } else {
+                    let content = format!(
+                        "not ready, the tip header in local storage is header#{stg_tip_height}, \
+                        {lag} blocks behind the tip header of bitcoin header#{btc_tip_height}\n"
+                    );
+                    text_response(StatusCode::SERVICE_UNAVAILABLE, content)
+                }
+            }
+            Err(message) => {
+                log::warn!("failed to check the readiness: {message}");
+                let content = format!("not ready, {message}\n");
+                text_response(StatusCode::SERVICE_UNAVAILABLE, content)
+            }
+        }
+    }
+}
+
+impl CorsCredentials {
+    // Returns the origin of a cross-origin JSON-RPC request, only if it's allowed.
+    fn allowed_origin(&self, request: &Request<Body>) -> Option<HeaderValue> {
+        if request.uri().path() != JSON_RPC_PATH
+            || ![Method::POST, Method::OPTIONS].contains(request.method())
+        {
+            return None;
+        }
+        let origin = request.headers().get(header::ORIGIN)?;
+        let is_allowed = origin
+            .to_str()
+            .map(|origin| self.allowed_origins.iter().any(|allowed| allowed == origin))
+            .unwrap_or(false);
+        is_allowed.then(|| origin.clone())
+    }
+
+    fn respond(&self, origin: HeaderValue, request: Request<Body>) -> RequestMiddlewareAction {
+        let response = respond_with_credentials(self.io_handler.clone(), origin, request);
+        RequestMiddlewareAction::Respond {
+            should_validate_hosts: true,
+            response: Box::pin(response),
+        }
+    }
+}
+
+// Answers the preflight request, or handles the JSON-RPC request, with the
+// CORS headers which allow credentials.
+async fn respond_with_credentials(
+    io_handler: IoHandler,
+    origin: HeaderValue,
+    request: Request<Body>,
+) -> hyper::Result<hyper::Response<Body>> {
+    let builder = hyper::Response::builder()
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")
+        .header(header::VARY, "origin");
+    if request.method() == Method::OPTIONS {
+        let builder = builder
+            .status(StatusCode::OK)
+            .header(header::ACCESS_CONTROL_ALLOW_METHODS, "OPTIONS, POST")
+            .header(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                "origin, content-type, accept",
+            );
+        return Ok(build_response(builder, Body::empty()));
+    }
+    let mut body = request.into_body();
+    let mut content = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if content.len() + chunk.len() > MAX_REQUEST_BODY_SIZE {
+            let builder = builder.status(StatusCode::PAYLOAD_TOO_LARGE);
+            return Ok(build_response(builder, Body::empty()));
+        }
+        content.extend_from_slice(&chunk);
+    }
+    let request = match String::from_utf8(content) {
+        Ok(request) => request,
+        Err(_) => {
+            let builder = builder.status(StatusCode::BAD_REQUEST);
+            return Ok(build_response(builder, Body::empty()));
+        }
+    };
+    let output = io_handler.handle_request(&request).await;
+    let builder = builder
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json; charset=utf-8");
+    let body = output
+        .map(|output| format!("{output}\n"))
+        .unwrap_or_default();
+    Ok(build_response(builder, Body::from(body)))
+}
+
+fn build_response(builder: hyper::http::response::Builder, body: Body) -> hyper::Response<Body> {
+    builder.body(body).unwrap_or_else(|err| {
+        log::error!("failed to build the response since {err}");
+        let mut response = hyper::Response::new(Body::empty());
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        response
+    })
+}
+
+fn not_found() -> Response {
+    text_response(StatusCode::NOT_FOUND, "Not Found\n".to_owned())
+}
+
+fn text_response(code: StatusCode, content: String) -> Response {
+    Response {
+        code,
+        content_type: HeaderValue::from_static("text/plain; charset=utf-8"),
+        content,
+    }
+}
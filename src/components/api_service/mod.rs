@@ -1,41 +1,188 @@
 //! JSON-RPC APIs service.
 
-use std::{net::SocketAddr, sync::RwLock, time::SystemTime};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 
-use bitcoin::Txid;
+use bitcoin::{
+    consensus::{deserialize, serialize},
+    BlockHash, MerkleBlock, TxMerkleNode, Txid,
+};
 use ckb_bitcoin_spv_verifier::types::{
     core::{Bytes, Hash},
     packed,
     prelude::*,
 };
-use ckb_jsonrpc_types::{JsonBytes, OutPoint};
+use ckb_jsonrpc_types::{CellOutput, JsonBytes, OutPoint};
+use ckb_types::{packed::Script, H256};
 use jsonrpc_core::{Error as RpcError, ErrorCode as RpcErrorCode, IoHandler, Result as RpcResult};
 use jsonrpc_derive::rpc;
 use jsonrpc_http_server::{Server, ServerBuilder};
-use jsonrpc_server_utils::{cors::AccessControlAllowOrigin, hosts::DomainsValidation};
-use serde::Serialize;
+use jsonrpc_server_utils::{
+    cors::AccessControlAllowOrigin,
+    hosts::{DomainsValidation, Host},
+};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    components::{SpvInstance, SpvService},
+    components::{SpvClientCell, SpvHealth, SpvInstance, SpvService},
     prelude::*,
     result::{Error, Result},
 };
 
 mod error;
+mod middleware;
 
 pub use error::ApiErrorCode;
 
+use middleware::{ApiMiddleware, CorsCredentials, Readiness};
+
 // Bitcoin target block time is 10 minutes.
-const SPV_INSTANCE_CACHED_SECS: u64 = 60 * 10;
+const BITCOIN_BLOCK_INTERVAL_SECS: u64 = 60 * 10;
+const SPV_INSTANCE_CACHED_SECS: u64 = BITCOIN_BLOCK_INTERVAL_SECS;
+// The error code of Bitcoin JSON-RPC, when the transaction or the block is not found.
+const BTC_RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+// About 1 week of Bitcoin blocks.
+const STORAGE_HEIGHT_LOOKUP_LIMIT: u32 = 6 * 24 * 7;
+// A reorg transaction is usually committed in several CKB blocks.
+const REORG_RETRY_AFTER_SECS: u64 = 30;
+// The prefetch of headers is triggered at most once in this interval.
+const HEADER_PREFETCH_INTERVAL_SECS: u64 = 10;
 
 pub struct ApiServiceConfig {
-    listen_address: SocketAddr,
+    listen_addresses: Vec<SocketAddr>,
+    base_path: Option<String>,
+    startup_sync_deadline: Option<Duration>,
+    safe_depth: u32,
+    prefer_storage_heights: bool,
+    health_api_enabled: bool,
+    ready_lag_threshold: u32,
+    reorg_fallback_min_clients: Option<u8>,
+    reorg_lookback_percent: u8,
+    cors_allow_origins: Vec<String>,
+    cors_allow_credentials: bool,
+    allowed_hosts: Option<Vec<Host>>,
+    admin_token: Option<String>,
+    spv_instance_refresh_on_error: bool,
+    proof_confirmations_min_onchain: Option<u32>,
+    spv_cell_scan_consistency_retries: u32,
+    header_prefetch_ahead: Option<u32>,
+    max_proof_age_blocks: Option<u32>,
+    max_batch_size: usize,
+    max_headers_range: u32,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct BitcoinTxProof {
     pub(crate) spv_client: OutPoint,
     pub(crate) proof: JsonBytes,
+    // The serialized header of the block which contains the transaction.
+    #[serde(default)]
+    pub(crate) header: JsonBytes,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BitcoinTxProofResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) result: Option<BitcoinTxProof>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<RpcError>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BitcoinTxProofVerification {
+    pub(crate) height: u32,
+    pub(crate) tx_matched: bool,
+    pub(crate) header_matched: bool,
+    pub(crate) header_proof_matched: bool,
+    pub(crate) spv_client_live: bool,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BitcoinHeaderProof {
+    pub(crate) spv_client: OutPoint,
+    pub(crate) proof: JsonBytes,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BitcoinTxProofBundle {
+    #[serde(flatten)]
+    pub(crate) tx_proof: BitcoinTxProof,
+    pub(crate) spv_client_output: CellOutput,
+    pub(crate) spv_client_data: JsonBytes,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BitcoinHeader {
+    pub(crate) height: u32,
+    pub(crate) hash: BlockHash,
+    pub(crate) raw: JsonBytes,
+    pub(crate) version: i32,
+    pub(crate) prev_blockhash: BlockHash,
+    pub(crate) merkle_root: TxMerkleNode,
+    pub(crate) time: u32,
+    pub(crate) bits: String,
+    pub(crate) nonce: u32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SpvInstanceInfo {
+    pub(crate) tip_client_id: u8,
+    pub(crate) clients_count: u8,
+    pub(crate) best_height: u32,
+    pub(crate) tip_client: OutPoint,
+    pub(crate) clients: Vec<SpvClientInfo>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SpvClientInfo {
+    pub(crate) id: u8,
+    pub(crate) min_height: u32,
+    pub(crate) max_height: u32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SpvClientForHeight {
+    pub(crate) spv_client: OutPoint,
+    #[serde(flatten)]
+    pub(crate) client: SpvClientInfo,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SyncState {
+    pub(crate) base_height: u32,
+    pub(crate) tip_height: u32,
+    pub(crate) last_spv_tx_hash: Option<H256>,
+    pub(crate) last_synced_at: Option<u64>,
+    pub(crate) ckb_balance: Option<u64>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct HealthState {
+    #[serde(flatten)]
+    pub(crate) health: SpvHealth,
+    pub(crate) is_ready: bool,
+}
+
+#[derive(Serialize, Clone)]
+pub struct CacheStats {
+    pub(crate) spv_instance: CacheStat,
+    pub(crate) headers: CacheStat,
+}
+
+#[derive(Serialize, Clone)]
+pub struct CacheStat {
+    pub(crate) size: usize,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) hit_ratio: f64,
 }
 
 #[rpc(server)]
@@ -46,12 +193,115 @@ pub trait SpvRpc {
         tx_hash: Txid,
         tx_index: u32,
         confirmations: u32,
+        block_hash: Option<BlockHash>,
+    ) -> RpcResult<BitcoinTxProof>;
+
+    /// An alias of `getTxProof` with the block hash, kept for compatibility.
+    #[rpc(name = "getTxProofByBlockHash")]
+    fn get_tx_proof_by_block_hash(
+        &self,
+        tx_hash: Txid,
+        tx_index: u32,
+        block_hash: BlockHash,
+        confirmations: u32,
+    ) -> RpcResult<BitcoinTxProof>;
+
+    #[rpc(name = "getTxProofByOutPoint")]
+    fn get_tx_proof_by_out_point(
+        &self,
+        tx_hash: Txid,
+        vout: u32,
+        tx_index: u32,
+        confirmations: u32,
     ) -> RpcResult<BitcoinTxProof>;
+
+    #[rpc(name = "getHeaderProof")]
+    fn get_header_proof(
+        &self,
+        heights: Vec<u32>,
+        confirmations: u32,
+    ) -> RpcResult<BitcoinHeaderProof>;
+
+    #[rpc(name = "getClientForHeight")]
+    fn get_client_for_height(
+        &self,
+        height: u32,
+        confirmations: u32,
+    ) -> RpcResult<SpvClientForHeight>;
+
+    #[rpc(name = "getTxProofs")]
+    fn get_tx_proofs(
+        &self,
+        requests: Vec<(Txid, u32, u32)>,
+    ) -> RpcResult<Vec<BitcoinTxProofResult>>;
+
+    #[rpc(name = "getHeadersProofForRange")]
+    fn get_headers_proof_for_range(
+        &self,
+        from: u32,
+        to: u32,
+        spv_client_height: u32,
+    ) -> RpcResult<JsonBytes>;
+
+    #[rpc(name = "verifyTxProof")]
+    fn verify_tx_proof(
+        &self,
+        proof: BitcoinTxProof,
+        tx_hash: Txid,
+    ) -> RpcResult<BitcoinTxProofVerification>;
+
+    #[rpc(name = "getProofBundle")]
+    fn get_proof_bundle(
+        &self,
+        tx_hash: Txid,
+        tx_index: u32,
+        confirmations: u32,
+    ) -> RpcResult<BitcoinTxProofBundle>;
+
+    #[rpc(name = "ping")]
+    fn ping(&self) -> RpcResult<String>;
+
+    #[rpc(name = "getMmrRoot")]
+    fn get_mmr_root(&self, height: u32) -> RpcResult<JsonBytes>;
+
+    #[rpc(name = "getHeaderByHeight")]
+    fn get_header_by_height(&self, height: u32) -> RpcResult<BitcoinHeader>;
+
+    #[rpc(name = "getSyncState")]
+    fn get_sync_state(&self) -> RpcResult<SyncState>;
+
+    #[rpc(name = "getHealth")]
+    fn get_health(&self) -> RpcResult<HealthState>;
+
+    #[rpc(name = "getSpvInstanceInfo")]
+    fn get_spv_instance_info(&self) -> RpcResult<SpvInstanceInfo>;
+
+    #[rpc(name = "getCacheStats")]
+    fn get_cache_stats(&self) -> RpcResult<CacheStats>;
+
+    #[rpc(name = "dropCaches")]
+    fn drop_caches(&self, admin_token: String) -> RpcResult<()>;
 }
 
 pub struct SpvRpcImpl {
     spv_service: SpvService,
     cached_spv_instance: RwLock<Option<CachedSpvInstance>>,
+    cached_spv_instance_hits: AtomicU64,
+    cached_spv_instance_misses: AtomicU64,
+    startup_sync_deadline: Option<StartupSyncDeadline>,
+    safe_depth: u32,
+    prefer_storage_heights: bool,
+    reorg_fallback_min_clients: Option<u8>,
+    reorg_lookback_percent: u8,
+    admin_token: Option<String>,
+    spv_instance_refresh_on_error: bool,
+    proof_confirmations_min_onchain: Option<u32>,
+    spv_cell_scan_consistency_retries: u32,
+    header_prefetch_ahead: Option<u32>,
+    header_prefetch: Arc<HeaderPrefetch>,
+    max_proof_age_blocks: Option<u32>,
+    max_batch_size: usize,
+    max_headers_range: u32,
 }
 
 #[derive(Clone)]
@@ -60,25 +310,290 @@ struct CachedSpvInstance {
     expired_timestamp: u64,
 }
 
+#[derive(Default)]
+struct HeaderPrefetch {
+    in_progress: AtomicBool,
+    last_started_at: RwLock<Option<Instant>>,
+}
+
+struct StartupSyncDeadline {
+    started_at: Instant,
+    deadline: Duration,
+    is_synced: AtomicBool,
+}
+
 impl ApiServiceConfig {
-    pub fn new(listen_address: SocketAddr) -> Self {
-        Self { listen_address }
+    pub fn new(listen_addresses: &[SocketAddr]) -> Self {
+        Self {
+            listen_addresses: listen_addresses.to_vec(),
+            base_path: None,
+            startup_sync_deadline: None,
+            safe_depth: 0,
+            prefer_storage_heights: false,
+            health_api_enabled: true,
+            ready_lag_threshold: 6,
+            reorg_fallback_min_clients: None,
+            reorg_lookback_percent: 20,
+            cors_allow_origins: Vec::new(),
+            cors_allow_credentials: false,
+            allowed_hosts: None,
+            admin_token: None,
+            spv_instance_refresh_on_error: false,
+            proof_confirmations_min_onchain: None,
+            spv_cell_scan_consistency_retries: 0,
+            header_prefetch_ahead: None,
+            max_proof_age_blocks: None,
+            max_batch_size: 100,
+            max_headers_range: 2016,
+        }
+    }
+
+    /// Serves all APIs under the provided URL path.
+    pub fn base_path(mut self, path: &str) -> Self {
+        let path = path.trim_matches('/');
+        self.base_path = if path.is_empty() {
+            None
+        } else {
+            Some(format!("/{path}"))
+        };
+        self
+    }
+
+    /// Reports unhealthy if the local storage doesn't catch up the Bitcoin chain
+    /// (within the safe depth) in the deadline after the service started.
+    ///
+    /// The safe depth is also used to report the readiness in "getHealth".
+    pub fn startup_sync_deadline(mut self, deadline: Option<Duration>, safe_depth: u32) -> Self {
+        self.startup_sync_deadline = deadline;
+        self.safe_depth = safe_depth;
+        self
+    }
+
+    /// Resolves the heights of blocks from the recent headers in the local storage
+    /// at first, only queries the Bitcoin node when they are not found.
+    pub fn prefer_storage_heights(mut self, prefer: bool) -> Self {
+        self.prefer_storage_heights = prefer;
+        self
+    }
+
+    /// Disables the health API "/ping" and the readiness API "/ready".
+    pub fn disable_health_api(mut self, disabled: bool) -> Self {
+        self.health_api_enabled = !disabled;
+        self
+    }
+
+    /// The readiness API "/ready" reports ready only if the local storage is
+    /// at most this count of blocks behind the Bitcoin tip.
+    pub fn ready_lag_threshold(mut self, threshold: u32) -> Self {
+        self.ready_lag_threshold = threshold;
+        self
+    }
+
+    /// Falls back to the SPV client which is at least this count of clients
+    /// before the tip, when the best SPV client is affected by a reorg.
+    pub fn reorg_fallback_min_clients(mut self, count: Option<u8>) -> Self {
+        self.reorg_fallback_min_clients = count;
+        self
+    }
+
+    /// Falls back to the SPV client which is this percent of all clients before
+    /// the tip, when the best SPV client is affected by a reorg.
+    ///
+    /// At least 1 client before the tip is used.
+    pub fn reorg_lookback_percent(mut self, percent: u8) -> Self {
+        self.reorg_lookback_percent = percent;
+        self
+    }
+
+    /// Enables admin APIs, such as "dropCaches", which require this token.
+    pub fn admin_token(mut self, token: Option<String>) -> Self {
+        self.admin_token = token;
+        self
+    }
+
+    /// Refreshes the cached SPV instance from chain and retries once, when a proof
+    /// failed with it since the SPV clients on chain are affected by a reorg.
+    pub fn spv_instance_refresh_on_error(mut self, refresh: bool) -> Self {
+        self.spv_instance_refresh_on_error = refresh;
+        self
+    }
+
+    /// Accepts a proof if the SPV client on chain covers this count of confirmations,
+    /// even if more confirmations are required, which are still checked against
+    /// the local storage.
+    pub fn proof_confirmations_min_onchain(mut self, confirmations: Option<u32>) -> Self {
+        self.proof_confirmations_min_onchain = confirmations;
+        self
+    }
+
+    /// Retries the scan of SPV cells several times when the count of cells is
+    /// incorrect, since the indexer may see a transient state during an update.
+    pub fn spv_cell_scan_consistency_retries(mut self, retries: u32) -> Self {
+        self.spv_cell_scan_consistency_retries = retries;
+        self
+    }
+
+    /// Prefetches this count of headers above the storage tip in background,
+    /// when a proof is requested for a transaction near the storage tip.
+    pub fn header_prefetch_ahead(mut self, count: Option<u32>) -> Self {
+        self.header_prefetch_ahead = count;
+        self
+    }
+
+    /// Rejects proofs when the selected SPV client on chain is more than this
+    /// count of blocks behind the Bitcoin tip.
+    pub fn max_proof_age_blocks(mut self, blocks: Option<u32>) -> Self {
+        self.max_proof_age_blocks = blocks;
+        self
+    }
+
+    /// Rejects batch requests which have more items than this count.
+    pub fn max_batch_size(mut self, size: usize) -> Self {
+        self.max_batch_size = size;
+        self
+    }
+
+    /// Rejects ranges of headers which have more headers than this count.
+    pub fn max_headers_range(mut self, count: u32) -> Self {
+        self.max_headers_range = count;
+        self
+    }
+
+    /// Allows cross-origin requests from the provided origins.
+    ///
+    /// If the list is empty, only same-origin requests are allowed.
+    pub fn cors_allow_origins<S: AsRef<str>>(mut self, origins: &[S]) -> Self {
+        self.cors_allow_origins = origins
+            .iter()
+            .map(|origin| origin.as_ref().to_owned())
+            .collect();
+        self
+    }
+
+    /// Allows credentials (cookies or HTTP authentication) in the cross-origin
+    /// requests from the allowed origins, which should not include "*".
+    pub fn cors_allow_credentials(mut self, allowed: bool) -> Self {
+        self.cors_allow_credentials = allowed;
+        self
+    }
+
+    /// Accepts the requests only if their `Host` headers are in the list or
+    /// are the listen address, to prevent DNS rebinding attacks.
+    ///
+    /// If the list is empty, requests with any `Host` header are accepted.
+    pub fn allowed_hosts<S: AsRef<str>>(mut self, hosts: &[S]) -> Self {
+        self.allowed_hosts = if hosts.is_empty() {
+            None
+        } else {
+            let hosts = hosts.iter().map(|host| Host::from(host.as_ref())).collect();
+            Some(hosts)
+        };
+        self
     }
 
-    pub fn start(&self, spv_service: SpvService) -> Result<Server> {
+    /// Starts a server on each listen address, all servers share the same APIs.
+    pub fn start(&self, spv_service: SpvService) -> Result<Vec<Server>> {
         log::info!("Starting the JSON-RPC service ...");
+        if self.listen_addresses.is_empty() {
+            return Err(Error::other("no listen address is provided"));
+        }
+        for (index, address) in self.listen_addresses.iter().enumerate() {
+            if self.listen_addresses[..index].contains(address) {
+                let msg = format!("the listen address {address} is duplicated");
+                return Err(Error::other(msg));
+            }
+        }
+        if self.cors_allow_credentials && self.cors_allow_origins.iter().any(|o| o == "*") {
+            let msg = "credentials can't be allowed in cross-origin requests from any origin \"*\"";
+            return Err(Error::other(msg));
+        }
+        if let Some(min_count) = self.reorg_fallback_min_clients {
+            // The count of SPV clients is fixed since the SPV instance is deployed.
+            let spv_type_script = spv_service.storage.spv_contract_type_script()?;
+            let clients_count = spv_service
+                .ckb_cli
+                .find_spv_cells(spv_type_script)?
+                .info
+                .clients_count;
+            if min_count >= clients_count {
+                let msg = format!(
+                    "the minimum count of clients for the reorg fallback ({min_count}) \
+                    should be less than the count of SPV clients ({clients_count})"
+                );
+                return Err(Error::other(msg));
+            }
+        }
         let mut io_handler = IoHandler::new();
-        let spv_rpc_impl = SpvRpcImpl::new(spv_service);
+        let readiness_spv_service = spv_service.clone();
+        let mut spv_rpc_impl = SpvRpcImpl::new(spv_service);
+        spv_rpc_impl.startup_sync_deadline =
+            self.startup_sync_deadline
+                .map(|deadline| StartupSyncDeadline {
+                    started_at: Instant::now(),
+                    deadline,
+                    is_synced: AtomicBool::new(false),
+                });
+        spv_rpc_impl.safe_depth = self.safe_depth;
+        spv_rpc_impl.prefer_storage_heights = self.prefer_storage_heights;
+        spv_rpc_impl.reorg_fallback_min_clients = self.reorg_fallback_min_clients;
+        spv_rpc_impl.reorg_lookback_percent = self.reorg_lookback_percent;
+        spv_rpc_impl.admin_token = self.admin_token.clone();
+        spv_rpc_impl.spv_instance_refresh_on_error = self.spv_instance_refresh_on_error;
+        spv_rpc_impl.proof_confirmations_min_onchain = self.proof_confirmations_min_onchain;
+        spv_rpc_impl.spv_cell_scan_consistency_retries = self.spv_cell_scan_consistency_retries;
+        spv_rpc_impl.header_prefetch_ahead = self.header_prefetch_ahead;
+        spv_rpc_impl.max_proof_age_blocks = self.max_proof_age_blocks;
+        spv_rpc_impl.max_batch_size = self.max_batch_size;
+        spv_rpc_impl.max_headers_range = self.max_headers_range;
         io_handler.extend_with(spv_rpc_impl.to_delegate());
 
-        ServerBuilder::new(io_handler)
-            .cors(DomainsValidation::AllowOnly(vec![
-                AccessControlAllowOrigin::Null,
-                AccessControlAllowOrigin::Any,
-            ]))
-            .health_api(("/ping", "ping"))
-            .start_http(&self.listen_address)
-            .map_err(Error::other)
+        if let Some(ref base_path) = self.base_path {
+            log::info!("The JSON-RPC APIs are served under the path \"{base_path}\"");
+        }
+        if !self.health_api_enabled {
+            log::info!("The health API is disabled");
+        }
+
+        let mut servers = Vec::with_capacity(self.listen_addresses.len());
+        for address in &self.listen_addresses {
+            let readiness = self.health_api_enabled.then(|| Readiness {
+                spv_service: readiness_spv_service.clone(),
+                lag_threshold: self.ready_lag_threshold,
+            });
+            let cors_credentials = self.cors_allow_credentials.then(|| CorsCredentials {
+                io_handler: io_handler.clone(),
+                allowed_origins: self.cors_allow_origins.clone(),
+            });
+            let middleware = ApiMiddleware {
+                base_path: self.base_path.clone(),
+                readiness,
+                cors_credentials,
+            };
+            let cors_allow_origins = self
+                .cors_allow_origins
+                .iter()
+                .map(|origin| AccessControlAllowOrigin::from(origin.as_str()))
+                .collect();
+            let mut builder = ServerBuilder::new(io_handler.clone())
+                .cors(DomainsValidation::AllowOnly(cors_allow_origins));
+            if self.health_api_enabled {
+                builder = builder.health_api(("/ping", "ping"));
+            }
+            if let Some(ref allowed_hosts) = self.allowed_hosts {
+                builder =
+                    builder.allowed_hosts(DomainsValidation::AllowOnly(allowed_hosts.clone()));
+            }
+            let server = builder
+                .request_middleware(middleware)
+                .start_http(address)
+                .map_err(|err| {
+                    let msg = format!("failed to listen on {address} since {err}");
+                    Error::other(msg)
+                })?;
+            log::info!("The JSON-RPC service is listening on {}", server.address());
+            servers.push(server);
+        }
+        Ok(servers)
     }
 }
 
@@ -87,6 +602,22 @@ impl SpvRpcImpl {
         Self {
             spv_service,
             cached_spv_instance: RwLock::new(None),
+            cached_spv_instance_hits: AtomicU64::new(0),
+            cached_spv_instance_misses: AtomicU64::new(0),
+            startup_sync_deadline: None,
+            safe_depth: 0,
+            prefer_storage_heights: false,
+            reorg_fallback_min_clients: None,
+            reorg_lookback_percent: 20,
+            admin_token: None,
+            spv_instance_refresh_on_error: false,
+            proof_confirmations_min_onchain: None,
+            spv_cell_scan_consistency_retries: 0,
+            header_prefetch_ahead: None,
+            header_prefetch: Default::default(),
+            max_proof_age_blocks: None,
+            max_batch_size: 100,
+            max_headers_range: 2016,
         }
     }
 
@@ -145,6 +676,19 @@ impl SpvRpcImpl {
         }
     }
 
+    fn invalidate_cache(&self) {
+        match self.cached_spv_instance.write() {
+            Ok(mut locked) => {
+                if locked.take().is_some() {
+                    log::debug!("invalidated the cached SPV instance");
+                }
+            }
+            Err(err) => {
+                log::debug!("failed to invalidate the cached SPV instance since {err}");
+            }
+        }
+    }
+
     fn update_spv_instance(&self, instance: SpvInstance) {
         match self.cached_spv_instance.write() {
             Ok(mut locked) => {
@@ -171,90 +715,34 @@ impl SpvRpcImpl {
             }
         }
     }
-}
 
-impl SpvRpc for SpvRpcImpl {
-    fn get_tx_proof(
+    fn ensure_no_reorg_in_progress(&self) -> RpcResult<()> {
+        if self.spv_service.is_reorg_in_progress() {
+            let desc = "the SPV instance on chain is being reorganized, please retry later";
+            log::debug!("{desc}");
+            let err = ApiErrorCode::OnchainReorgInProgress
+                .with_desc_and_retry_after(desc, REORG_RETRY_AFTER_SECS);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // The SPV clients on chain usually lag behind the local storage.
+    fn onchain_confirmations(&self, confirmations: u32) -> u32 {
+        self.proof_confirmations_min_onchain
+            .map_or(confirmations, |min| min.min(confirmations))
+    }
+
+    // Selects an SPV client on chain, which matches the local storage and
+    // covers the target height with enough confirmations.
+    fn select_spv_client(
         &self,
-        txid: Txid,
-        tx_index: u32,
+        stg_tip_height: u32,
+        target_height: u32,
         confirmations: u32,
-    ) -> RpcResult<BitcoinTxProof> {
-        log::debug!("Call getTxProof with params [{txid:#x}, {confirmations}]");
+    ) -> RpcResult<SpvClientCell> {
         let spv = &self.spv_service;
 
-        let (target_height, target_hash, raw_tx_out_proof) =
-            tokio::task::block_in_place(|| -> RpcResult<(u32, Hash, Vec<u8>)> {
-                let (merkle_block, raw_tx_out_proof) =
-                    spv.btc_cli.get_tx_out_proof(txid).map_err(|err| {
-                        let message =
-                            format!("failed to get tx out proof for {txid:#x} from remote");
-                        log::error!("{message} since {err}");
-                        RpcError {
-                            code: RpcErrorCode::InternalError,
-                            message,
-                            data: None,
-                        }
-                    })?;
-                let block_hash = merkle_block.header.block_hash();
-                log::trace!(">>> the input tx in header {block_hash:#x}");
-                let block_height = spv.btc_cli.get_block_height(block_hash).map_err(|err| {
-                    let message =
-                        format!("failed to get block height for {block_hash:#x} from remote");
-                    log::error!("{message} since {err}");
-                    RpcError {
-                        code: RpcErrorCode::InternalError,
-                        message,
-                        data: None,
-                    }
-                })?;
-                log::trace!(">>> the input tx in header {block_height}");
-                Ok((block_height, block_hash.into(), raw_tx_out_proof))
-            })?;
-
-        let (stg_tip_height, _) = spv.storage.tip_state().map_err(|err| {
-            let message = "failed to read tip bitcoin height from local storage".to_owned();
-            log::error!("{message} since {err}");
-            RpcError {
-                code: RpcErrorCode::InternalError,
-                message,
-                data: None,
-            }
-        })?;
-        log::debug!(">>> tip height in local storage is {stg_tip_height}");
-
-        if stg_tip_height < target_height {
-            let desc = format!(
-                "target transaction is in header#{target_height}, \
-                but the tip header in local storage is header#{stg_tip_height}"
-            );
-            return Err(ApiErrorCode::StorageTxTooNew.with_desc(desc));
-        }
-        if stg_tip_height < target_height + confirmations {
-            let desc = format!(
-                "target transaction is in header#{target_height} \
-                and it requires {confirmations} confirmations, \
-                but the tip header in local storage is header#{stg_tip_height}"
-            );
-            return Err(ApiErrorCode::StorageTxUnconfirmed.with_desc(desc));
-        }
-        let stg_target_hash = spv
-            .storage
-            .bitcoin_header_hash(target_height)
-            .map_err(|err| {
-                let desc = format!("local storage doesn't have header#{target_height}");
-                log::error!("{desc} since {err}");
-                ApiErrorCode::StorageHeaderMissing.with_desc(desc)
-            })?;
-        if target_hash != stg_target_hash {
-            let desc = format!(
-                "target transaction is in header#{target_height}, \
-                the header hash from remote is {target_hash:#x}, \
-                its hash in local storage is {stg_target_hash:#x}"
-            );
-            return Err(ApiErrorCode::StorageHeaderUnmatched.with_desc(desc));
-        }
-
         let spv_type_script = spv.storage.spv_contract_type_script().map_err(|err| {
             let message = "failed to get SPV contract type script from storage".to_owned();
             log::error!("{message} since {err}");
@@ -265,42 +753,127 @@ impl SpvRpc for SpvRpcImpl {
             }
         })?;
 
+        let onchain_confirmations = self.onchain_confirmations(confirmations);
+        log::debug!(">>> the SPV client on chain requires {onchain_confirmations} confirmations");
+
         log::debug!(">>> try the cached SPV instance at first");
 
-        let spv_instance = if let Ok(Some(spv_instance)) =
-            self.load_spv_instance(stg_tip_height, target_height + confirmations)
+        let (spv_instance, is_cached) = if let Ok(Some(spv_instance)) =
+            self.load_spv_instance(stg_tip_height, target_height + onchain_confirmations)
         {
             log::debug!(">>> the cached SPV instance is {spv_instance}");
-            spv_instance
+            self.cached_spv_instance_hits
+                .fetch_add(1, Ordering::Relaxed);
+            (spv_instance, true)
         } else {
             log::debug!(">>> fetch SPV instance from remote since cached is not satisfied");
-            let spv_instance = tokio::task::block_in_place(|| -> RpcResult<SpvInstance> {
-                spv.ckb_cli.find_spv_cells(spv_type_script).map_err(|err| {
-                    let message = format!(
-                        "failed to get SPV cell base on height {stg_tip_height} from chain"
-                    );
-                    log::error!("{message} since {err}");
-                    RpcError {
-                        code: RpcErrorCode::InternalError,
-                        message,
-                        data: None,
-                    }
-                })
-            })?;
-            log::debug!(">>> the fetched SPV instance is {spv_instance}");
-            self.update_spv_instance(spv_instance.clone());
-            spv_instance
+            self.cached_spv_instance_misses
+                .fetch_add(1, Ordering::Relaxed);
+            let spv_instance = self.fetch_spv_instance(spv_type_script.clone())?;
+            (spv_instance, false)
         };
 
-        // First Strategy: find the best SPV client not greater than the storage tip height.
-        // The spv client found has the longest lifetime and
-        // is most likely to cover the height of the block where the bitcoin tx is located.
-        // The downside is that it can be affected by reorg.
-        let mut spv_client_cell = spv_instance
-            .find_best_spv_client_not_greater_than_height(stg_tip_height)
-            .map_err(|err| {
-                let message = format!(
-                    "failed to get SPV cell base on height {stg_tip_height} from fetched data"
+        let result = self.find_spv_client_for_proof(
+            &spv_instance,
+            stg_tip_height,
+            target_height,
+            onchain_confirmations,
+        );
+        let spv_client_cell = match result {
+            Err(err) if is_cached && self.spv_instance_refresh_on_error && is_reorg_error(&err) => {
+                log::debug!(
+                    ">>> fetch SPV instance from remote since the cached failed: {}",
+                    err.message
+                );
+                let spv_instance = self.fetch_spv_instance(spv_type_script)?;
+                self.find_spv_client_for_proof(
+                    &spv_instance,
+                    stg_tip_height,
+                    target_height,
+                    onchain_confirmations,
+                )?
+            }
+            result => result?,
+        };
+        self.ensure_spv_client_not_too_old(&spv_client_cell)?;
+
+        Ok(spv_client_cell)
+    }
+
+    // A proof from an SPV client far behind the Bitcoin tip is valid, but it
+    // reflects an out-of-date view, which usually means the updater is stale.
+    fn ensure_spv_client_not_too_old(&self, spv_client_cell: &SpvClientCell) -> RpcResult<()> {
+        let max_age = match self.max_proof_age_blocks {
+            Some(max_age) => max_age,
+            None => return Ok(()),
+        };
+        let spv = &self.spv_service;
+        let btc_tip_height = tokio::task::block_in_place(|| {
+            spv.btc_cli.get_tip_height().map_err(|err| {
+                let message = "failed to get the tip height from remote".to_owned();
+                log::error!("{message} since {err}");
+                RpcError {
+                    code: RpcErrorCode::InternalError,
+                    message,
+                    data: None,
+                }
+            })
+        })?;
+        let spv_height = spv_client_cell.client.headers_mmr_root.max_height;
+        let age = btc_tip_height.saturating_sub(spv_height);
+        if age > max_age {
+            let desc = format!(
+                "the service is behind the Bitcoin chain, the SPV client on chain \
+                (header#{spv_height}) is {age} blocks behind the Bitcoin tip \
+                (header#{btc_tip_height}), more than {max_age} blocks"
+            );
+            log::warn!("{desc}");
+            return Err(ApiErrorCode::OnchainInstanceBehind.with_desc(desc));
+        }
+        Ok(())
+    }
+
+    fn fetch_spv_instance(&self, spv_type_script: Script) -> RpcResult<SpvInstance> {
+        let spv = &self.spv_service;
+        let spv_instance = tokio::task::block_in_place(|| -> RpcResult<SpvInstance> {
+            spv.ckb_cli
+                .find_spv_cells_with_retries(
+                    spv_type_script,
+                    self.spv_cell_scan_consistency_retries,
+                )
+                .map_err(|err| {
+                    let message = "failed to get SPV cells from chain".to_owned();
+                    log::error!("{message} since {err}");
+                    RpcError {
+                        code: RpcErrorCode::InternalError,
+                        message,
+                        data: None,
+                    }
+                })
+        })?;
+        log::debug!(">>> the fetched SPV instance is {spv_instance}");
+        self.update_spv_instance(spv_instance.clone());
+        Ok(spv_instance)
+    }
+
+    fn find_spv_client_for_proof(
+        &self,
+        spv_instance: &SpvInstance,
+        stg_tip_height: u32,
+        target_height: u32,
+        confirmations: u32,
+    ) -> RpcResult<SpvClientCell> {
+        let spv = &self.spv_service;
+
+        // First Strategy: find the best SPV client not greater than the storage tip height.
+        // The spv client found has the longest lifetime and
+        // is most likely to cover the height of the block where the bitcoin tx is located.
+        // The downside is that it can be affected by reorg.
+        let mut spv_client_cell = spv_instance
+            .find_best_spv_client_not_greater_than_height(stg_tip_height)
+            .map_err(|err| {
+                let message = format!(
+                    "failed to get SPV cell base on height {stg_tip_height} from fetched data"
                 );
                 log::error!("{message} since {err}");
                 RpcError {
@@ -345,12 +918,20 @@ impl SpvRpc for SpvRpcImpl {
             log::warn!("[storage] header#{spv_best_height}; mmr-root {stg_header_root}");
             let desc = "Strategy 1 failed to find a valid SPV client due to reorg, switching to strategy 2 for further lookup";
             log::warn!("{desc}");
+            // The cached SPV instance is known to be stale, don't reuse it for next requests.
+            self.invalidate_cache();
 
-            // Second Strategy: Find the Nth (20% of total) spv cell before the tip spv cell.
+            // Second Strategy: Find the Nth (the configured percent of total, at least 1,
+            // or the configured minimum, whichever is more) spv cell before the tip spv cell.
             // The cell is far enough away from the tip to be less affected by the reorg,
             // and has a relatively long survival period.
             // But it may not be able to cover the height of the block where the newer bitcoin tx is located
-            let count = spv_instance.clients.len() / 5;
+            let clients_count = spv_instance.clients.len();
+            let mut count = (clients_count * usize::from(self.reorg_lookback_percent) / 100).max(1);
+            // It's checked to be less than the count of clients when the service starts.
+            if let Some(min_count) = self.reorg_fallback_min_clients {
+                count = count.max(usize::from(min_count));
+            }
             spv_client_cell = spv_instance
                 .find_spv_client_before_tip(count)
                 .map_err(|err| {
@@ -402,11 +983,395 @@ impl SpvRpc for SpvRpcImpl {
                 log::warn!("[onchain] header#{spv_best_height}; mmr-root {spv_header_root}");
                 let stg_header_root = packed_stg_header_root.unpack();
                 log::warn!("[storage] header#{spv_best_height}; mmr-root {stg_header_root}");
+                let matched_height = match self.find_highest_matched_height(spv_instance) {
+                    Some(height) => height,
+                    None => {
+                        let desc = "none of SPV clients on chain matches the local storage, \
+                            the SPV instance may be a wrong one, or a catastrophic reorg happened";
+                        log::error!("{desc}");
+                        return Err(ApiErrorCode::OnchainInstanceDiverged.with_desc(desc));
+                    }
+                };
                 let desc = "the SPV instance on chain is unknown, reorg is required";
                 log::warn!("{desc}");
-                return Err(ApiErrorCode::OnchainReorgRequired.with_desc(desc));
+                // The SPV clients above the divergence point will be reorganized.
+                let reorg_depth = spv_instance
+                    .find_tip_spv_client()
+                    .map(|tip| {
+                        tip.client
+                            .headers_mmr_root
+                            .max_height
+                            .saturating_sub(matched_height)
+                    })
+                    .unwrap_or_default();
+                let retry_after = estimate_reorg_retry_after(reorg_depth);
+                let err =
+                    ApiErrorCode::OnchainReorgRequired.with_desc_and_retry_after(desc, retry_after);
+                return Err(err);
+            }
+        }
+
+        Ok(spv_client_cell)
+    }
+
+    fn ensure_admin_token(&self, admin_token: &str) -> RpcResult<()> {
+        let expected = if let Some(ref expected) = self.admin_token {
+            expected
+        } else {
+            let desc = "admin APIs are disabled";
+            log::warn!("rejected the admin API since {desc}");
+            return Err(ApiErrorCode::AdminApisDisabled.with_desc(desc));
+        };
+        if !eq_in_constant_time(expected.as_bytes(), admin_token.as_bytes()) {
+            let desc = "the admin token is incorrect";
+            log::warn!("rejected the admin API since {desc}");
+            return Err(ApiErrorCode::AdminTokenIncorrect.with_desc(desc));
+        }
+        Ok(())
+    }
+
+    fn ensure_batch_size(&self, size: usize) -> RpcResult<()> {
+        if size > self.max_batch_size {
+            let message = format!(
+                "the batch has {size} items, which is more than the limit {}",
+                self.max_batch_size
+            );
+            return Err(RpcError {
+                code: RpcErrorCode::InvalidParams,
+                message,
+                data: None,
+            });
+        }
+        Ok(())
+    }
+
+    // Checks whether the header is in the local storage, which only keeps headers
+    // from the base height to the tip height.
+    fn ensure_header_in_storage(&self, height: u32) -> RpcResult<()> {
+        let spv = &self.spv_service;
+
+        let (stg_base_height, _) = spv.storage.base_state().map_err(|err| {
+            let message = "failed to read base bitcoin height from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        let (stg_tip_height, _) = spv.storage.tip_state().map_err(|err| {
+            let message = "failed to read tip bitcoin height from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        if height < stg_base_height || height > stg_tip_height {
+            let desc = format!(
+                "local storage only has headers from header#{stg_base_height} \
+                to header#{stg_tip_height}, but header#{height} is required"
+            );
+            return Err(ApiErrorCode::StorageHeaderMissing.with_desc(desc));
+        }
+        let is_pruned = spv
+            .storage
+            .is_bitcoin_header_pruned(height)
+            .map_err(|err| {
+                let message = "failed to read pruned bitcoin height from local storage".to_owned();
+                log::error!("{message} since {err}");
+                RpcError {
+                    code: RpcErrorCode::InternalError,
+                    message,
+                    data: None,
+                }
+            })?;
+        if is_pruned {
+            let desc = format!("header#{height} is pruned from local storage");
+            return Err(ApiErrorCode::StorageHeaderPruned.with_desc(desc));
+        }
+        Ok(())
+    }
+
+    // Finds the divergence point, the tip height of the highest SPV client on
+    // chain which matches the local storage; the clients higher than the
+    // storage tip are not comparable.
+    fn find_highest_matched_height(&self, instance: &SpvInstance) -> Option<u32> {
+        let storage = &self.spv_service.storage;
+        instance
+            .clients
+            .values()
+            .filter(|cell| {
+                let spv_header_root = &cell.client.headers_mmr_root;
+                storage
+                    .generate_headers_root(spv_header_root.max_height)
+                    .map(|stg_header_root| {
+                        stg_header_root.as_slice() == spv_header_root.pack().as_slice()
+                    })
+                    .unwrap_or(false)
+            })
+            .map(|cell| cell.client.headers_mmr_root.max_height)
+            .max()
+    }
+
+    // The index of heights is always looked up, but the recent headers are
+    // only looked up one by one when the storage is preferred.
+    fn find_height_in_storage(&self, block_hash: BlockHash) -> Option<u32> {
+        let hash = block_hash.into();
+        let storage = &self.spv_service.storage;
+        let result = if self.prefer_storage_heights {
+            storage.find_bitcoin_height(&hash, STORAGE_HEIGHT_LOOKUP_LIMIT)
+        } else {
+            storage.bitcoin_height(&hash)
+        };
+        match result {
+            Ok(Some(height)) => {
+                log::trace!(">>> found header#{height} for {block_hash:#x} in local storage");
+                Some(height)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                log::warn!("failed to find the height of {block_hash:#x} in storage since {err}");
+                None
+            }
+        }
+    }
+
+    fn get_tx_proof_internal(
+        &self,
+        txid: Txid,
+        tx_index: u32,
+        block_hash_opt: Option<BlockHash>,
+        confirmations: u32,
+    ) -> RpcResult<(BitcoinTxProof, SpvClientCell)> {
+        let metrics = &self.spv_service.metrics;
+        metrics.inc_tx_proof_requests();
+        self.generate_tx_proof(txid, tx_index, block_hash_opt, confirmations)
+            .map_err(|err| {
+                metrics.inc_tx_proof_errors(err.code.code());
+                err
+            })
+    }
+
+    fn generate_tx_proof(
+        &self,
+        txid: Txid,
+        tx_index: u32,
+        block_hash_opt: Option<BlockHash>,
+        confirmations: u32,
+    ) -> RpcResult<(BitcoinTxProof, SpvClientCell)> {
+        let spv = &self.spv_service;
+
+        self.ensure_no_reorg_in_progress()?;
+
+        let (target_height, target_hash, raw_tx_out_proof) =
+            self.fetch_tx_out_proof(txid, tx_index, block_hash_opt)?;
+
+        let (stg_tip_height, _) = spv.storage.tip_state().map_err(|err| {
+            let message = "failed to read tip bitcoin height from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        log::debug!(">>> tip height in local storage is {stg_tip_height}");
+
+        self.check_tx_in_storage(stg_tip_height, target_height, target_hash, confirmations)?;
+
+        let spv_client_cell =
+            self.select_spv_client(stg_tip_height, target_height, confirmations)?;
+
+        let btc_tx_proof =
+            self.build_tx_proof(&spv_client_cell, tx_index, target_height, raw_tx_out_proof)?;
+        Ok((btc_tx_proof, spv_client_cell))
+    }
+
+    // Fetches the tx out proof from the Bitcoin node, and returns the height and
+    // the hash of the block which contains the transaction.
+    fn fetch_tx_out_proof(
+        &self,
+        txid: Txid,
+        tx_index: u32,
+        block_hash_opt: Option<BlockHash>,
+    ) -> RpcResult<(u32, Hash, Vec<u8>)> {
+        let spv = &self.spv_service;
+
+        tokio::task::block_in_place(|| -> RpcResult<(u32, Hash, Vec<u8>)> {
+            let (merkle_block, raw_tx_out_proof) = spv
+                .btc_cli
+                .get_tx_out_proof(txid, block_hash_opt)
+                .map_err(|err| {
+                    if err.rpc_code() == Some(BTC_RPC_INVALID_ADDRESS_OR_KEY) {
+                        let desc = format!("target transaction {txid:#x} is not found in blocks");
+                        log::debug!("{desc} since {err}");
+                        return ApiErrorCode::BitcoinTxNotFound.with_desc(desc);
+                    }
+                    let message = format!("failed to get tx out proof for {txid:#x} from remote");
+                    log::error!("{message} since {err}");
+                    RpcError {
+                        code: RpcErrorCode::InternalError,
+                        message,
+                        data: None,
+                    }
+                })?;
+            let mut matches = Vec::new();
+            let mut indexes = Vec::new();
+            merkle_block
+                .extract_matches(&mut matches, &mut indexes)
+                .map_err(|err| {
+                    let desc = format!("the tx out proof for {txid:#x} from remote is invalid");
+                    log::error!("{desc} since {err}");
+                    ApiErrorCode::BitcoinTxProofInvalid.with_desc(desc)
+                })?;
+            let tx_count = merkle_block.txn.num_transactions();
+            if tx_index >= tx_count {
+                let desc = format!(
+                    "the input index {tx_index} is out of range, \
+                    the block of target transaction {txid:#x} has {tx_count} transactions"
+                );
+                return Err(ApiErrorCode::BitcoinTxIndexOutOfRange.with_desc(desc));
+            }
+            let positions = matches
+                .iter()
+                .zip(indexes.iter())
+                .filter_map(|(id, index)| (*id == txid).then_some(*index))
+                .collect::<Vec<_>>();
+            match positions.as_slice() {
+                [index] if *index == tx_index => {}
+                [index] => {
+                    let desc = format!(
+                        "target transaction {txid:#x} is at index {index} in its block, \
+                        but the input index is {tx_index}"
+                    );
+                    return Err(ApiErrorCode::BitcoinTxIndexUnmatched.with_desc(desc));
+                }
+                _ => {
+                    let desc = format!(
+                        "expect exactly one position for target transaction {txid:#x} \
+                        in the tx out proof from remote, but got {}",
+                        positions.len()
+                    );
+                    return Err(ApiErrorCode::BitcoinTxProofInvalid.with_desc(desc));
+                }
+            }
+            let block_hash = merkle_block.header.block_hash();
+            log::trace!(">>> the input tx in header {block_hash:#x}");
+            let block_height = if let Some(height) = self.find_height_in_storage(block_hash) {
+                height
+            } else {
+                spv.btc_cli.get_block_height(block_hash).map_err(|err| {
+                    let message =
+                        format!("failed to get block height for {block_hash:#x} from remote");
+                    log::error!("{message} since {err}");
+                    RpcError {
+                        code: RpcErrorCode::InternalError,
+                        message,
+                        data: None,
+                    }
+                })?
+            };
+            log::trace!(">>> the input tx in header {block_height}");
+            Ok((block_height, block_hash.into(), raw_tx_out_proof))
+        })
+    }
+
+    fn check_tx_in_storage(
+        &self,
+        stg_tip_height: u32,
+        target_height: u32,
+        target_hash: Hash,
+        confirmations: u32,
+    ) -> RpcResult<()> {
+        let spv = &self.spv_service;
+
+        if stg_tip_height < target_height + confirmations {
+            self.prefetch_headers();
+        }
+        if stg_tip_height < target_height {
+            let desc = format!(
+                "target transaction is in header#{target_height}, \
+                but the tip header in local storage is header#{stg_tip_height}"
+            );
+            return Err(ApiErrorCode::StorageTxTooNew.with_desc(desc));
+        }
+        if stg_tip_height < target_height + confirmations {
+            let desc = format!(
+                "target transaction is in header#{target_height} \
+                and it requires {confirmations} confirmations, \
+                but the tip header in local storage is header#{stg_tip_height}"
+            );
+            return Err(ApiErrorCode::StorageTxUnconfirmed.with_desc(desc));
+        }
+        self.ensure_header_in_storage(target_height)?;
+        let stg_target_hash = spv
+            .storage
+            .bitcoin_header_hash(target_height)
+            .map_err(|err| {
+                let desc = format!("local storage doesn't have header#{target_height}");
+                log::error!("{desc} since {err}");
+                ApiErrorCode::StorageHeaderMissing.with_desc(desc)
+            })?;
+        // The merkle root of the tx out proof has been checked against its header,
+        // and the header hash covers the merkle root, so when both hashes match,
+        // the proof also matches the merkle root of the header in local storage.
+        if target_hash != stg_target_hash {
+            let desc = format!(
+                "target transaction is in header#{target_height}, \
+                the header hash from remote is {target_hash:#x}, \
+                its hash in local storage is {stg_target_hash:#x}"
+            );
+            return Err(ApiErrorCode::StorageHeaderUnmatched.with_desc(desc));
+        }
+        Ok(())
+    }
+
+    // Prefetches headers in background, at most one prefetch runs at the same time,
+    // and a new one is not started until the interval elapsed.
+    fn prefetch_headers(&self) {
+        let count = match self.header_prefetch_ahead {
+            Some(count) if count > 0 => count,
+            _ => return,
+        };
+        let prefetch = &self.header_prefetch;
+        if let Ok(last_started_at) = prefetch.last_started_at.read() {
+            let is_due = last_started_at
+                .map(|instant| instant.elapsed().as_secs() >= HEADER_PREFETCH_INTERVAL_SECS)
+                .unwrap_or(true);
+            if !is_due {
+                return;
             }
         }
+        if prefetch.in_progress.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Ok(mut last_started_at) = prefetch.last_started_at.write() {
+            *last_started_at = Some(Instant::now());
+        }
+
+        let spv_service = self.spv_service.clone();
+        let prefetch = Arc::clone(prefetch);
+        thread::spawn(move || {
+            match spv_service.prefetch_headers(count) {
+                Ok(tip_height) => {
+                    log::debug!("[prefetch] the tip header in local storage is header#{tip_height}")
+                }
+                Err(err) => log::warn!("failed to prefetch headers since {err}"),
+            }
+            prefetch.in_progress.store(false, Ordering::SeqCst);
+        });
+    }
+
+    fn build_tx_proof(
+        &self,
+        spv_client_cell: &SpvClientCell,
+        tx_index: u32,
+        target_height: u32,
+        raw_tx_out_proof: Vec<u8>,
+    ) -> RpcResult<BitcoinTxProof> {
+        let spv = &self.spv_service;
 
         let header_proof = spv
             .storage
@@ -424,6 +1389,16 @@ impl SpvRpc for SpvRpcImpl {
                 }
             })?;
 
+        let header = spv.storage.bitcoin_header(target_height).map_err(|err| {
+            let message = format!("failed to read header#{target_height} from local storage");
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+
         let tx_proof: Bytes = packed::TransactionProof::new_builder()
             .tx_index(tx_index.pack())
             .height(target_height.pack())
@@ -433,9 +1408,1004 @@ impl SpvRpc for SpvRpcImpl {
             .as_bytes();
 
         let btc_tx_proof = BitcoinTxProof {
-            spv_client: spv_client_cell.cell.out_point.into(),
+            spv_client: spv_client_cell.cell.out_point.clone().into(),
             proof: JsonBytes::from_bytes(tx_proof),
+            header: JsonBytes::from_vec(serialize(&header)),
         };
         Ok(btc_tx_proof)
     }
 }
+
+impl CacheStat {
+    fn new(size: usize, hits: u64, misses: u64) -> Self {
+        let hit_ratio = if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        };
+        Self {
+            size,
+            hits,
+            misses,
+            hit_ratio,
+        }
+    }
+}
+
+impl SpvRpc for SpvRpcImpl {
+    fn get_tx_proof(
+        &self,
+        txid: Txid,
+        tx_index: u32,
+        confirmations: u32,
+        block_hash_opt: Option<BlockHash>,
+    ) -> RpcResult<BitcoinTxProof> {
+        log::debug!("Call getTxProof with params [{txid:#x}, {confirmations}, {block_hash_opt:?}]");
+        self.get_tx_proof_internal(txid, tx_index, block_hash_opt, confirmations)
+            .map(|(tx_proof, _)| tx_proof)
+    }
+
+    fn get_tx_proof_by_block_hash(
+        &self,
+        txid: Txid,
+        tx_index: u32,
+        block_hash: BlockHash,
+        confirmations: u32,
+    ) -> RpcResult<BitcoinTxProof> {
+        log::debug!("Call getTxProofByBlockHash as an alias of getTxProof");
+        self.get_tx_proof(txid, tx_index, confirmations, Some(block_hash))
+    }
+
+    fn get_tx_proof_by_out_point(
+        &self,
+        txid: Txid,
+        vout: u32,
+        tx_index: u32,
+        confirmations: u32,
+    ) -> RpcResult<BitcoinTxProof> {
+        log::debug!(
+            "Call getTxProofByOutPoint with params [{txid:#x}, {vout}, {tx_index}, {confirmations}]"
+        );
+        let spv = &self.spv_service;
+
+        let tx = tokio::task::block_in_place(|| {
+            spv.btc_cli.get_raw_transaction(txid).map_err(|err| {
+                if err.rpc_code() == Some(BTC_RPC_INVALID_ADDRESS_OR_KEY) {
+                    let desc = format!("target transaction {txid:#x} is not found");
+                    log::debug!("{desc} since {err}");
+                    return ApiErrorCode::BitcoinTxNotFound.with_desc(desc);
+                }
+                let message = format!("failed to get transaction {txid:#x} from remote");
+                log::error!("{message} since {err}");
+                RpcError {
+                    code: RpcErrorCode::InternalError,
+                    message,
+                    data: None,
+                }
+            })
+        })?;
+        if vout as usize >= tx.output.len() {
+            let desc = format!(
+                "target transaction {txid:#x} has {} outputs, but the input vout is {vout}",
+                tx.output.len()
+            );
+            return Err(ApiErrorCode::BitcoinTxOutputNotFound.with_desc(desc));
+        }
+
+        self.get_tx_proof_internal(txid, tx_index, None, confirmations)
+            .map(|(tx_proof, _)| tx_proof)
+    }
+
+    fn get_header_proof(
+        &self,
+        mut heights: Vec<u32>,
+        confirmations: u32,
+    ) -> RpcResult<BitcoinHeaderProof> {
+        log::debug!("Call getHeaderProof with params [{heights:?}, {confirmations}]");
+        let spv = &self.spv_service;
+
+        self.ensure_batch_size(heights.len())?;
+        self.ensure_no_reorg_in_progress()?;
+
+        // The positions in a MMR proof should be sorted and unique.
+        heights.sort_unstable();
+        heights.dedup();
+
+        let target_height = if let Some(height) = heights.iter().max() {
+            *height
+        } else {
+            return Err(RpcError {
+                code: RpcErrorCode::InvalidParams,
+                message: "at least one height is required".to_owned(),
+                data: None,
+            });
+        };
+        for height in &heights {
+            self.ensure_header_in_storage(*height)?;
+        }
+
+        let (stg_tip_height, _) = spv.storage.tip_state().map_err(|err| {
+            let message = "failed to read tip bitcoin height from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        log::debug!(">>> tip height in local storage is {stg_tip_height}");
+
+        // Same as the confirmations which are required for transactions.
+        if stg_tip_height < target_height + confirmations {
+            let desc = format!(
+                "header#{target_height} requires {confirmations} confirmations, \
+                but the tip header in local storage is header#{stg_tip_height}"
+            );
+            return Err(ApiErrorCode::StorageTxUnconfirmed.with_desc(desc));
+        }
+
+        let spv_client_cell =
+            self.select_spv_client(stg_tip_height, target_height, confirmations)?;
+
+        let header_proof = spv
+            .storage
+            .generate_headers_proof(spv_client_cell.client.headers_mmr_root.max_height, heights)
+            .map_err(|err| {
+                let message = "failed to generate headers MMR proof".to_owned();
+                log::error!("{message} since {err}");
+                RpcError {
+                    code: RpcErrorCode::InternalError,
+                    message,
+                    data: None,
+                }
+            })?;
+        let proof: Bytes = header_proof.pack().as_bytes();
+
+        let btc_header_proof = BitcoinHeaderProof {
+            spv_client: spv_client_cell.cell.out_point.into(),
+            proof: JsonBytes::from_bytes(proof),
+        };
+        Ok(btc_header_proof)
+    }
+
+    fn get_client_for_height(
+        &self,
+        height: u32,
+        confirmations: u32,
+    ) -> RpcResult<SpvClientForHeight> {
+        log::debug!("Call getClientForHeight with params [{height}, {confirmations}]");
+        let spv = &self.spv_service;
+
+        self.ensure_no_reorg_in_progress()?;
+        self.ensure_header_in_storage(height)?;
+
+        let (stg_tip_height, _) = spv.storage.tip_state().map_err(|err| {
+            let message = "failed to read tip bitcoin height from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        log::debug!(">>> tip height in local storage is {stg_tip_height}");
+
+        // The same SPV client as the proofs for this height would use.
+        let spv_client_cell = self.select_spv_client(stg_tip_height, height, confirmations)?;
+
+        let headers_mmr_root = &spv_client_cell.client.headers_mmr_root;
+        let result = SpvClientForHeight {
+            spv_client: spv_client_cell.cell.out_point.into(),
+            client: SpvClientInfo {
+                id: spv_client_cell.client.id,
+                min_height: headers_mmr_root.min_height,
+                max_height: headers_mmr_root.max_height,
+            },
+        };
+        Ok(result)
+    }
+
+    fn get_tx_proofs(
+        &self,
+        requests: Vec<(Txid, u32, u32)>,
+    ) -> RpcResult<Vec<BitcoinTxProofResult>> {
+        log::debug!("Call getTxProofs with {} requests", requests.len());
+        let spv = &self.spv_service;
+
+        self.ensure_batch_size(requests.len())?;
+        self.ensure_no_reorg_in_progress()?;
+
+        let (stg_tip_height, _) = spv.storage.tip_state().map_err(|err| {
+            let message = "failed to read tip bitcoin height from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        log::debug!(">>> tip height in local storage is {stg_tip_height}");
+
+        let metrics = &spv.metrics;
+        // The SPV client is selected once for each required height, so the
+        // failure of a height doesn't affect the requests for other heights.
+        let mut spv_client_cells = HashMap::new();
+        let results = requests
+            .into_iter()
+            .map(|(txid, tx_index, confirmations)| {
+                metrics.inc_tx_proof_requests();
+                let (target_height, target_hash, raw_tx_out_proof) =
+                    self.fetch_tx_out_proof(txid, tx_index, None)?;
+                self.check_tx_in_storage(
+                    stg_tip_height,
+                    target_height,
+                    target_hash,
+                    confirmations,
+                )?;
+                let spv_client_cell = spv_client_cells
+                    .entry((target_height, confirmations))
+                    .or_insert_with(|| {
+                        self.select_spv_client(stg_tip_height, target_height, confirmations)
+                    })
+                    .clone()?;
+                self.build_tx_proof(&spv_client_cell, tx_index, target_height, raw_tx_out_proof)
+            })
+            .map(|result| match result {
+                Ok(tx_proof) => BitcoinTxProofResult {
+                    result: Some(tx_proof),
+                    error: None,
+                },
+                Err(err) => {
+                    metrics.inc_tx_proof_errors(err.code.code());
+                    BitcoinTxProofResult {
+                        result: None,
+                        error: Some(err),
+                    }
+                }
+            })
+            .collect();
+        Ok(results)
+    }
+
+    fn get_headers_proof_for_range(
+        &self,
+        from: u32,
+        to: u32,
+        spv_client_height: u32,
+    ) -> RpcResult<JsonBytes> {
+        log::debug!("Call getHeadersProofForRange with params [{from}, {to}, {spv_client_height}]");
+        let spv = &self.spv_service;
+
+        if from > to || to > spv_client_height {
+            let message = format!(
+                "the range from header#{from} to header#{to} should be not empty, \
+                and be covered by the SPV client at header#{spv_client_height}"
+            );
+            return Err(RpcError {
+                code: RpcErrorCode::InvalidParams,
+                message,
+                data: None,
+            });
+        }
+        // Checked before collecting the heights, the span is at most `u32::MAX`.
+        if to - from >= self.max_headers_range {
+            let message = format!(
+                "the range from header#{from} to header#{to} has more than {} headers",
+                self.max_headers_range
+            );
+            return Err(RpcError {
+                code: RpcErrorCode::InvalidParams,
+                message,
+                data: None,
+            });
+        }
+        self.ensure_header_in_storage(from)?;
+        self.ensure_header_in_storage(spv_client_height)?;
+
+        let header_proof = spv
+            .storage
+            .generate_headers_proof(spv_client_height, (from..=to).collect())
+            .map_err(|err| {
+                let message = "failed to generate headers MMR proof".to_owned();
+                log::error!("{message} since {err}");
+                RpcError {
+                    code: RpcErrorCode::InternalError,
+                    message,
+                    data: None,
+                }
+            })?;
+        let proof: Bytes = header_proof.pack().as_bytes();
+
+        Ok(JsonBytes::from_bytes(proof))
+    }
+
+    fn verify_tx_proof(
+        &self,
+        proof: BitcoinTxProof,
+        txid: Txid,
+    ) -> RpcResult<BitcoinTxProofVerification> {
+        log::debug!("Call verifyTxProof with params [.., {txid:#x}]");
+        let spv = &self.spv_service;
+
+        let tx_proof =
+            packed::TransactionProof::from_slice(proof.proof.as_bytes()).map_err(|err| {
+                let message = format!("the proof is not a packed transaction proof since {err}");
+                RpcError {
+                    code: RpcErrorCode::InvalidParams,
+                    message,
+                    data: None,
+                }
+            })?;
+        let tx_index: u32 = tx_proof.tx_index().unpack();
+        let height: u32 = tx_proof.height().unpack();
+        let merkle_block: MerkleBlock = deserialize(&tx_proof.transaction_proof().raw_data())
+            .map_err(|err| {
+                let message = format!("the tx out proof in the proof is invalid since {err}");
+                RpcError {
+                    code: RpcErrorCode::InvalidParams,
+                    message,
+                    data: None,
+                }
+            })?;
+
+        // The merkle root is recomputed and checked against the header of the tx out proof.
+        let mut matches = Vec::new();
+        let mut indexes = Vec::new();
+        let tx_matched = merkle_block
+            .extract_matches(&mut matches, &mut indexes)
+            .is_ok()
+            && matches
+                .iter()
+                .zip(indexes.iter())
+                .any(|(id, index)| *id == txid && *index == tx_index);
+
+        let header_matched = spv
+            .storage
+            .bitcoin_header_hash(height)
+            .map(|stg_hash| stg_hash == merkle_block.header.block_hash().into())
+            .unwrap_or(false);
+
+        let spv_type_script = spv.storage.spv_contract_type_script().map_err(|err| {
+            let message = "failed to get SPV contract type script from storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        let spv_instance = self.fetch_spv_instance(spv_type_script)?;
+        let spv_client_cell = spv_instance
+            .clients
+            .values()
+            .find(|client| OutPoint::from(client.cell.out_point.clone()) == proof.spv_client);
+        let spv_client_live = spv_client_cell.is_some();
+
+        // The proof of headers is deterministic, so generate it from the local storage
+        // and compare them.
+        let header_proof_matched = if let Some(spv_client_cell) = spv_client_cell {
+            let spv_best_height = spv_client_cell.client.headers_mmr_root.max_height;
+            if height <= spv_best_height && self.ensure_header_in_storage(spv_best_height).is_ok() {
+                let stg_header_proof = spv
+                    .storage
+                    .generate_headers_proof(spv_best_height, vec![height])
+                    .map_err(|err| {
+                        let message = "failed to generate headers MMR proof".to_owned();
+                        log::error!("{message} since {err}");
+                        RpcError {
+                            code: RpcErrorCode::InternalError,
+                            message,
+                            data: None,
+                        }
+                    })?;
+                stg_header_proof.pack().as_slice() == tx_proof.header_proof().as_slice()
+            } else {
+                false
+            }
+        } else {
+            false
+        };
+
+        let verification = BitcoinTxProofVerification {
+            height,
+            tx_matched,
+            header_matched,
+            header_proof_matched,
+            spv_client_live,
+        };
+        Ok(verification)
+    }
+
+    fn get_proof_bundle(
+        &self,
+        txid: Txid,
+        tx_index: u32,
+        confirmations: u32,
+    ) -> RpcResult<BitcoinTxProofBundle> {
+        log::debug!("Call getProofBundle with params [{txid:#x}, {confirmations}]");
+        let (tx_proof, spv_client_cell) =
+            self.get_tx_proof_internal(txid, tx_index, None, confirmations)?;
+        let bundle = BitcoinTxProofBundle {
+            tx_proof,
+            spv_client_output: spv_client_cell.cell.output.into(),
+            spv_client_data: JsonBytes::from_bytes(spv_client_cell.cell.output_data),
+        };
+        Ok(bundle)
+    }
+
+    fn ping(&self) -> RpcResult<String> {
+        if let Some(ref startup) = self.startup_sync_deadline {
+            if !startup.is_synced.load(Ordering::Relaxed) {
+                // Only the state cached by the sync loop is checked, since the
+                // remote nodes are checked by the readiness API.
+                let health = self.spv_service.cached_health();
+                match (health.storage_tip_height, health.bitcoin_tip_height) {
+                    (Some(stg_tip_height), Some(_)) if health.is_synced(self.safe_depth) => {
+                        log::info!(
+                            "The initial sync is finished, \
+                            the tip header in local storage is header#{stg_tip_height}"
+                        );
+                        startup.is_synced.store(true, Ordering::Relaxed);
+                    }
+                    (stg_tip_height, btc_tip_height)
+                        if startup.started_at.elapsed() > startup.deadline =>
+                    {
+                        let secs = startup.deadline.as_secs();
+                        let desc = if let (Some(stg_tip_height), Some(btc_tip_height)) =
+                            (stg_tip_height, btc_tip_height)
+                        {
+                            format!(
+                                "the initial sync is not finished in {secs} seconds, \
+                                the tip header in local storage is header#{stg_tip_height}, \
+                                but the tip header of bitcoin is header#{btc_tip_height}"
+                            )
+                        } else {
+                            format!(
+                                "the initial sync is not finished in {secs} seconds, since {}",
+                                health.errors.join("; ")
+                            )
+                        };
+                        log::warn!("{desc}");
+                        return Err(ApiErrorCode::StorageStartupSyncTimeout.with_desc(desc));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok("pong".to_owned())
+    }
+
+    fn get_mmr_root(&self, height: u32) -> RpcResult<JsonBytes> {
+        log::debug!("Call getMmrRoot with params [{height}]");
+        let spv = &self.spv_service;
+
+        self.ensure_header_in_storage(height)?;
+
+        let packed_stg_header_root = spv.storage.generate_headers_root(height).map_err(|err| {
+            let message = format!("failed to generate headers MMR root for height {height}");
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        let root: Bytes = packed_stg_header_root.as_bytes();
+
+        Ok(JsonBytes::from_bytes(root))
+    }
+
+    fn get_header_by_height(&self, height: u32) -> RpcResult<BitcoinHeader> {
+        log::debug!("Call getHeaderByHeight with params [{height}]");
+        let spv = &self.spv_service;
+
+        self.ensure_header_in_storage(height)?;
+
+        let header = spv.storage.bitcoin_header(height).map_err(|err| {
+            let message = format!("failed to read header#{height} from local storage");
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+
+        Ok(BitcoinHeader {
+            height,
+            hash: header.block_hash(),
+            raw: JsonBytes::from_vec(serialize(&header)),
+            version: header.version.to_consensus(),
+            prev_blockhash: header.prev_blockhash,
+            merkle_root: header.merkle_root,
+            time: header.time,
+            // Same as the format in Bitcoin RPC APIs.
+            bits: format!("{:08x}", header.bits.to_consensus()),
+            nonce: header.nonce,
+        })
+    }
+
+    fn get_health(&self) -> RpcResult<HealthState> {
+        log::debug!("Call getHealth");
+        let health = tokio::task::block_in_place(|| self.spv_service.health());
+        let is_ready = health.is_ready(self.safe_depth);
+        Ok(HealthState { health, is_ready })
+    }
+
+    fn get_sync_state(&self) -> RpcResult<SyncState> {
+        log::debug!("Call getSyncState");
+        let spv = &self.spv_service;
+
+        let (base_height, _) = spv.storage.base_state().map_err(|err| {
+            let message = "failed to read base bitcoin height from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        let (tip_height, _) = spv.storage.tip_state().map_err(|err| {
+            let message = "failed to read tip bitcoin height from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        let last_spv_tx_hash = spv.storage.last_spv_tx_hash().map_err(|err| {
+            let message = "failed to read the last SPV tx hash from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        let last_synced_at = spv.storage.last_sync_timestamp().map_err(|err| {
+            let message = "failed to read the last sync timestamp from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        let ckb_balance = spv.storage.last_ckb_balance().map_err(|err| {
+            let message = "failed to read the last CKB balance from local storage".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+
+        let state = SyncState {
+            base_height,
+            tip_height,
+            last_spv_tx_hash,
+            last_synced_at,
+            ckb_balance,
+        };
+        Ok(state)
+    }
+
+    fn get_spv_instance_info(&self) -> RpcResult<SpvInstanceInfo> {
+        log::debug!("Call getSpvInstanceInfo");
+        let spv = &self.spv_service;
+
+        let spv_instance = if let Some(spv_instance) = self.load_cache_spv_instance() {
+            spv_instance
+        } else {
+            let spv_type_script = spv.storage.spv_contract_type_script().map_err(|err| {
+                let message = "failed to get SPV contract type script from storage".to_owned();
+                log::error!("{message} since {err}");
+                RpcError {
+                    code: RpcErrorCode::InternalError,
+                    message,
+                    data: None,
+                }
+            })?;
+            self.fetch_spv_instance(spv_type_script)?
+        };
+
+        let tip_client_cell = spv_instance.find_tip_spv_client().map_err(|err| {
+            let message = "failed to get the tip SPV client".to_owned();
+            log::error!("{message} since {err}");
+            RpcError {
+                code: RpcErrorCode::InternalError,
+                message,
+                data: None,
+            }
+        })?;
+        let mut clients = spv_instance
+            .clients
+            .iter()
+            .map(|(id, cell)| SpvClientInfo {
+                id: *id,
+                min_height: cell.client.headers_mmr_root.min_height,
+                max_height: cell.client.headers_mmr_root.max_height,
+            })
+            .collect::<Vec<_>>();
+        clients.sort_by_key(|client| client.id);
+
+        let info = SpvInstanceInfo {
+            tip_client_id: spv_instance.info.info.tip_client_id,
+            clients_count: spv_instance.info.clients_count,
+            best_height: tip_client_cell.client.headers_mmr_root.max_height,
+            tip_client: tip_client_cell.cell.out_point.into(),
+            clients,
+        };
+        Ok(info)
+    }
+
+    fn get_cache_stats(&self) -> RpcResult<CacheStats> {
+        log::debug!("Call getCacheStats");
+        let size = self
+            .cached_spv_instance
+            .read()
+            .map(|locked| usize::from(locked.is_some()))
+            .unwrap_or_default();
+        let hits = self.cached_spv_instance_hits.load(Ordering::Relaxed);
+        let misses = self.cached_spv_instance_misses.load(Ordering::Relaxed);
+        let spv_instance = CacheStat::new(size, hits, misses);
+        let (size, hits, misses) =
+            self.spv_service
+                .storage
+                .header_cache_stats()
+                .map_err(|err| {
+                    let message = "failed to read the statistics of the cached headers".to_owned();
+                    log::error!("{message} since {err}");
+                    RpcError {
+                        code: RpcErrorCode::InternalError,
+                        message,
+                        data: None,
+                    }
+                })?;
+        let headers = CacheStat::new(size, hits, misses);
+        Ok(CacheStats {
+            spv_instance,
+            headers,
+        })
+    }
+
+    fn drop_caches(&self, admin_token: String) -> RpcResult<()> {
+        log::debug!("Call dropCaches");
+        self.ensure_admin_token(&admin_token)?;
+        match self.cached_spv_instance.write() {
+            Ok(mut locked) => *locked = None,
+            Err(err) => {
+                let message = "failed to drop the cached SPV instance".to_owned();
+                log::error!("{message} since {err}");
+                return Err(RpcError {
+                    code: RpcErrorCode::InternalError,
+                    message,
+                    data: None,
+                });
+            }
+        }
+        self.cached_spv_instance_hits.store(0, Ordering::Relaxed);
+        self.cached_spv_instance_misses.store(0, Ordering::Relaxed);
+        self.spv_service
+            .storage
+            .clear_header_cache()
+            .map_err(|err| {
+                let message = "failed to drop the cached headers".to_owned();
+                log::error!("{message} since {err}");
+                RpcError {
+                    code: RpcErrorCode::InternalError,
+                    message,
+                    data: None,
+                }
+            })?;
+        log::info!("All caches are dropped");
+        Ok(())
+    }
+}
+
+// Compares in constant time, so the token can't be guessed by the timing of
+// responses; only its length could be leaked.
+fn eq_in_constant_time(lhs: &[u8], rhs: &[u8]) -> bool {
+    lhs.len() == rhs.len() && lhs.iter().zip(rhs).fold(0u8, |diff, (l, r)| diff | (l ^ r)) == 0
+}
+
+// Each diverged header takes a while to be reorganized on chain,
+// but clients should not wait longer than a Bitcoin block interval.
+fn estimate_reorg_retry_after(reorg_depth: u32) -> u64 {
+    (u64::from(reorg_depth) * REORG_RETRY_AFTER_SECS)
+        .clamp(REORG_RETRY_AFTER_SECS, BITCOIN_BLOCK_INTERVAL_SECS)
+}
+
+fn is_reorg_error(err: &RpcError) -> bool {
+    [
+        ApiErrorCode::OnchainReorgRequired,
+        ApiErrorCode::OnchainInstanceDiverged,
+    ]
+    .into_iter()
+    .any(|code| err.code == RpcErrorCode::ServerError(code as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroU32,
+        sync::atomic::AtomicBool,
+        time::{Duration, Instant},
+    };
+
+    use bitcoin::{constants::genesis_block, hashes::Hash as _, Network, Txid};
+    use ckb_sdk::rpc::CkbRpcClient;
+    use jsonrpc_core::{ErrorCode as RpcErrorCode, Result as RpcResult};
+
+    use super::{
+        eq_in_constant_time, estimate_reorg_retry_after, is_reorg_error, ApiErrorCode, SpvRpc as _,
+        SpvRpcImpl, StartupSyncDeadline, BITCOIN_BLOCK_INTERVAL_SECS, REORG_RETRY_AFTER_SECS,
+    };
+    use crate::{
+        components::{BitcoinClient, SpvService, Storage},
+        prelude::*,
+        utilities::TempDir,
+    };
+
+    // The remote nodes are never connected in these tests.
+    //
+    // The directory is returned first, so it's dropped after the storage.
+    fn new_spv_rpc_impl(name: &str) -> (TempDir, SpvRpcImpl) {
+        let dir = TempDir::new(name);
+        let spv_service = SpvService {
+            ckb_cli: CkbRpcClient::new("http://127.0.0.1:8114"),
+            btc_cli: BitcoinClient::new("http://127.0.0.1:8332".parse().unwrap(), None, None),
+            storage: Storage::new(dir.path()).unwrap(),
+            reorg_in_progress: Default::default(),
+            reorg_refused: Default::default(),
+            bitcoin_tip_height: Default::default(),
+            sync_lock: Default::default(),
+            download_concurrency: NonZeroU32::MIN,
+            metrics: Default::default(),
+        };
+        (dir, SpvRpcImpl::new(spv_service))
+    }
+
+    // Initializes the storage at the base height, then appends `count` headers.
+    //
+    // Only the continuity of headers is checked by the storage, so the headers
+    // are derived from the genesis header.
+    fn init_storage_with_headers(storage: &Storage, base_height: u32, count: u32) {
+        let mut header = genesis_block(Network::Bitcoin).header;
+        storage.initialize_with(base_height, header).unwrap();
+        let mut headers = Vec::new();
+        for nonce in 0..count {
+            header.prev_blockhash = header.block_hash();
+            header.nonce = nonce;
+            headers.push(header);
+        }
+        storage.append_headers(headers).unwrap();
+    }
+
+    fn error_code<T>(result: RpcResult<T>) -> i64 {
+        match result {
+            Ok(_) => panic!("expect an error but got ok"),
+            Err(err) => match err.code {
+                RpcErrorCode::ServerError(code) => code,
+                code => panic!("expect a server error but got {code:?}: {}", err.message),
+            },
+        }
+    }
+
+    fn assert_error_code<T>(result: RpcResult<T>, expected: ApiErrorCode) {
+        let expected = RpcErrorCode::ServerError(expected as i64);
+        match result {
+            Ok(_) => panic!("expect error code {expected:?} but got ok"),
+            Err(err) => assert_eq!(err.code, expected, "{}", err.message),
+        }
+    }
+
+    #[test]
+    fn test_api_error_codes_are_stable() {
+        // The codes are a part of the APIs, so the errors which are returned by
+        // the APIs are checked against the numbers, rather than the enum.
+        let (_dir, mut rpc) = new_spv_rpc_impl("api-error-codes-are-stable");
+        init_storage_with_headers(&rpc.spv_service.storage, 100, 10);
+
+        assert_eq!(error_code(rpc.drop_caches("token".to_owned())), 27001);
+        rpc.admin_token = Some("token".to_owned());
+        assert_eq!(error_code(rpc.drop_caches("tokem".to_owned())), 27002);
+
+        rpc.startup_sync_deadline = Some(StartupSyncDeadline {
+            started_at: Instant::now() - Duration::from_secs(1),
+            deadline: Duration::ZERO,
+            is_synced: AtomicBool::new(false),
+        });
+        assert_eq!(error_code(rpc.ping()), 23901);
+
+        let storage = &rpc.spv_service.storage;
+        let target_hash = storage.bitcoin_header_hash(107).unwrap();
+        let unmatched_hash = storage.bitcoin_header_hash(106).unwrap();
+        let result = rpc.check_tx_in_storage(106, 107, target_hash, 1);
+        assert_eq!(error_code(result), 23101);
+        let result = rpc.check_tx_in_storage(110, 107, target_hash, 6);
+        assert_eq!(error_code(result), 23102);
+        let result = rpc.check_tx_in_storage(110, 107, unmatched_hash, 1);
+        assert_eq!(error_code(result), 23302);
+        assert_eq!(error_code(rpc.get_mmr_root(99)), 23301);
+        storage.prune_headers_below(105).unwrap();
+        assert_eq!(error_code(rpc.get_mmr_root(103)), 23303);
+
+        rpc.spv_service.set_reorg_in_progress(true);
+        let result = rpc.get_tx_proof(Txid::all_zeros(), 0, 6, None);
+        assert_eq!(error_code(result), 25902);
+
+        // The others require the remote nodes, only check the errors which are
+        // built from them.
+        let codes = [
+            (ApiErrorCode::BitcoinTxNotFound, 21001),
+            (ApiErrorCode::BitcoinTxOutputNotFound, 21002),
+            (ApiErrorCode::BitcoinTxProofInvalid, 21101),
+            (ApiErrorCode::BitcoinTxIndexUnmatched, 21102),
+            (ApiErrorCode::BitcoinTxIndexOutOfRange, 21103),
+            (ApiErrorCode::OnchainTxUnconfirmed, 25101),
+            (ApiErrorCode::OnchainInstanceBehind, 25201),
+            (ApiErrorCode::OnchainReorgRequired, 25901),
+            (ApiErrorCode::OnchainInstanceDiverged, 25903),
+        ];
+        for (code, expected) in codes {
+            assert_eq!(error_code::<()>(Err(code.with_desc("desc"))), expected);
+        }
+    }
+
+    #[test]
+    fn test_tx_proof_failure_codes() {
+        let (_dir, rpc) = new_spv_rpc_impl("tx-proof-failure-codes");
+        let target_hash = genesis_block(Network::Bitcoin).block_hash().into();
+
+        let result = rpc.check_tx_in_storage(100, 101, target_hash, 0);
+        assert_error_code(result, ApiErrorCode::StorageTxTooNew);
+
+        let result = rpc.check_tx_in_storage(100, 95, target_hash, 6);
+        assert_error_code(result, ApiErrorCode::StorageTxUnconfirmed);
+
+        assert!(rpc.ensure_no_reorg_in_progress().is_ok());
+        rpc.spv_service.set_reorg_in_progress(true);
+        let result = rpc.ensure_no_reorg_in_progress();
+        assert_error_code(result, ApiErrorCode::OnchainReorgInProgress);
+    }
+
+    #[test]
+    fn test_tx_proof_by_block_hash_is_an_alias() {
+        let (_dir, rpc) = new_spv_rpc_impl("tx-proof-by-block-hash");
+        let txid = Txid::all_zeros();
+        let block_hash = genesis_block(Network::Bitcoin).block_hash();
+
+        rpc.spv_service.set_reorg_in_progress(true);
+        let result = rpc.get_tx_proof(txid, 0, 6, Some(block_hash));
+        assert_error_code(result, ApiErrorCode::OnchainReorgInProgress);
+        let result = rpc.get_tx_proof_by_block_hash(txid, 0, block_hash, 6);
+        assert_error_code(result, ApiErrorCode::OnchainReorgInProgress);
+    }
+
+    #[test]
+    fn test_ping_checks_cached_health() {
+        let (_dir, mut rpc) = new_spv_rpc_impl("ping-checks-cached-health");
+        init_storage_with_headers(&rpc.spv_service.storage, 100, 10);
+        rpc.startup_sync_deadline = Some(StartupSyncDeadline {
+            started_at: Instant::now() - Duration::from_secs(1),
+            deadline: Duration::ZERO,
+            is_synced: AtomicBool::new(false),
+        });
+        let set_bitcoin_tip_height = |height| {
+            *rpc.spv_service.bitcoin_tip_height.lock().unwrap() = Some(height);
+        };
+
+        // The Bitcoin tip is not fetched by the sync loop yet.
+        assert_error_code(rpc.ping(), ApiErrorCode::StorageStartupSyncTimeout);
+        set_bitcoin_tip_height(120);
+        assert_error_code(rpc.ping(), ApiErrorCode::StorageStartupSyncTimeout);
+        set_bitcoin_tip_height(110);
+        assert_eq!(rpc.ping().unwrap(), "pong");
+        // Once synced, the initial sync is not checked anymore.
+        set_bitcoin_tip_height(120);
+        assert_eq!(rpc.ping().unwrap(), "pong");
+    }
+
+    #[test]
+    fn test_admin_token() {
+        assert!(eq_in_constant_time(b"token", b"token"));
+        assert!(!eq_in_constant_time(b"token", b"tokem"));
+        assert!(!eq_in_constant_time(b"token", b"token2"));
+        assert!(!eq_in_constant_time(b"token", b""));
+
+        let (_dir, mut rpc) = new_spv_rpc_impl("admin-token");
+        let result = rpc.drop_caches("token".to_owned());
+        assert_error_code(result, ApiErrorCode::AdminApisDisabled);
+        rpc.admin_token = Some("token".to_owned());
+        let result = rpc.drop_caches("tokem".to_owned());
+        assert_error_code(result, ApiErrorCode::AdminTokenIncorrect);
+        assert!(rpc.ensure_admin_token("token").is_ok());
+    }
+
+    #[test]
+    fn test_storage_header_failure_codes() {
+        let (_dir, rpc) = new_spv_rpc_impl("storage-header-failure-codes");
+        let storage = &rpc.spv_service.storage;
+        init_storage_with_headers(storage, 100, 10);
+
+        let result = rpc.ensure_header_in_storage(99);
+        assert_error_code(result, ApiErrorCode::StorageHeaderMissing);
+        let result = rpc.ensure_header_in_storage(111);
+        assert_error_code(result, ApiErrorCode::StorageHeaderMissing);
+        assert!(rpc.ensure_header_in_storage(103).is_ok());
+
+        let target_hash = storage.bitcoin_header_hash(107).unwrap();
+        assert!(rpc.check_tx_in_storage(110, 107, target_hash, 1).is_ok());
+        let unmatched_hash = storage.bitcoin_header_hash(106).unwrap();
+        let result = rpc.check_tx_in_storage(110, 107, unmatched_hash, 1);
+        assert_error_code(result, ApiErrorCode::StorageHeaderUnmatched);
+
+        assert_eq!(storage.prune_headers_below(105).unwrap(), 4);
+        let result = rpc.ensure_header_in_storage(103);
+        assert_error_code(result, ApiErrorCode::StorageHeaderPruned);
+        assert!(rpc.ensure_header_in_storage(100).is_ok());
+        assert!(rpc.ensure_header_in_storage(105).is_ok());
+    }
+
+    #[test]
+    fn test_reorg_retry_after() {
+        assert_eq!(estimate_reorg_retry_after(0), REORG_RETRY_AFTER_SECS);
+        assert_eq!(estimate_reorg_retry_after(1), REORG_RETRY_AFTER_SECS);
+        assert_eq!(estimate_reorg_retry_after(3), REORG_RETRY_AFTER_SECS * 3);
+        assert_eq!(
+            estimate_reorg_retry_after(u32::MAX),
+            BITCOIN_BLOCK_INTERVAL_SECS
+        );
+    }
+
+    #[test]
+    fn test_header_proof_requires_confirmations() {
+        let (_dir, rpc) = new_spv_rpc_impl("header-proof-confirmations");
+        init_storage_with_headers(&rpc.spv_service.storage, 100, 10);
+
+        let result = rpc.get_header_proof(vec![108, 105, 108], 5).map(|_| ());
+        assert_error_code(result, ApiErrorCode::StorageTxUnconfirmed);
+
+        let mut rpc = rpc;
+        rpc.max_batch_size = 2;
+        let result = rpc.get_header_proof(vec![101, 102, 103], 0).map(|_| ());
+        assert_eq!(
+            result.err().map(|err| err.code),
+            Some(RpcErrorCode::InvalidParams)
+        );
+    }
+
+    #[test]
+    fn test_batch_size_limit() {
+        let (_dir, mut rpc) = new_spv_rpc_impl("batch-size-limit");
+        rpc.max_batch_size = 2;
+        assert!(rpc.ensure_batch_size(2).is_ok());
+        let result = rpc.ensure_batch_size(3);
+        assert_eq!(
+            result.err().map(|err| err.code),
+            Some(RpcErrorCode::InvalidParams)
+        );
+    }
+
+    #[test]
+    fn test_reorg_errors() {
+        let reorg_codes = [
+            ApiErrorCode::OnchainReorgRequired,
+            ApiErrorCode::OnchainInstanceDiverged,
+        ];
+        for code in reorg_codes {
+            assert!(is_reorg_error(&code.with_desc("reorg")));
+        }
+        let other_codes = [
+            ApiErrorCode::OnchainReorgInProgress,
+            ApiErrorCode::OnchainInstanceBehind,
+            ApiErrorCode::StorageHeaderUnmatched,
+        ];
+        for code in other_codes {
+            assert!(!is_reorg_error(&code.with_desc("not reorg")));
+        }
+    }
+}
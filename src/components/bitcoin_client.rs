@@ -1,8 +1,17 @@
 //! A bitcoin client to communicate with a Bitcoin chain.
 
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::{
+    collections::HashMap,
+    io::Read as _,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
 
-use bitcoin::{consensus::deserialize, BlockHash, MerkleBlock, Txid};
+use bitcoin::{consensus::deserialize, BlockHash, MerkleBlock, Network, Transaction, Txid};
 use ckb_bitcoin_spv_verifier::types::core::Header;
 use faster_hex::hex_decode;
 use jsonrpc_core::{Error as RpcError, ErrorCode as RpcErrorCode, Id as RpcId, Value as RpcValue};
@@ -12,21 +21,38 @@ use url::Url;
 
 use crate::result::{BtcRpcError, BtcRpcResult, Error, Result};
 
+// The delay before the first retry, it's doubled before each next retry.
+const RETRY_BASE_DELAY_MILLIS: u64 = 200;
+
 pub struct BitcoinClient {
     client: Client,
-    endpoint: Url,
+    endpoints: Vec<Endpoint>,
+    // The index of the active endpoint, shared by all clones.
+    current: Arc<AtomicUsize>,
+    max_response_bytes: Option<u64>,
+    max_retries: u32,
+    timeout: Option<Duration>,
+    id: AtomicU64,
+}
+
+#[derive(Clone)]
+struct Endpoint {
+    url: Url,
     username: Option<String>,
     password: Option<String>,
-    id: AtomicU64,
 }
 
 impl Clone for BitcoinClient {
     fn clone(&self) -> Self {
-        Self::new(
-            self.endpoint.clone(),
-            self.username.clone(),
-            self.password.clone(),
-        )
+        Self {
+            client: Client::new(),
+            endpoints: self.endpoints.clone(),
+            current: Arc::clone(&self.current),
+            max_response_bytes: self.max_response_bytes,
+            max_retries: self.max_retries,
+            timeout: self.timeout,
+            id: 0.into(),
+        }
     }
 }
 
@@ -69,31 +95,196 @@ pub struct Output {
     pub id: RpcId,
 }
 
+// Only the required fields of the verbose result of "getblockheader".
 #[derive(Deserialize)]
-#[serde(deny_unknown_fields)]
-struct BlockHeight {
+struct BlockHeaderVerbose {
     height: u32,
 }
 
+// Only the required fields of the result of "getblockchaininfo".
+#[derive(Deserialize)]
+struct BlockchainInfo {
+    chain: String,
+}
+
 /// Implement simple JSON-RPC methods.
 impl BitcoinClient {
     pub fn new(endpoint: Url, username: Option<String>, password: Option<String>) -> Self {
         let client = Client::new();
-        Self {
-            client,
-            endpoint,
+        let endpoint = Endpoint {
+            url: endpoint,
             username,
             password,
+        };
+        Self {
+            client,
+            endpoints: vec![endpoint],
+            current: Default::default(),
+            max_response_bytes: None,
+            max_retries: 0,
+            timeout: None,
             id: 0.into(),
         }
     }
 
+    /// Adds a fallback endpoint, which is switched to when the active endpoint
+    /// fails with transient HTTP errors.
+    pub fn fallback_endpoint(
+        mut self,
+        endpoint: Url,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> Self {
+        self.endpoints.push(Endpoint {
+            url: endpoint,
+            username,
+            password,
+        });
+        self
+    }
+
+    /// Aborts when the body of a response is larger than the limit.
+    pub fn max_response_bytes(mut self, limit: Option<u64>) -> Self {
+        self.max_response_bytes = limit;
+        self
+    }
+
+    /// Retries the requests which failed with transient HTTP errors, such as
+    /// timeouts, connection errors and server errors, with exponential backoff.
+    ///
+    /// Each retry is sent to the next endpoint, if there are fallback endpoints;
+    /// and each endpoint is tried once at least.
+    ///
+    /// JSON-RPC errors are never retried.
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Aborts a request if it isn't finished in the duration.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
     pub fn post<PARAM, RET>(&self, method: &str, params: PARAM) -> BtcRpcResult<RET>
     where
         PARAM: serde::ser::Serialize,
         RET: serde::de::DeserializeOwned,
     {
         let params = serde_json::to_value(params)?;
+        self.with_retries(method, |endpoint| {
+            let (_, req_json) = self.build_request(method, params.clone());
+            let output: Output = self.send(endpoint, &req_json)?;
+            parse_output(output)
+        })
+    }
+
+    /// Sends the requests of the same method in one JSON-RPC batch request.
+    ///
+    /// The results are in the same order as the parameters, each of them
+    /// could fail independently.
+    pub fn post_batch<PARAM, RET>(
+        &self,
+        method: &str,
+        params_list: &[PARAM],
+    ) -> BtcRpcResult<Vec<BtcRpcResult<RET>>>
+    where
+        PARAM: serde::ser::Serialize,
+        RET: serde::de::DeserializeOwned,
+    {
+        let params_list = params_list
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<::std::result::Result<Vec<_>, _>>()?;
+        if params_list.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.with_retries(method, |endpoint| {
+            let (ids, reqs_json): (Vec<_>, Vec<_>) = params_list
+                .iter()
+                .map(|params| self.build_request(method, params.clone()))
+                .unzip();
+            let outputs: Vec<Output> = self.send(endpoint, &reqs_json)?;
+            // The responses of a batch request could be in any order.
+            let mut outputs = outputs
+                .into_iter()
+                .filter_map(|output| match output.id {
+                    RpcId::Num(id) => Some((id, output)),
+                    _ => None,
+                })
+                .collect::<HashMap<_, _>>();
+            let results = ids
+                .into_iter()
+                .map(|id| {
+                    outputs.remove(&id).map(parse_output).unwrap_or_else(|| {
+                        let error = RpcError {
+                            code: RpcErrorCode::InternalError,
+                            message: format!("the response of request (id: {id}) is missing"),
+                            data: None,
+                        };
+                        Err(error.into())
+                    })
+                })
+                .collect();
+            Ok(results)
+        })
+    }
+
+    fn with_retries<T, F>(&self, method: &str, mut call: F) -> BtcRpcResult<T>
+    where
+        F: FnMut(&Endpoint) -> BtcRpcResult<T>,
+    {
+        let fallbacks_count = (self.endpoints.len() - 1) as u32;
+        let max_retries = self.max_retries.max(fallbacks_count);
+        let mut retried = 0;
+        loop {
+            let index = self.current.load(Ordering::Relaxed) % self.endpoints.len();
+            let endpoint = &self.endpoints[index];
+            match call(endpoint) {
+                Err(BtcRpcError::Http(ref err))
+                    if retried < max_retries && is_transient_http_error(err) =>
+                {
+                    let delay = RETRY_BASE_DELAY_MILLIS * 2u64.pow(retried.min(10));
+                    retried += 1;
+                    log::warn!(
+                        "failed to call \"{method}\" of {} since {err}, \
+                        retry after {delay} ms ({retried}/{max_retries})",
+                        endpoint.url
+                    );
+                    self.switch_endpoint(index);
+                    thread::sleep(Duration::from_millis(delay));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Switches to the next endpoint, unless it's already switched by others.
+    fn switch_endpoint(&self, failed_index: usize) {
+        if self.endpoints.len() < 2 {
+            return;
+        }
+        let next_index = (failed_index + 1) % self.endpoints.len();
+        if self
+            .current
+            .compare_exchange(
+                failed_index,
+                next_index,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            log::warn!(
+                "switch the Bitcoin endpoint from {} to {}",
+                self.endpoints[failed_index].url,
+                self.endpoints[next_index].url
+            );
+        }
+    }
+
+    fn build_request(&self, method: &str, params: RpcValue) -> (u64, RpcValue) {
         let id = self.id.fetch_add(1, Ordering::Relaxed);
         log::trace!("params \"{params}\", id: {id}");
 
@@ -102,38 +293,58 @@ impl BitcoinClient {
         req_json.insert("jsonrpc".to_owned(), serde_json::json!("1.0"));
         req_json.insert("method".to_owned(), serde_json::json!(method));
         req_json.insert("params".to_owned(), params);
-        log::trace!("request data \"{:?}\"", serde_json::to_string(&req_json));
+        (id, RpcValue::Object(req_json))
+    }
+
+    fn send<REQ, RESP>(&self, endpoint: &Endpoint, req_json: &REQ) -> BtcRpcResult<RESP>
+    where
+        REQ: serde::ser::Serialize,
+        RESP: serde::de::DeserializeOwned,
+    {
+        log::trace!("request data \"{:?}\"", serde_json::to_string(req_json));
 
         log::trace!(
-            "username \"{:?}\", have-password: {}",
-            self.username,
-            self.password.is_some()
+            "endpoint {}, username \"{:?}\", have-password: {}",
+            endpoint.url,
+            endpoint.username,
+            endpoint.password.is_some()
         );
 
-        let req = self.client.post(self.endpoint.clone());
-        let req = match (&self.username, &self.password) {
+        let mut req = self.client.post(endpoint.url.clone());
+        if let Some(timeout) = self.timeout {
+            req = req.timeout(timeout);
+        }
+        let req = match (&endpoint.username, &endpoint.password) {
             (Some(ref username), password) => req.basic_auth(username, password.clone()),
             (None, Some(ref password)) => req.basic_auth("", Some(password)),
             (None, None) => req,
         }
         .header(reqwest::header::CONTENT_TYPE, "text/plain")
-        .json(&req_json);
+        .json(req_json);
         log::trace!("request: {req:?}");
         let resp = req.send()?;
         log::trace!("response: {resp:?}");
 
-        let output = resp.error_for_status()?.json::<Output>()?;
-        match (output.result, output.error) {
-            (_, Some(error)) => Err(error.into()),
-            (Some(result), None) => serde_json::from_value(result).map_err(Into::into),
-            (None, None) => {
-                let error = RpcError {
-                    code: RpcErrorCode::InternalError,
-                    message: "result is empty withtout errors".to_owned(),
-                    data: None,
-                };
-                Err(error.into())
+        // Bitcoin Core responds JSON-RPC errors with HTTP error status codes,
+        // so parse the body at first, to keep the original JSON-RPC error code.
+        let status_err_opt = resp.error_for_status_ref().err();
+        let output_result = if let Some(limit) = self.max_response_bytes {
+            let mut body = Vec::new();
+            resp.take(limit.saturating_add(1))
+                .read_to_end(&mut body)
+                .map_err(anyhow::Error::from)?;
+            if body.len() as u64 > limit {
+                let msg = format!("the response body is larger than the limit {limit} bytes");
+                return Err(anyhow::Error::msg(msg).into());
             }
+            serde_json::from_slice::<RESP>(&body).map_err(BtcRpcError::from)
+        } else {
+            resp.json::<RESP>().map_err(BtcRpcError::from)
+        };
+        match (output_result, status_err_opt) {
+            (Ok(output), _) => Ok(output),
+            (Err(_), Some(status_err)) => Err(status_err.into()),
+            (Err(err), None) => Err(err),
         }
     }
 
@@ -142,10 +353,15 @@ impl BitcoinClient {
         self.post("getbestblockhash", params)
     }
 
+    fn get_blockchain_info(&self) -> BtcRpcResult<BlockchainInfo> {
+        let params = serialize_parameters!();
+        self.post("getblockchaininfo", params)
+    }
+
     pub fn get_tip_height(&self) -> BtcRpcResult<u32> {
         // Two way to get the tip height:
         // - getblockcount
-        // - getbestblockhash -> getblockstats(height)
+        // - getbestblockhash -> getblockheader(hash, verbose)
         self.get_best_block_hash()
             .and_then(|hash| self.get_block_height(hash))
     }
@@ -162,40 +378,21 @@ impl BitcoinClient {
         self.post("getblockhash", params)
     }
 
+    // "getblockstats" is not available for pruned blocks, so use "getblockheader".
     pub fn get_block_height(&self, hash: BlockHash) -> BtcRpcResult<u32> {
-        let stats = &["height"];
-        let params = serialize_parameters!(hash, stats);
-        let height: BlockHeight = self.post("getblockstats", params)?;
-        Ok(height.height)
+        let params = serialize_parameters!(hash, true);
+        let header: BlockHeaderVerbose = self.post("getblockheader", params)?;
+        Ok(header.height)
     }
 
     pub fn get_raw_block_header(&self, hash: BlockHash) -> BtcRpcResult<Vec<u8>> {
         let params = serialize_parameters!(hash, false);
-        self.post("getblockheader", params).and_then(|hex: String| {
-            let mut bin = vec![0; hex.len() / 2];
-            hex_decode(hex.as_bytes(), &mut bin).map_err(|err| {
-                let error = RpcError {
-                    code: RpcErrorCode::ParseError,
-                    message: format!("failed to decode the hex string \"{hex}\" since {err}"),
-                    data: None,
-                };
-                <RpcError as Into<BtcRpcError>>::into(error)
-            })?;
-            Ok(bin)
-        })
+        self.post("getblockheader", params).and_then(decode_hex)
     }
 
     pub fn get_block_header(&self, hash: BlockHash) -> BtcRpcResult<Header> {
-        self.get_raw_block_header(hash).and_then(|bin| {
-            deserialize(&bin).map_err(|err| {
-                let error = RpcError {
-                    code: RpcErrorCode::ParseError,
-                    message: format!("failed to deserialize header from hex string since {err}"),
-                    data: None,
-                };
-                error.into()
-            })
-        })
+        self.get_raw_block_header(hash)
+            .and_then(|bin| deserialize_header(&bin))
     }
 
     pub fn get_block_header_by_height(&self, height: u32) -> BtcRpcResult<Header> {
@@ -203,9 +400,19 @@ impl BitcoinClient {
             .and_then(|hash| self.get_block_header(hash))
     }
 
-    pub fn get_raw_tx_out_proof(&self, txid: Txid) -> BtcRpcResult<Vec<u8>> {
+    /// Gets the proof of a transaction; if a block hash is provided, only looks
+    /// for the transaction in that block.
+    pub fn get_raw_tx_out_proof(
+        &self,
+        txid: Txid,
+        block_hash_opt: Option<BlockHash>,
+    ) -> BtcRpcResult<Vec<u8>> {
         let txids = vec![txid];
-        let params = serialize_parameters!(txids);
+        let params = if let Some(block_hash) = block_hash_opt {
+            serialize_parameters!(txids, block_hash)
+        } else {
+            serialize_parameters!(txids)
+        };
         self.post("gettxoutproof", params).and_then(|hex: String| {
             let mut bin = vec![0; hex.len() / 2];
             hex_decode(hex.as_bytes(), &mut bin).map_err(|err| {
@@ -220,26 +427,66 @@ impl BitcoinClient {
         })
     }
 
-    pub fn get_tx_out_proof(&self, txid: Txid) -> BtcRpcResult<(MerkleBlock, Vec<u8>)> {
-        self.get_raw_tx_out_proof(txid).and_then(|bin| {
-            deserialize(&bin)
-                .map_err(|err| {
+    pub fn get_tx_out_proof(
+        &self,
+        txid: Txid,
+        block_hash_opt: Option<BlockHash>,
+    ) -> BtcRpcResult<(MerkleBlock, Vec<u8>)> {
+        self.get_raw_tx_out_proof(txid, block_hash_opt)
+            .and_then(|bin| {
+                deserialize(&bin)
+                    .map_err(|err| {
+                        let error = RpcError {
+                            code: RpcErrorCode::ParseError,
+                            message: format!(
+                                "failed to deserialize tx out proof from hex string since {err}"
+                            ),
+                            data: None,
+                        };
+                        error.into()
+                    })
+                    .map(|mb| (mb, bin))
+            })
+    }
+
+    pub fn get_raw_transaction(&self, txid: Txid) -> BtcRpcResult<Transaction> {
+        let params = serialize_parameters!(txid, false);
+        self.post("getrawtransaction", params)
+            .and_then(|hex: String| {
+                let mut bin = vec![0; hex.len() / 2];
+                hex_decode(hex.as_bytes(), &mut bin).map_err(|err| {
+                    let error = RpcError {
+                        code: RpcErrorCode::ParseError,
+                        message: format!("failed to decode the hex string \"{hex}\" since {err}"),
+                        data: None,
+                    };
+                    <RpcError as Into<BtcRpcError>>::into(error)
+                })?;
+                deserialize(&bin).map_err(|err| {
                     let error = RpcError {
                         code: RpcErrorCode::ParseError,
                         message: format!(
-                            "failed to deserialize tx out proof from hex string since {err}"
+                            "failed to deserialize transaction from hex string since {err}"
                         ),
                         data: None,
                     };
                     error.into()
                 })
-                .map(|mb| (mb, bin))
-        })
+            })
     }
 }
 
 /// Implement combined methods.
 impl BitcoinClient {
+    /// Gets the network which the Bitcoin node runs on.
+    pub fn get_network(&self) -> Result<Network> {
+        let info = self.get_blockchain_info()?;
+        Network::from_core_arg(&info.chain).map_err(|err| {
+            let msg = format!("failed to parse the chain of the Bitcoin node since {err}");
+            Error::other(msg)
+        })
+    }
+
     pub fn check_then_fetch_header(&self, height: u32) -> Result<Header> {
         let tip_height = self.get_tip_height()?;
         log::debug!("The height of the best bitcoin block is {tip_height}");
@@ -255,32 +502,142 @@ impl BitcoinClient {
         Ok(header)
     }
 
+    /// Downloads the headers from `start` to `end`, base on the header `start_hash`
+    /// which is at height `start - 1`.
+    ///
+    /// Returns `None` if the downloaded headers are not continuous, which happens
+    /// when a reorg occurs during the download, the caller should retry later.
     pub fn get_headers(
         &self,
         start: u32,
         end: u32,
-        mut expected_prev_hash: BlockHash,
+        start_hash: BlockHash,
     ) -> Result<Option<Vec<Header>>> {
-        log::info!("Download headers from {start} to {end} base on {expected_prev_hash:#x}");
-        let mut headers = Vec::new();
-        for height in start..=end {
-            let header = self.get_block_header_by_height(height)?;
+        log::debug!("Download headers from {start} to {end} base on {start_hash:#x}");
+        let headers = self.download_headers(start, end)?;
+        Ok(Self::check_headers_continuity(
+            start, end, start_hash, headers,
+        ))
+    }
+
+    /// Downloads the headers from `start` to `end`, without checking whether
+    /// they are based on any header.
+    ///
+    /// Each header is checked against its block hash at the same height, but
+    /// the continuity should be checked by the caller, with
+    /// [`check_headers_continuity`](Self::check_headers_continuity).
+    pub fn download_headers(&self, start: u32, end: u32) -> Result<Vec<Header>> {
+        log::info!("Download headers from {start} to {end}");
+        // Download the hashes in one batch, then the headers in another batch.
+        let hashes_params = (start..=end).map(|height| (height,)).collect::<Vec<_>>();
+        let hashes = self
+            .post_batch::<_, BlockHash>("getblockhash", &hashes_params)?
+            .into_iter()
+            .collect::<BtcRpcResult<Vec<_>>>()?;
+        let headers_params = hashes.iter().map(|hash| (*hash, false)).collect::<Vec<_>>();
+        let downloaded_headers = self
+            .post_batch::<_, String>("getblockheader", &headers_params)?
+            .into_iter()
+            .map(|result| {
+                result
+                    .and_then(decode_hex)
+                    .and_then(|bin| deserialize_header(&bin))
+            })
+            .collect::<BtcRpcResult<Vec<_>>>()?;
+
+        let mut headers = Vec::with_capacity(downloaded_headers.len());
+        for ((height, header), expected_hash) in (start..=end).zip(downloaded_headers).zip(hashes) {
             let block_hash = header.block_hash();
             log::trace!(
                 "[download] header#{height:07}, {block_hash:#x}; tip; prev {}",
                 header.prev_blockhash
             );
+            if block_hash != expected_hash {
+                let msg = format!(
+                    "[download] header#{height} is unmatched, \
+                    expect {expected_hash:#x} but got {block_hash:#x}"
+                );
+                return Err(Error::other(msg));
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    /// Checks the downloaded headers from `start` to `end` are continuous, and
+    /// the first one follows the header `start_hash` which is at height `start - 1`.
+    ///
+    /// Returns `None` if they are not continuous, which happens when a reorg
+    /// occurs during the download.
+    pub fn check_headers_continuity(
+        start: u32,
+        end: u32,
+        start_hash: BlockHash,
+        headers: Vec<Header>,
+    ) -> Option<Vec<Header>> {
+        let mut expected_prev_hash = start_hash;
+        for (height, header) in (start..=end).zip(headers.iter()) {
             if header.prev_blockhash != expected_prev_hash {
                 log::warn!(
-                    "[download] reorg at {height} when download headers from {start} to {end}\
-                    expect {expected_prev_hash:#x} but got {:#x}",
+                    "[download] reorg at {height} when download headers from {start} to {end}, \
+                    expect previous hash {expected_prev_hash:#x} but got {:#x}",
                     header.prev_blockhash
                 );
-                return Ok(None);
+                return None;
             }
-            expected_prev_hash = block_hash;
-            headers.push(header);
+            expected_prev_hash = header.block_hash();
+        }
+        Some(headers)
+    }
+}
+
+fn is_transient_http_error(err: &reqwest::Error) -> bool {
+    err.is_timeout()
+        || err.is_connect()
+        || err
+            .status()
+            .map(|status| status.is_server_error())
+            .unwrap_or(false)
+}
+
+fn parse_output<RET>(output: Output) -> BtcRpcResult<RET>
+where
+    RET: serde::de::DeserializeOwned,
+{
+    match (output.result, output.error) {
+        (_, Some(error)) => Err(error.into()),
+        (Some(result), None) => serde_json::from_value(result).map_err(Into::into),
+        (None, None) => {
+            let error = RpcError {
+                code: RpcErrorCode::InternalError,
+                message: "result is empty withtout errors".to_owned(),
+                data: None,
+            };
+            Err(error.into())
         }
-        Ok(Some(headers))
     }
 }
+
+fn decode_hex(hex: String) -> BtcRpcResult<Vec<u8>> {
+    let mut bin = vec![0; hex.len() / 2];
+    hex_decode(hex.as_bytes(), &mut bin).map_err(|err| {
+        let error = RpcError {
+            code: RpcErrorCode::ParseError,
+            message: format!("failed to decode the hex string \"{hex}\" since {err}"),
+            data: None,
+        };
+        <RpcError as Into<BtcRpcError>>::into(error)
+    })?;
+    Ok(bin)
+}
+
+fn deserialize_header(bin: &[u8]) -> BtcRpcResult<Header> {
+    deserialize(bin).map_err(|err| {
+        let error = RpcError {
+            code: RpcErrorCode::ParseError,
+            message: format!("failed to deserialize header from hex string since {err}"),
+            data: None,
+        };
+        error.into()
+    })
+}
@@ -1,6 +1,6 @@
 //! Expand the functionality of the original CKB RPC client.
 
-use std::{collections::HashMap, fmt};
+use std::{collections::HashMap, fmt, thread, time::Duration};
 
 use ckb_bitcoin_spv_verifier::types::{
     core::{SpvClient, SpvInfo},
@@ -23,6 +23,9 @@ use ckb_types::{
 
 use crate::result::{Error, Result};
 
+// The indexer usually catches up with a new CKB block in a short while.
+const SPV_CELLS_SCAN_RETRY_INTERVAL_MILLIS: u64 = 500;
+
 #[derive(Clone)]
 pub struct SpvInfoCell {
     pub(crate) info: SpvInfo,
@@ -83,13 +86,40 @@ impl SpvInfoCell {
 pub trait CkbRpcClientExtension {
     fn dynamic_fee_rate(&self) -> Result<u64>;
     fn send_transaction_ext(&self, tx_json: TransactionView, dry_run: bool) -> Result<H256>;
-    fn find_raw_spv_cells(&self, spv_type_script: Script) -> Result<Vec<LiveCell>>;
+    /// Finds all SPV cells in the provided order of the indexer.
+    fn find_raw_spv_cells(&self, spv_type_script: Script, order: Order) -> Result<Vec<LiveCell>>;
+    fn get_capacity_of_lock(&self, lock_script: Script) -> Result<u64>;
 
     fn find_spv_cells(&self, spv_type_script: Script) -> Result<SpvInstance> {
-        let cells = self.find_raw_spv_cells(spv_type_script)?;
+        // The newest cells come first, so pages are deterministic if the cells are paginated.
+        let cells = self.find_raw_spv_cells(spv_type_script, Order::Desc)?;
         parse_raw_spv_cells(cells)
     }
 
+    /// Finds all SPV cells, retries several times when the count of cells is incorrect,
+    /// which happens momentarily when the indexer sees an update halfway.
+    fn find_spv_cells_with_retries(
+        &self,
+        spv_type_script: Script,
+        retries: u32,
+    ) -> Result<SpvInstance> {
+        let mut remaining = retries;
+        loop {
+            match self.find_spv_cells(spv_type_script.clone()) {
+                Err(Error::SpvCellsCountMismatch { expected, actual }) if remaining > 0 => {
+                    remaining -= 1;
+                    log::warn!(
+                        "the count of SPV cells is incorrect, expect {expected} but got {actual}, \
+                        retry after {SPV_CELLS_SCAN_RETRY_INTERVAL_MILLIS} ms \
+                        ({remaining} retries left)"
+                    );
+                    thread::sleep(Duration::from_millis(SPV_CELLS_SCAN_RETRY_INTERVAL_MILLIS));
+                }
+                result => return result,
+            }
+        }
+    }
+
     fn find_best_spv_client(
         &self,
         spv_type_script: Script,
@@ -145,7 +175,7 @@ impl CkbRpcClientExtension for CkbRpcClient {
         Ok(tx_hash)
     }
 
-    fn find_raw_spv_cells(&self, spv_type_script: Script) -> Result<Vec<LiveCell>> {
+    fn find_raw_spv_cells(&self, spv_type_script: Script, order: Order) -> Result<Vec<LiveCell>> {
         let args_data = spv_type_script.args().raw_data();
         let args = packed::SpvTypeArgsReader::from_slice(&args_data)
             .map_err(|err| {
@@ -157,7 +187,6 @@ impl CkbRpcClientExtension for CkbRpcClient {
         log::trace!("the type script of SPV cell is {spv_type_script}");
 
         let query = CellQueryOptions::new(spv_type_script, PrimaryScriptType::Type);
-        let order = Order::Desc;
         let search_key = SearchKey::from(query);
 
         self.get_cells(search_key, order, u32::MAX.into(), None)
@@ -169,13 +198,20 @@ impl CkbRpcClientExtension for CkbRpcClient {
                 if actual == expected {
                     Ok(cells.into_iter().map(Into::into).collect())
                 } else {
-                    let msg = format!(
-                        "the count of SPV cells is incorrect, expect {expected} but got {actual}"
-                    );
-                    Err(Error::other(msg))
+                    Err(Error::SpvCellsCountMismatch { expected, actual })
                 }
             })
     }
+
+    fn get_capacity_of_lock(&self, lock_script: Script) -> Result<u64> {
+        let query = CellQueryOptions::new(lock_script, PrimaryScriptType::Lock);
+        let search_key = SearchKey::from(query);
+        let capacity = self
+            .get_cells_capacity(search_key)?
+            .map(|cells_capacity| cells_capacity.capacity.value())
+            .unwrap_or(0);
+        Ok(capacity)
+    }
 }
 
 impl SpvInstance {
@@ -0,0 +1,258 @@
+//! Expose the metrics of the service in the Prometheus text format.
+//!
+//! Ref: <https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format>
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use jsonrpc_core::IoHandler;
+use jsonrpc_http_server::{
+    hyper::{header::HeaderValue, Body, Method, Request, StatusCode},
+    RequestMiddleware, RequestMiddlewareAction, Response, Server, ServerBuilder,
+};
+
+use crate::{
+    components::SpvService,
+    result::{Error, Result},
+};
+
+const METRICS_PATH: &str = "/metrics";
+const METRIC_PREFIX: &str = "ckb_bitcoin_spv";
+
+/// The metrics which are collected when the service is running.
+#[derive(Default)]
+pub struct Metrics {
+    onchain_tip_height: Mutex<Option<u32>>,
+    // In shannons, of the address which pays for the SPV updates.
+    ckb_balance: Mutex<Option<u64>>,
+    // Set when the updates are halted, since the local storage is corrupted.
+    updates_halted: AtomicBool,
+    storage_reorgs: AtomicU64,
+    // The count of sent transactions, by the operation.
+    sent_txs: Mutex<BTreeMap<&'static str, u64>>,
+    tx_proof_requests: AtomicU64,
+    // The count of failed requests, by the error code.
+    tx_proof_errors: Mutex<BTreeMap<i64, u64>>,
+}
+
+struct MetricsMiddleware {
+    spv_service: SpvService,
+}
+
+impl Metrics {
+    pub(crate) fn set_onchain_tip_height(&self, height: u32) {
+        *self
+            .onchain_tip_height
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(height);
+    }
+
+    pub(crate) fn set_ckb_balance(&self, balance: u64) {
+        *self
+            .ckb_balance
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(balance);
+    }
+
+    pub(crate) fn set_updates_halted(&self, halted: bool) {
+        self.updates_halted.store(halted, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_storage_reorgs(&self) {
+        self.storage_reorgs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_sent_txs(&self, operation: &'static str) {
+        *self
+            .sent_txs
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(operation)
+            .or_default() += 1;
+    }
+
+    pub(crate) fn inc_tx_proof_requests(&self) {
+        self.tx_proof_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_tx_proof_errors(&self, code: i64) {
+        *self
+            .tx_proof_errors
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(code)
+            .or_default() += 1;
+    }
+
+    fn render(&self, storage_tip_height: Option<u32>) -> String {
+        let mut output = String::new();
+        let onchain_tip_height = *self
+            .onchain_tip_height
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+
+        write_header(
+            &mut output,
+            "storage_tip_height",
+            "gauge",
+            "The height of the tip header in the local storage.",
+        );
+        if let Some(height) = storage_tip_height {
+            write_sample(&mut output, "storage_tip_height", "", height);
+        }
+        write_header(
+            &mut output,
+            "onchain_tip_height",
+            "gauge",
+            "The height of the tip SPV client on chain, which was checked at last.",
+        );
+        if let Some(height) = onchain_tip_height {
+            write_sample(&mut output, "onchain_tip_height", "", height);
+        }
+        write_header(
+            &mut output,
+            "onchain_lag",
+            "gauge",
+            "How many headers the tip SPV client on chain is behind the local storage.",
+        );
+        if let Some((stg_height, spv_height)) = storage_tip_height.zip(onchain_tip_height) {
+            let lag = stg_height.saturating_sub(spv_height);
+            write_sample(&mut output, "onchain_lag", "", lag);
+        }
+        write_header(
+            &mut output,
+            "ckb_balance_shannons",
+            "gauge",
+            "The total capacity of the address which pays for the SPV updates, in shannons.",
+        );
+        let ckb_balance = *self
+            .ckb_balance
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if let Some(balance) = ckb_balance {
+            write_sample(&mut output, "ckb_balance_shannons", "", balance);
+        }
+
+        write_header(
+            &mut output,
+            "updates_halted",
+            "gauge",
+            "Whether the updates of the SPV instance are halted, since the local storage is corrupted.",
+        );
+        let updates_halted = self.updates_halted.load(Ordering::Relaxed);
+        write_sample(&mut output, "updates_halted", "", u8::from(updates_halted));
+
+        write_header(
+            &mut output,
+            "storage_reorgs_total",
+            "counter",
+            "The count of reorgs which were handled by rolling back the local storage.",
+        );
+        let storage_reorgs = self.storage_reorgs.load(Ordering::Relaxed);
+        write_sample(&mut output, "storage_reorgs_total", "", storage_reorgs);
+        write_header(
+            &mut output,
+            "sent_transactions_total",
+            "counter",
+            "The count of sent CKB transactions which update SPV cells, by the operation.",
+        );
+        for (operation, count) in self
+            .sent_txs
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+        {
+            let labels = format!("{{operation=\"{operation}\"}}");
+            write_sample(&mut output, "sent_transactions_total", &labels, count);
+        }
+
+        write_header(
+            &mut output,
+            "tx_proof_requests_total",
+            "counter",
+            "The count of requests for transaction proofs.",
+        );
+        let tx_proof_requests = self.tx_proof_requests.load(Ordering::Relaxed);
+        write_sample(
+            &mut output,
+            "tx_proof_requests_total",
+            "",
+            tx_proof_requests,
+        );
+        write_header(
+            &mut output,
+            "tx_proof_errors_total",
+            "counter",
+            "The count of failed requests for transaction proofs, by the error code.",
+        );
+        for (code, count) in self
+            .tx_proof_errors
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+        {
+            let labels = format!("{{code=\"{code}\"}}");
+            write_sample(&mut output, "tx_proof_errors_total", &labels, count);
+        }
+        output
+    }
+}
+
+impl RequestMiddleware for MetricsMiddleware {
+    fn on_request(&self, request: Request<Body>) -> RequestMiddlewareAction {
+        if *request.method() != Method::GET || request.uri().path() != METRICS_PATH {
+            return Response {
+                code: StatusCode::NOT_FOUND,
+                content_type: HeaderValue::from_static("text/plain; charset=utf-8"),
+                content: "Not Found\n".to_owned(),
+            }
+            .into();
+        }
+        let storage_tip_height = match self.spv_service.storage.tip_state() {
+            Ok((height, _)) => Some(height),
+            Err(err) => {
+                log::warn!("[metrics] failed to read the storage tip since {err}");
+                None
+            }
+        };
+        Response {
+            code: StatusCode::OK,
+            content_type: HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+            content: self.spv_service.metrics.render(storage_tip_height),
+        }
+        .into()
+    }
+}
+
+/// Starts a server which serves the metrics at the path "/metrics".
+pub fn start_metrics_server(address: &SocketAddr, spv_service: SpvService) -> Result<Server> {
+    let middleware = MetricsMiddleware { spv_service };
+    let server = ServerBuilder::new(IoHandler::new())
+        .request_middleware(middleware)
+        .start_http(address)
+        .map_err(|err| {
+            let msg = format!("failed to listen on {address} for metrics since {err}");
+            Error::other(msg)
+        })?;
+    log::info!(
+        "The metrics are served on http://{}{METRICS_PATH}",
+        server.address()
+    );
+    Ok(server)
+}
+
+fn write_header(output: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {METRIC_PREFIX}_{name} {help}");
+    let _ = writeln!(output, "# TYPE {METRIC_PREFIX}_{name} {kind}");
+}
+
+fn write_sample<T: fmt::Display>(output: &mut String, name: &str, labels: &str, value: T) {
+    let _ = writeln!(output, "{METRIC_PREFIX}_{name}{labels} {value}");
+}
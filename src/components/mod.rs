@@ -2,13 +2,23 @@
 
 mod bitcoin_client;
 mod ckb_client;
+mod peer_client;
+mod signer;
 pub(crate) mod storage;
 
 mod api_service;
+mod metrics;
 mod spv_service;
+mod zmq;
 
 pub use api_service::ApiServiceConfig;
 pub use bitcoin_client::BitcoinClient;
 pub use ckb_client::{CkbRpcClientExtension, SpvClientCell, SpvInfoCell, SpvInstance};
-pub use spv_service::{SpvOperation, SpvReorgInput, SpvService, SpvUpdateInput};
+pub use metrics::{start_metrics_server, Metrics};
+pub use peer_client::PeerClient;
+pub use signer::{LocalSigner, RemoteSigner, Signer};
+pub use spv_service::{
+    SpvHealth, SpvOperation, SpvReorgInput, SpvService, SpvUpdateInput, SyncOptions, SyncStatus,
+};
 pub use storage::{Error as StorageError, Storage};
+pub use zmq::BlockNotifier;
@@ -0,0 +1,134 @@
+//! A client to download data from another running instance of this service.
+
+use std::collections::HashMap;
+
+use bitcoin::consensus::deserialize;
+use ckb_bitcoin_spv_verifier::types::core::Header;
+use ckb_jsonrpc_types::JsonBytes;
+use jsonrpc_core::{
+    Call as RpcCall, ErrorCode as RpcErrorCode, Id as RpcId, MethodCall as RpcMethodCall,
+    Output as RpcOutput, Params as RpcParams, Value as RpcValue, Version as RpcVersion,
+};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    components::api_service::ApiErrorCode,
+    result::{Error, Result},
+};
+
+pub struct PeerClient {
+    client: Client,
+    endpoint: Url,
+}
+
+#[derive(Deserialize)]
+struct PeerSyncState {
+    tip_height: u32,
+}
+
+#[derive(Deserialize)]
+struct PeerHeader {
+    raw: JsonBytes,
+}
+
+impl PeerClient {
+    pub fn new(endpoint: Url) -> Self {
+        Self {
+            client: Client::new(),
+            endpoint,
+        }
+    }
+
+    /// Returns the height of the best header in the storage of the peer.
+    pub fn get_tip_height(&self) -> Result<u32> {
+        let mut results = self.post_batch("getSyncState", vec![RpcParams::None])?;
+        let state: PeerSyncState = serde_json::from_value(results.remove(0)).map_err(|err| {
+            let msg = format!("failed to parse the sync state of the peer since {err}");
+            Error::other(msg)
+        })?;
+        Ok(state.tip_height)
+    }
+
+    /// Downloads the headers in the range, in one JSON-RPC batch request.
+    ///
+    /// The headers are not verified, they should be verified before being used.
+    pub fn get_headers(&self, start: u32, end: u32) -> Result<Vec<Header>> {
+        let params_list = (start..=end)
+            .map(|height| RpcParams::Array(vec![RpcValue::from(height)]))
+            .collect();
+        let results = self.post_batch("getHeaderByHeight", params_list)?;
+        (start..=end)
+            .zip(results)
+            .map(|(height, result)| {
+                let header: PeerHeader = serde_json::from_value(result).map_err(|err| {
+                    let msg = format!("failed to parse header#{height} of the peer since {err}");
+                    Error::other(msg)
+                })?;
+                deserialize(header.raw.as_bytes()).map_err(|err| {
+                    let msg = format!("failed to deserialize header#{height} since {err}");
+                    Error::other(msg)
+                })
+            })
+            .collect()
+    }
+
+    // Returns the results in the same order as the parameters.
+    fn post_batch(&self, method: &str, params_list: Vec<RpcParams>) -> Result<Vec<RpcValue>> {
+        let count = params_list.len() as u64;
+        let calls = params_list
+            .into_iter()
+            .enumerate()
+            .map(|(id, params)| {
+                RpcCall::MethodCall(RpcMethodCall {
+                    jsonrpc: Some(RpcVersion::V2),
+                    method: method.to_owned(),
+                    params,
+                    id: RpcId::Num(id as u64),
+                })
+            })
+            .collect::<Vec<_>>();
+        let outputs: Vec<RpcOutput> = self
+            .client
+            .post(self.endpoint.clone())
+            .json(&calls)
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .and_then(|resp| resp.json())
+            .map_err(|err| {
+                let msg = format!("failed to call \"{method}\" of the peer since {err}");
+                Error::other(msg)
+            })?;
+        // The responses of a batch request could be in any order.
+        let mut outputs = outputs
+            .into_iter()
+            .filter_map(|output| match output.id() {
+                RpcId::Num(id) => Some((*id, output)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        (0..count)
+            .map(|id| {
+                let output = outputs.remove(&id).ok_or_else(|| {
+                    let msg = format!("the peer didn't respond the request (id: {id})");
+                    Error::other(msg)
+                })?;
+                jsonrpc_core::Result::<RpcValue>::from(output).map_err(|err| {
+                    let pruned =
+                        RpcErrorCode::ServerError(ApiErrorCode::StorageHeaderPruned as i64);
+                    let msg = if err.code == pruned {
+                        format!(
+                            "the storage of the peer is pruned, it can't be used as \
+                            a bootstrap peer since {}",
+                            err.message
+                        )
+                    } else {
+                        format!("the peer responded an error for \"{method}\": {err}")
+                    };
+                    Error::other(msg)
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,237 @@
+//! Sign the transactions which are sent by the service.
+//!
+//! Only the sighash-all lock is supported, the signature could be created by
+//! a local private key, or by an external signer through HTTP.
+
+use ckb_hash::{blake2b_256, new_blake2b};
+use ckb_jsonrpc_types::JsonBytes;
+use ckb_sdk::{
+    constants::SIGHASH_TYPE_HASH,
+    types::{Address, TransactionWithScriptGroups},
+    SECP256K1,
+};
+use ckb_types::{
+    bytes::Bytes,
+    core::{ScriptHashType, TransactionView},
+    packed::{Script, WitnessArgs},
+    prelude::*,
+};
+use reqwest::blocking::Client;
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    Message, SecretKey,
+};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::result::{Error, Result};
+
+const SIGNATURE_SIZE: usize = 65;
+
+/// Signs the sighash-all messages of transactions.
+pub trait Signer {
+    /// Returns a recoverable signature of the message, the last byte is the recovery ID.
+    fn sign(&self, message: &[u8; 32]) -> Result<[u8; SIGNATURE_SIZE]>;
+
+    /// Signs all inputs which are locked by the lock script, with the sighash-all mode.
+    fn sign_transaction(
+        &self,
+        tx_with_groups: &mut TransactionWithScriptGroups,
+        lock_script: &Script,
+    ) -> Result<()> {
+        let mut tx_view = tx_with_groups.get_tx_view().clone();
+        for script_group in tx_with_groups.get_script_groups() {
+            if script_group.input_indices.is_empty() || &script_group.script != lock_script {
+                continue;
+            }
+            let (index, witness_args, message) =
+                sighash_all_message(&tx_view, &script_group.input_indices)?;
+            let signature = self.sign(&message)?;
+            check_signature(&message, &signature, lock_script)?;
+            let witness_args = witness_args
+                .as_builder()
+                .lock(Some(Bytes::from(signature.to_vec())).pack())
+                .build();
+            let mut witnesses = tx_view.witnesses().into_iter().collect::<Vec<_>>();
+            witnesses[index] = witness_args.as_bytes().pack();
+            tx_view = tx_view
+                .as_advanced_builder()
+                .set_witnesses(witnesses)
+                .build();
+        }
+        tx_with_groups.set_tx_view(tx_view);
+        Ok(())
+    }
+}
+
+/// Signs with a secp256k1 private key, which is held by the service.
+pub struct LocalSigner {
+    secret_key: SecretKey,
+}
+
+/// Sends the messages to an external signer, which holds the private key.
+///
+/// The external signer receives a POST request with a JSON body, such as
+/// `{"address": "ckt1...", "message": "0x<32 bytes>"}`, and should respond
+/// `{"signature": "0x<65 bytes>"}`, which is a recoverable signature.
+pub struct RemoteSigner {
+    client: Client,
+    endpoint: Url,
+    address: Address,
+}
+
+#[derive(Serialize)]
+struct SignRequest {
+    address: String,
+    message: JsonBytes,
+}
+
+#[derive(Deserialize)]
+struct SignResponse {
+    signature: JsonBytes,
+}
+
+impl LocalSigner {
+    pub fn new(secret_key: SecretKey) -> Self {
+        Self { secret_key }
+    }
+}
+
+impl Signer for LocalSigner {
+    fn sign(&self, message: &[u8; 32]) -> Result<[u8; SIGNATURE_SIZE]> {
+        let message = Message::from_slice(message)?;
+        let (recovery_id, compact) = SECP256K1
+            .sign_ecdsa_recoverable(&message, &self.secret_key)
+            .serialize_compact();
+        let mut signature = [0u8; SIGNATURE_SIZE];
+        signature[..64].copy_from_slice(&compact);
+        signature[64] = recovery_id.to_i32() as u8;
+        Ok(signature)
+    }
+}
+
+impl RemoteSigner {
+    /// Creates a remote signer for the address, whose lock should be a sighash-all lock.
+    pub fn new(endpoint: Url, address: Address) -> Result<Self> {
+        let lock_script: Script = (&address).into();
+        let expected = Script::new_builder()
+            .code_hash(SIGHASH_TYPE_HASH.pack())
+            .hash_type(ScriptHashType::Type.into())
+            .args(lock_script.args())
+            .build();
+        if lock_script != expected || lock_script.args().raw_data().len() != 20 {
+            let msg = format!("the lock of the address {address} is not a sighash-all lock");
+            return Err(Error::cli(msg));
+        }
+        Ok(Self {
+            client: Client::new(),
+            endpoint,
+            address,
+        })
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn sign(&self, message: &[u8; 32]) -> Result<[u8; SIGNATURE_SIZE]> {
+        let request = SignRequest {
+            address: self.address.to_string(),
+            message: JsonBytes::from_vec(message.to_vec()),
+        };
+        let response: SignResponse = self
+            .client
+            .post(self.endpoint.clone())
+            .json(&request)
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .and_then(|resp| resp.json())
+            .map_err(|err| {
+                let msg = format!("failed to request a signature from the signer since {err}");
+                Error::other(msg)
+            })?;
+        let signature = response.signature.as_bytes();
+        if signature.len() != SIGNATURE_SIZE {
+            let msg = format!(
+                "the signer responded a signature with {} bytes, but {SIGNATURE_SIZE} bytes \
+                are expected",
+                signature.len()
+            );
+            return Err(Error::other(msg));
+        }
+        let mut result = [0u8; SIGNATURE_SIZE];
+        result.copy_from_slice(signature);
+        Ok(result)
+    }
+}
+
+// Returns the index of the witness to put the signature in, the witness args
+// of it, and the message to sign.
+//
+// Ref: <https://github.com/nervosnetwork/ckb-system-scripts/wiki/How-to-sign-transaction>
+fn sighash_all_message(
+    tx_view: &TransactionView,
+    input_indices: &[usize],
+) -> Result<(usize, WitnessArgs, [u8; 32])> {
+    let witnesses = tx_view.witnesses();
+    let index = input_indices[0];
+    let witness_data = witnesses
+        .get(index)
+        .map(|witness| witness.raw_data())
+        .ok_or_else(|| {
+            let msg = format!("the witness of input#{index} is not found");
+            Error::other(msg)
+        })?;
+    let witness_args = if witness_data.is_empty() {
+        WitnessArgs::default()
+    } else {
+        WitnessArgs::from_slice(&witness_data).map_err(|err| {
+            let msg = format!("failed to parse the witness args of input#{index} since {err}");
+            Error::other(msg)
+        })?
+    };
+    let zero_lock = Bytes::from(vec![0u8; SIGNATURE_SIZE]);
+    let placeholder = witness_args
+        .clone()
+        .as_builder()
+        .lock(Some(zero_lock).pack())
+        .build();
+
+    let mut blake2b = new_blake2b();
+    blake2b.update(tx_view.hash().as_slice());
+    let placeholder_data = placeholder.as_bytes();
+    blake2b.update(&(placeholder_data.len() as u64).to_le_bytes());
+    blake2b.update(&placeholder_data);
+    let other_indices = input_indices
+        .iter()
+        .skip(1)
+        .copied()
+        .chain(tx_view.inputs().len()..witnesses.len());
+    for other_index in other_indices {
+        let data = witnesses
+            .get(other_index)
+            .map(|witness| witness.raw_data())
+            .unwrap_or_default();
+        blake2b.update(&(data.len() as u64).to_le_bytes());
+        blake2b.update(&data);
+    }
+    let mut message = [0u8; 32];
+    blake2b.finalize(&mut message);
+    Ok((index, witness_args, message))
+}
+
+// Checks the signature is signed by the owner of the sighash-all lock script.
+fn check_signature(
+    message: &[u8; 32],
+    signature: &[u8; SIGNATURE_SIZE],
+    lock_script: &Script,
+) -> Result<()> {
+    let message = Message::from_slice(message)?;
+    let recovery_id = RecoveryId::from_i32(i32::from(signature[64]))?;
+    let signature = RecoverableSignature::from_compact(&signature[..64], recovery_id)?;
+    let pubkey = SECP256K1.recover_ecdsa(&message, &signature)?;
+    let pubkey_hash = blake2b_256(&pubkey.serialize()[..]);
+    if lock_script.args().raw_data().as_ref() != &pubkey_hash[..20] {
+        let msg = "the signature is not signed by the owner of the lock script";
+        return Err(Error::other(msg));
+    }
+    Ok(())
+}
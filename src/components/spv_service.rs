@@ -1,15 +1,26 @@
 //! Internal SPV service.
 
-use bitcoin::BlockHash;
+use std::{
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bitcoin::{BlockHash, Work};
 use ckb_bitcoin_spv_verifier::types::{
     core::{Hash, Header},
     prelude::{Pack as VPack, Unpack as VUnpack},
 };
 use ckb_sdk::rpc::CkbRpcClient;
-use ckb_types::prelude::*;
+use ckb_types::{packed::Script, prelude::*};
+use serde::Serialize;
 
 use crate::{
-    components::{BitcoinClient, SpvClientCell, SpvInfoCell, SpvInstance, Storage},
+    components::{BitcoinClient, Metrics, SpvClientCell, SpvInfoCell, SpvInstance, Storage},
     prelude::*,
     result::{Error, Result},
 };
@@ -19,27 +30,285 @@ pub struct SpvService {
     pub(crate) ckb_cli: CkbRpcClient,
     pub(crate) btc_cli: BitcoinClient,
     pub(crate) storage: Storage,
+    // Set by the service when a reorg transaction is sent but not committed.
+    pub(crate) reorg_in_progress: Arc<AtomicBool>,
+    // Set by the sync loop when the rollback of the storage is refused,
+    // the storage is still readable but the SPV instance should not be updated.
+    pub(crate) reorg_refused: Arc<AtomicBool>,
+    // Set by the sync loop, the Bitcoin tip height which is fetched last time.
+    pub(crate) bitcoin_tip_height: Arc<Mutex<Option<u32>>>,
+    // Held when headers are appended into the storage, since they could be
+    // appended by both the sync loop and the prefetch of the APIs.
+    pub(crate) sync_lock: Arc<Mutex<()>>,
+    // How many batches of headers are downloaded concurrently when syncing.
+    pub(crate) download_concurrency: NonZeroU32,
+    pub(crate) metrics: Arc<Metrics>,
 }
 
+/// How to sync the local storage with the Bitcoin chain.
+#[derive(Clone, Copy)]
+pub struct SyncOptions {
+    /// How many headers are downloaded in each batch.
+    pub(crate) batch_size: u32,
+    /// How many times a failed batch is retried.
+    pub(crate) batch_retry: u32,
+    /// Ramp up the batch size by this step, if it's provided.
+    pub(crate) resume_batch_size: Option<u32>,
+    /// Only roll back the storage to a chain which has more work.
+    pub(crate) reorg_require_more_work: bool,
+    /// Refuse to roll back the storage deeper than this depth.
+    pub(crate) max_reorg_depth: Option<u32>,
+}
+
+// Sizes the batches of headers to download.
+//
+// If a step is provided, the size ramps up from the step to the max size:
+// additive increase after successes, multiplicative decrease after failures;
+// otherwise, the size is fixed.
+struct BatchSizer {
+    curr: u32,
+    max: u32,
+    step: Option<u32>,
+}
+
+/// The outcome of syncing the local storage with the Bitcoin chain.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SyncStatus {
+    /// The local storage catches up the Bitcoin tip.
+    Synced,
+    /// Some headers are left, try to sync again.
+    Syncing,
+    /// The rollback of the local storage is refused, since the fork point is
+    /// too deep or the chain of the Bitcoin node doesn't have more work.
+    ReorgRefused,
+}
+
+#[derive(Clone)]
 pub struct SpvUpdateInput {
     pub(crate) info: SpvInfoCell,
     pub(crate) curr: SpvClientCell,
     pub(crate) next: SpvClientCell,
 }
 
+#[derive(Clone)]
 pub struct SpvReorgInput {
     pub(crate) info: SpvInfoCell,
     pub(crate) curr: SpvClientCell,
     pub(crate) stale: Vec<SpvClientCell>,
 }
 
+/// The readiness of the service, every check is done independently.
+#[derive(Serialize, Clone)]
+pub struct SpvHealth {
+    pub(crate) storage_tip_height: Option<u32>,
+    pub(crate) bitcoin_tip_height: Option<u32>,
+    pub(crate) ckb_tip_number: Option<u64>,
+    // How many Bitcoin blocks the local storage is behind the Bitcoin tip.
+    pub(crate) sync_lag: Option<u32>,
+    pub(crate) reorg_in_progress: bool,
+    pub(crate) reorg_refused: bool,
+    pub(crate) errors: Vec<String>,
+}
+
 pub enum SpvOperation {
     Update(SpvUpdateInput),
     Reorg(SpvReorgInput),
     Reset(SpvReorgInput),
 }
 
+impl SpvReorgInput {
+    /// Checks that the stale clients form a contiguous segment of the ring,
+    /// which starts from the tip client and goes backward,
+    /// and the current client is right before the last stale client.
+    ///
+    /// When all clients are stale, the current client is the tip client.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let clients_count = self.info.clients_count;
+        let stale_count = self.stale.len();
+        if stale_count == 0 || stale_count > clients_count as usize {
+            let msg = format!(
+                "the count of stale SPV clients ({stale_count}) should be in [1, {clients_count}]"
+            );
+            return Err(Error::other(msg));
+        }
+        let prev_id = |id: u8| if id == 0 { clients_count - 1 } else { id - 1 };
+        for pair in self.stale.windows(2) {
+            let (newer_id, older_id) = (pair[0].client.id, pair[1].client.id);
+            if prev_id(newer_id) != older_id {
+                let msg = format!(
+                    "stale SPV clients are not contiguous, \
+                    client (id={older_id}) should not be right before client (id={newer_id})"
+                );
+                return Err(Error::other(msg));
+            }
+        }
+        let curr_id = self.curr.client.id;
+        let expected_curr_id = if stale_count == clients_count as usize {
+            self.stale[0].client.id
+        } else {
+            prev_id(self.stale[stale_count - 1].client.id)
+        };
+        if curr_id != expected_curr_id || self.info.info.tip_client_id != curr_id {
+            let msg = format!(
+                "the current SPV client (id={curr_id}) is not adjacent to the stale clients, \
+                expect client (id={expected_curr_id}), and the tip client id in info is {}",
+                self.info.info.tip_client_id
+            );
+            return Err(Error::other(msg));
+        }
+        Ok(())
+    }
+
+    /// Takes all clients as stale, from the tip client and goes backward,
+    /// to rebuild the whole SPV instance.
+    pub(crate) fn reset_all(ins: SpvInstance) -> Result<Self> {
+        let SpvInstance { info, clients } = ins;
+        let clients_count = info.clients_count;
+        let mut id = info.info.tip_client_id;
+        let mut stale = Vec::with_capacity(usize::from(clients_count));
+        for _ in 0..clients_count {
+            let cell = clients.get(&id).ok_or_else(|| {
+                let msg = format!("the SPV client (id={id}) is not found");
+                Error::other(msg)
+            })?;
+            stale.push(cell.clone());
+            id = if id == 0 { clients_count - 1 } else { id - 1 };
+        }
+        let curr = stale
+            .first()
+            .cloned()
+            .ok_or_else(|| Error::other("no SPV clients are found"))?;
+        let input = Self { info, curr, stale };
+        input.validate()?;
+        Ok(input)
+    }
+}
+
+impl BatchSizer {
+    fn new(options: &SyncOptions) -> Self {
+        let max = options.batch_size.max(1);
+        let step = options.resume_batch_size.map(|size| size.clamp(1, max));
+        Self {
+            curr: step.unwrap_or(options.batch_size),
+            max,
+            step,
+        }
+    }
+
+    fn size(&self) -> u32 {
+        self.curr
+    }
+
+    fn on_success(&mut self) {
+        if let Some(step) = self.step {
+            self.curr = (self.curr + step).min(self.max);
+        }
+    }
+
+    fn on_failure(&mut self) {
+        if self.step.is_some() {
+            self.curr = (self.curr / 2).max(1);
+        }
+    }
+}
+
+impl SpvHealth {
+    /// The local storage is readable, and it catches up the Bitcoin chain
+    /// within the safe depth.
+    pub(crate) fn is_synced(&self, safe_depth: u32) -> bool {
+        self.sync_lag.map(|lag| lag <= safe_depth).unwrap_or(false)
+    }
+
+    /// All checks passed, the local storage is synced, no reorg is in progress
+    /// and no rollback is refused.
+    pub(crate) fn is_ready(&self, safe_depth: u32) -> bool {
+        self.errors.is_empty()
+            && self.is_synced(safe_depth)
+            && !self.reorg_in_progress
+            && !self.reorg_refused
+    }
+}
+
 impl SpvService {
+    /// Checks the local storage, the Bitcoin node and the CKB node.
+    ///
+    /// Failed checks are collected into the report rather than returned as errors.
+    pub(crate) fn health(&self) -> SpvHealth {
+        let mut errors = Vec::new();
+        let storage_tip_height = self
+            .storage
+            .tip_state()
+            .map(|(height, _)| height)
+            .map_err(|err| errors.push(format!("failed to read the storage tip since {err}")))
+            .ok();
+        let bitcoin_tip_height = self
+            .btc_cli
+            .get_tip_height()
+            .map_err(|err| errors.push(format!("failed to get the Bitcoin tip since {err}")))
+            .ok();
+        let ckb_tip_number = self
+            .ckb_cli
+            .get_tip_block_number()
+            .map(|number| number.value())
+            .map_err(|err| errors.push(format!("failed to get the CKB tip since {err}")))
+            .ok();
+        let sync_lag = storage_tip_height
+            .zip(bitcoin_tip_height)
+            .map(|(stg_tip_height, btc_tip_height)| btc_tip_height.saturating_sub(stg_tip_height));
+        SpvHealth {
+            storage_tip_height,
+            bitcoin_tip_height,
+            ckb_tip_number,
+            sync_lag,
+            reorg_in_progress: self.is_reorg_in_progress(),
+            reorg_refused: self.is_reorg_refused(),
+            errors,
+        }
+    }
+
+    /// Checks the local storage, and the Bitcoin tip which is fetched by the
+    /// sync loop, without any requests to the remote nodes.
+    pub(crate) fn cached_health(&self) -> SpvHealth {
+        let mut errors = Vec::new();
+        let storage_tip_height = self
+            .storage
+            .tip_state()
+            .map(|(height, _)| height)
+            .map_err(|err| errors.push(format!("failed to read the storage tip since {err}")))
+            .ok();
+        let bitcoin_tip_height = *self
+            .bitcoin_tip_height
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if bitcoin_tip_height.is_none() {
+            errors.push("the Bitcoin tip is not fetched by the sync loop yet".to_owned());
+        }
+        let sync_lag = storage_tip_height
+            .zip(bitcoin_tip_height)
+            .map(|(stg_tip_height, btc_tip_height)| btc_tip_height.saturating_sub(stg_tip_height));
+        SpvHealth {
+            storage_tip_height,
+            bitcoin_tip_height,
+            ckb_tip_number: None,
+            sync_lag,
+            reorg_in_progress: self.is_reorg_in_progress(),
+            reorg_refused: self.is_reorg_refused(),
+            errors,
+        }
+    }
+
+    pub(crate) fn is_reorg_in_progress(&self) -> bool {
+        self.reorg_in_progress.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_reorg_in_progress(&self, in_progress: bool) {
+        self.reorg_in_progress.store(in_progress, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_reorg_refused(&self) -> bool {
+        self.reorg_refused.load(Ordering::SeqCst)
+    }
+
     pub(crate) fn select_operation(&self) -> Result<SpvOperation> {
         let spv_type_script = self.storage.spv_contract_type_script()?;
         let ins = self.ckb_cli.find_spv_cells(spv_type_script)?;
@@ -55,6 +324,8 @@ impl SpvService {
             })?
             .to_owned();
         log::info!("[onchain] tip SPV client {}", spv_client_curr.client);
+        self.metrics
+            .set_onchain_tip_height(spv_client_curr.client.headers_mmr_root.max_height);
 
         let spv_header_root_curr = &spv_client_curr.client.headers_mmr_root;
         let spv_height_curr = spv_header_root_curr.max_height;
@@ -66,8 +337,12 @@ impl SpvService {
             let stg_header_root_curr = packed_stg_header_root_curr.unpack();
             log::warn!("[storage] header#{spv_height_curr}; mmr-root {stg_header_root_curr}");
             let input = self.prepare_reorg_input(ins)?;
+            input.validate()?;
             if input.info.clients_count as usize == input.stale.len() {
-                log::warn!("[onchain] all SPV clients are stale, resetting");
+                log::error!(
+                    "[onchain] all SPV clients are stale, resetting; \
+                    if it's not a catastrophic reorg, check the SPV contract and instance"
+                );
                 return Ok(SpvOperation::Reset(input));
             } else {
                 return Ok(SpvOperation::Reorg(input));
@@ -95,6 +370,53 @@ impl SpvService {
         Ok(SpvOperation::Update(input))
     }
 
+    /// Compares the tip SPV client on chain with the local storage,
+    /// and warns if they are diverged.
+    ///
+    /// Returns `false` only if they are diverged.
+    pub(crate) fn compare_with_onchain(&self) -> Result<bool> {
+        let spv_type_script = self.storage.spv_contract_type_script()?;
+        let ins = self.ckb_cli.find_spv_cells(spv_type_script)?;
+        let tip_client_id = ins.info.info.tip_client_id;
+        let spv_client = ins.clients.get(&tip_client_id).ok_or_else(|| {
+            let msg = format!("the current tip SPV client (id={tip_client_id}) is not found");
+            Error::other(msg)
+        })?;
+
+        let spv_header_root = &spv_client.client.headers_mmr_root;
+        let spv_height = spv_header_root.max_height;
+        let (stg_tip_height, _) = self.storage.tip_state()?;
+        if stg_tip_height < spv_height {
+            log::debug!(
+                "[compare] skipped since the storage tip header#{stg_tip_height} \
+                is lower than the SPV tip header#{spv_height}"
+            );
+            return Ok(true);
+        }
+
+        let packed_stg_header_root = self.storage.generate_headers_root(spv_height)?;
+        let packed_spv_header_root = spv_header_root.pack();
+        if packed_stg_header_root.as_slice() != packed_spv_header_root.as_slice() {
+            log::warn!("[compare] the tip SPV client on chain is diverged from the storage");
+            log::warn!("[onchain] header#{spv_height}; mmr-root {spv_header_root}");
+            let stg_header_root = packed_stg_header_root.unpack();
+            log::warn!("[storage] header#{spv_height}; mmr-root {stg_header_root}");
+            Ok(false)
+        } else {
+            log::debug!("[compare] the tip SPV client on chain (header#{spv_height}) is matched");
+            Ok(true)
+        }
+    }
+
+    /// Fetches the total capacity of the live cells which are locked by the
+    /// lock script, and saves it into the storage and the metrics.
+    pub(crate) fn check_ckb_balance(&self, lock_script: Script) -> Result<u64> {
+        let balance = self.ckb_cli.get_capacity_of_lock(lock_script)?;
+        self.storage.save_last_ckb_balance(balance)?;
+        self.metrics.set_ckb_balance(balance);
+        Ok(balance)
+    }
+
     pub(crate) fn prepare_reorg_input(&self, ins: SpvInstance) -> Result<SpvReorgInput> {
         let SpvInstance { mut info, clients } = ins;
         let mut stale = Vec::new();
@@ -145,13 +467,25 @@ impl SpvService {
         Ok(input)
     }
 
-    pub(crate) fn sync_storage(&self, batch_size: u32) -> Result<bool> {
+    pub(crate) fn sync_storage(&self, options: &SyncOptions) -> Result<SyncStatus> {
+        let status = self.sync_storage_once(options)?;
+        self.reorg_refused
+            .store(status == SyncStatus::ReorgRefused, Ordering::SeqCst);
+        Ok(status)
+    }
+
+    fn sync_storage_once(&self, options: &SyncOptions) -> Result<SyncStatus> {
+        let _guard = self.lock_sync()?;
         let spv = &self;
         let (stg_tip_height, stg_tip_header) = spv.storage.tip_state()?;
         let stg_tip_hash = stg_tip_header.block_hash();
         log::info!("[storage] header#{stg_tip_height:07}, {stg_tip_hash:#x}; tip");
 
         let (btc_tip_height, btc_tip_header) = spv.btc_cli.get_tip_state()?;
+        *self
+            .bitcoin_tip_height
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(btc_tip_height);
         log::info!(
             "[bitcoin] header#{btc_tip_height:07}, {:#x}; tip; prev {:#x}",
             btc_tip_header.block_hash(),
@@ -159,19 +493,20 @@ impl SpvService {
         );
 
         if stg_tip_height >= btc_tip_height {
-            return Ok(true);
+            self.mark_synced()?;
+            return Ok(SyncStatus::Synced);
         }
 
         let btc_header = spv.btc_cli.get_block_header_by_height(stg_tip_height)?;
         let btc_hash = btc_header.block_hash();
         if stg_tip_hash == btc_hash {
-            let headers_opt = self.sync_storage_internal(
-                batch_size,
+            let is_synced = self.sync_storage_internal(
+                options,
                 stg_tip_height + 1,
                 btc_tip_height,
                 stg_tip_hash,
             )?;
-            return Ok(headers_opt.is_some());
+            return self.mark_synced_if(is_synced);
         }
 
         log::info!("Try to find the height when fork happened");
@@ -179,6 +514,18 @@ impl SpvService {
         let mut fork_point = None;
 
         for height in (stg_base_height..stg_tip_height).rev() {
+            if let Some(max_depth) = options.max_reorg_depth {
+                if stg_tip_height - height > max_depth {
+                    // Keep the storage unmodified, so it could still be read,
+                    // until an operator intervenes.
+                    log::error!(
+                        "Refuse to roll back the chain in storage since the fork point is \
+                        deeper than {max_depth} blocks below the tip header#{stg_tip_height:07}, \
+                        please check the Bitcoin node"
+                    );
+                    return Ok(SyncStatus::ReorgRefused);
+                }
+            }
             let stg_hash = spv.storage.bitcoin_header_hash(height)?;
             log::debug!("[storage] header#{height:07}, {stg_hash:#x}");
             let btc_header = spv.btc_cli.get_block_header_by_height(height)?;
@@ -187,7 +534,7 @@ impl SpvService {
 
             if stg_hash == btc_hash {
                 log::info!("Fork happened at height {height}");
-                fork_point = Some((height, btc_hash));
+                fork_point = Some((height, btc_header.block_hash()));
                 break;
             }
         }
@@ -201,51 +548,319 @@ impl SpvService {
         }
         let (fork_height, fork_hash) = fork_point.unwrap();
 
+        if options.reorg_require_more_work
+            && !self.has_more_work_than_storage(
+                options,
+                (fork_height, fork_hash),
+                stg_tip_height,
+                btc_tip_height,
+            )?
+        {
+            log::warn!(
+                "Skip the rollback to header#{fork_height:07} since the chain of the Bitcoin node \
+                doesn't have more work than the chain in storage, check it later"
+            );
+            return Ok(SyncStatus::ReorgRefused);
+        }
+
         log::warn!("The chain in storage rollback to header#{fork_height:07}, {fork_hash:#x}");
         spv.storage.rollback_to(Some(fork_height))?;
+        self.metrics.inc_storage_reorgs();
 
-        let headers_opt = self.sync_storage_internal(
-            batch_size,
-            fork_height + 1,
-            btc_tip_height,
-            fork_hash.into(),
+        let is_synced =
+            self.sync_storage_internal(options, fork_height + 1, btc_tip_height, fork_hash)?;
+        self.mark_synced_if(is_synced)
+    }
+
+    /// Compares the cumulative work of the chain in storage and the chain of
+    /// the Bitcoin node, both are above the fork point.
+    ///
+    /// Returns `true` only if the chain of the Bitcoin node has strictly more work.
+    /// The headers of the Bitcoin node are downloaded in batches, and it stops as
+    /// soon as their work is more than the work in storage.
+    fn has_more_work_than_storage(
+        &self,
+        options: &SyncOptions,
+        (fork_height, fork_hash): (u32, BlockHash),
+        stg_tip_height: u32,
+        btc_tip_height: u32,
+    ) -> Result<bool> {
+        let mut stg_work_opt: Option<Work> = None;
+        for height in (fork_height + 1)..=stg_tip_height {
+            let work = self.storage.bitcoin_header(height)?.work();
+            stg_work_opt = Some(stg_work_opt.map_or(work, |total| total + work));
+        }
+        log::info!(
+            "[storage] work above the fork point: {:.2} (log2)",
+            stg_work_opt.map(Work::log2).unwrap_or_default()
+        );
+
+        let batch_size = options.batch_size.max(1);
+        let concurrency = self.download_concurrency.get() as usize;
+        let mut btc_work_opt: Option<Work> = None;
+        let mut start_height = fork_height + 1;
+        let mut start_hash = fork_hash;
+        while start_height <= btc_tip_height {
+            let mut ranges = Vec::with_capacity(concurrency);
+            let mut range_start = start_height;
+            while range_start <= btc_tip_height && ranges.len() < concurrency {
+                let range_end = range_start.saturating_add(batch_size).min(btc_tip_height);
+                ranges.push((range_start, range_end));
+                range_start = range_end + 1;
+            }
+            let results = self.download_batches(&ranges);
+            for ((range_start, range_end), result) in ranges.into_iter().zip(results) {
+                let headers = match BitcoinClient::check_headers_continuity(
+                    range_start,
+                    range_end,
+                    start_hash,
+                    result?,
+                ) {
+                    Some(headers) => headers,
+                    None => {
+                        log::warn!(
+                            "the chain of the Bitcoin node is changing, \
+                            compare the work with the storage later"
+                        );
+                        return Ok(false);
+                    }
+                };
+                for header in &headers {
+                    let work = header.work();
+                    btc_work_opt = Some(btc_work_opt.map_or(work, |total| total + work));
+                    start_hash = header.block_hash();
+                }
+                start_height = range_end + 1;
+                if btc_work_opt > stg_work_opt {
+                    log::info!(
+                        "[bitcoin] work above the fork point: {:.2} (log2) at least, \
+                        up to header#{range_end:07}",
+                        btc_work_opt.map(Work::log2).unwrap_or_default()
+                    );
+                    return Ok(true);
+                }
+            }
+        }
+        log::info!(
+            "[bitcoin] work above the fork point: {:.2} (log2)",
+            btc_work_opt.map(Work::log2).unwrap_or_default()
+        );
+        Ok(false)
+    }
+
+    /// Appends at most `count` headers above the storage tip, without handling reorgs,
+    /// which are left to the sync loop.
+    ///
+    /// Returns the tip height of the storage.
+    pub(crate) fn prefetch_headers(&self, count: u32) -> Result<u32> {
+        let _guard = self.lock_sync()?;
+        let (stg_tip_height, stg_tip_header) = self.storage.tip_state()?;
+        let btc_tip_height = self.btc_cli.get_tip_height()?;
+        if count == 0 || stg_tip_height >= btc_tip_height {
+            return Ok(stg_tip_height);
+        }
+        let end_height = stg_tip_height.saturating_add(count).min(btc_tip_height);
+        let headers_opt = self.btc_cli.get_headers(
+            stg_tip_height + 1,
+            end_height,
+            stg_tip_header.block_hash(),
         )?;
-        Ok(headers_opt.is_some())
+        match headers_opt {
+            Some(headers) if !headers.is_empty() => {
+                let (tip_height, _) = self.storage.append_headers(headers)?;
+                log::debug!("[prefetch] header#{tip_height:07}; tip");
+                Ok(tip_height)
+            }
+            _ => Ok(stg_tip_height),
+        }
+    }
+
+    fn lock_sync(&self) -> Result<MutexGuard<'_, ()>> {
+        self.sync_lock.lock().map_err(|err| {
+            let msg = format!("failed to lock the storage for sync since {err}");
+            Error::other(msg)
+        })
+    }
+
+    fn mark_synced_if(&self, is_synced: bool) -> Result<SyncStatus> {
+        if is_synced {
+            self.mark_synced()?;
+            Ok(SyncStatus::Synced)
+        } else {
+            Ok(SyncStatus::Syncing)
+        }
+    }
+
+    fn mark_synced(&self) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| {
+                let msg = format!("failed to read current timestamp since {err}");
+                Error::other(msg)
+            })?
+            .as_secs();
+        self.storage.save_last_sync_timestamp(timestamp)?;
+        Ok(())
     }
 
     fn sync_storage_internal(
         &self,
-        batch_size: u32,
+        options: &SyncOptions,
         mut start_height: u32,
         end_height: u32,
         mut start_hash: BlockHash,
-    ) -> Result<Option<Vec<Header>>> {
-        let spv = self;
-        let mut headers = Vec::new();
-        while start_height <= end_height {
-            let mut next_height = start_height + batch_size;
-            if next_height > end_height {
-                next_height = end_height;
+    ) -> Result<bool> {
+        let batch_retry = options.batch_retry;
+        let mut batch_sizer = BatchSizer::new(options);
+        let concurrency = self.download_concurrency.get() as usize;
+        let mut retried = 0;
+        // The headers are appended into the storage batch by batch,
+        // don't keep them in memory.
+        'sync: while start_height <= end_height {
+            // Download several non-overlapping batches concurrently, but append
+            // them strictly in order, since the MMR append is position-dependent.
+            let mut ranges = Vec::with_capacity(concurrency);
+            let mut range_start = start_height;
+            while range_start <= end_height && ranges.len() < concurrency {
+                let range_end = range_start
+                    .saturating_add(batch_sizer.size())
+                    .min(end_height);
+                ranges.push((range_start, range_end));
+                range_start = range_end + 1;
             }
+            let results = self.download_batches(&ranges);
+            for ((range_start, range_end), result) in ranges.into_iter().zip(results) {
+                let result = result.and_then(|headers| {
+                    self.append_batch(range_start, range_end, start_hash, headers)
+                });
+                match result {
+                    Ok(Some(last_hash)) => {
+                        retried = 0;
+                        batch_sizer.on_success();
+                        start_height = range_end + 1;
+                        start_hash = last_hash;
+                    }
+                    Ok(None) => return Ok(false),
+                    Err(err) if retried < batch_retry => {
+                        retried += 1;
+                        batch_sizer.on_failure();
+                        log::warn!(
+                            "failed to sync headers from {range_start} to {range_end} \
+                            since {err}, retry it ({retried}/{batch_retry})"
+                        );
+                        // Drop the batches after the failed one, they will be downloaded again.
+                        continue 'sync;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(true)
+    }
 
-            let tmp_headers = if let Some(headers) =
-                spv.btc_cli
-                    .get_headers(start_height, next_height, start_hash)?
-            {
-                headers
-            } else {
-                return Ok(None);
-            };
+    // Download batches of headers, each batch is downloaded in its own thread.
+    fn download_batches(&self, ranges: &[(u32, u32)]) -> Vec<Result<Vec<Header>>> {
+        if let [(start, end)] = ranges {
+            return vec![self.btc_cli.download_headers(*start, *end)];
+        }
+        thread::scope(|scope| {
+            let handles = ranges
+                .iter()
+                .map(|&(start, end)| scope.spawn(move || self.btc_cli.download_headers(start, end)))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle.join().unwrap_or_else(|_| {
+                        Err(Error::other("the thread which downloads headers panicked"))
+                    })
+                })
+                .collect()
+        })
+    }
 
-            start_height = next_height + 1;
-            if let Some(header) = tmp_headers.last() {
-                start_hash = header.block_hash();
-            } else {
-                return Ok(None);
-            }
-            headers.extend_from_slice(&tmp_headers);
-            let _ = spv.storage.append_headers(tmp_headers)?;
+    // Append a batch of downloaded headers into the storage, base on the last good hash.
+    //
+    // Returns the hash of the last appended header, or `None` if the batch
+    // doesn't follow the last good hash.
+    fn append_batch(
+        &self,
+        start_height: u32,
+        end_height: u32,
+        start_hash: BlockHash,
+        headers: Vec<Header>,
+    ) -> Result<Option<BlockHash>> {
+        let headers = match BitcoinClient::check_headers_continuity(
+            start_height,
+            end_height,
+            start_hash,
+            headers,
+        ) {
+            Some(headers) => headers,
+            None => return Ok(None),
+        };
+        let last_hash = match headers.last() {
+            Some(header) => header.block_hash(),
+            None => return Ok(None),
+        };
+        let _ = self.storage.append_headers(headers)?;
+        Ok(Some(last_hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatchSizer, SyncOptions};
+
+    fn new_batch_sizer(batch_size: u32, resume_batch_size: Option<u32>) -> BatchSizer {
+        let options = SyncOptions {
+            batch_size,
+            batch_retry: 3,
+            resume_batch_size,
+            reorg_require_more_work: false,
+            max_reorg_depth: None,
+        };
+        BatchSizer::new(&options)
+    }
+
+    #[test]
+    fn test_batch_sizer_without_step() {
+        let mut sizer = new_batch_sizer(100, None);
+        assert_eq!(sizer.size(), 100);
+        sizer.on_failure();
+        assert_eq!(sizer.size(), 100);
+        sizer.on_success();
+        assert_eq!(sizer.size(), 100);
+    }
+
+    #[test]
+    fn test_batch_sizer_with_step() {
+        let mut sizer = new_batch_sizer(100, Some(30));
+        assert_eq!(sizer.size(), 30);
+        // Additive increase, up to the max size.
+        sizer.on_success();
+        assert_eq!(sizer.size(), 60);
+        sizer.on_success();
+        assert_eq!(sizer.size(), 90);
+        sizer.on_success();
+        assert_eq!(sizer.size(), 100);
+        sizer.on_success();
+        assert_eq!(sizer.size(), 100);
+        // Multiplicative decrease, down to 1.
+        sizer.on_failure();
+        assert_eq!(sizer.size(), 50);
+        for _ in 0..10 {
+            sizer.on_failure();
         }
-        Ok(Some(headers))
+        assert_eq!(sizer.size(), 1);
+        sizer.on_success();
+        assert_eq!(sizer.size(), 31);
+    }
+
+    #[test]
+    fn test_batch_sizer_clamps_step() {
+        assert_eq!(new_batch_sizer(100, Some(500)).size(), 100);
+        assert_eq!(new_batch_sizer(100, Some(0)).size(), 1);
+        assert_eq!(new_batch_sizer(0, Some(10)).size(), 1);
     }
 }
@@ -1,9 +1,112 @@
 //! Memory cache for the storage.
 
-use std::sync::RwLock;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use ckb_bitcoin_spv_verifier::types::core::Header;
 
 #[derive(Default)]
 pub(crate) struct Cache {
     pub(crate) base_bitcoin_height: RwLock<Option<u32>>,
-    // TODO Cache headers by their heights.
+    pub(crate) headers: RwLock<HeaderCache>,
+}
+
+/// Caches the headers at the highest heights, since the recent headers are
+/// read most frequently, e.g., when looking for the fork point of a reorg.
+///
+/// It's disabled when the capacity is 0.
+#[derive(Default)]
+pub(crate) struct HeaderCache {
+    capacity: usize,
+    headers: BTreeMap<u32, Header>,
+    // Increased on each write, so a header which is read from the storage
+    // before a write won't be cached after the write.
+    generation: u64,
+    // Only counted when the cache is enabled.
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl HeaderCache {
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.headers.len() > self.capacity {
+            let _ = self.headers.pop_first();
+        }
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub(crate) fn get(&self, height: u32) -> Option<Header> {
+        let header = self.headers.get(&height).copied();
+        if self.capacity > 0 {
+            let counter = if header.is_some() {
+                &self.hits
+            } else {
+                &self.misses
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        header
+    }
+
+    /// Returns the count of cached headers, the count of hits and the count of misses.
+    pub(crate) fn stats(&self) -> (usize, u64, u64) {
+        (
+            self.headers.len(),
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Drops all cached headers and resets the statistics.
+    pub(crate) fn clear(&mut self) {
+        self.generation += 1;
+        self.headers.clear();
+        *self.hits.get_mut() = 0;
+        *self.misses.get_mut() = 0;
+    }
+
+    /// Caches a header which is read from the storage, unless any header is
+    /// written after it's read.
+    pub(crate) fn insert_if_unchanged(&mut self, height: u32, header: Header, generation: u64) {
+        if self.generation == generation {
+            self.insert(height, header);
+        }
+    }
+
+    /// Updates the cache when a header is written into the storage.
+    pub(crate) fn update(&mut self, height: u32, header: Header) {
+        self.generation += 1;
+        self.insert(height, header);
+    }
+
+    /// Drops the header when it's deleted from the storage.
+    pub(crate) fn remove(&mut self, height: u32) {
+        self.generation += 1;
+        let _ = self.headers.remove(&height);
+    }
+
+    /// Drops the headers above the height, when the tip is moved to it.
+    pub(crate) fn remove_above(&mut self, height: u32) {
+        self.generation += 1;
+        let _ = self.headers.split_off(&(height + 1));
+    }
+
+    fn insert(&mut self, height: u32, header: Header) {
+        if self.capacity == 0 {
+            return;
+        }
+        let _ = self.headers.insert(height, header);
+        if self.headers.len() > self.capacity {
+            let _ = self.headers.pop_first();
+        }
+    }
 }
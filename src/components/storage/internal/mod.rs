@@ -2,14 +2,19 @@
 
 use std::{path::Path, sync::Arc};
 
+use bitcoin::consensus::deserialize;
+use ckb_bitcoin_spv_verifier::types::core::Header;
 use rocksdb::{
     prelude::{
-        GetColumnFamilys as _, GetPinned as _, GetPinnedCF as _, OpenCF as _, Put as _, PutCF as _,
+        CompactRange as _, CompactRangeCF as _, DeleteCF as _, Flush as _, FlushCF as _,
+        GetColumnFamilys as _, GetPinned as _, GetPinnedCF as _, IterateCF as _, OpenCF as _,
+        Put as _, PutCF as _,
     },
-    ColumnFamily, ColumnFamilyDescriptor, DBPinnableSlice, Options, DB,
+    ColumnFamily, ColumnFamilyDescriptor, DBPinnableSlice, Direction, IteratorMode, Options, DB,
 };
 
 use crate::components::storage::{
+    prelude::StorageReader,
     result::{Error, Result},
     schemas::columns::{self, Column},
 };
@@ -33,6 +38,7 @@ impl Storage {
             let mut cf_names = Vec::with_capacity(columns::COUNT);
             cf_names.push(columns::COLUMN_BITCOIN_HEADER_MMR.to_string());
             cf_names.push(columns::COLUMN_BITCOIN_HEADERS.to_string());
+            cf_names.push(columns::COLUMN_BITCOIN_BLOCK_HASH_TO_HEIGHT.to_string());
             cf_names
         };
         let cf_descriptors: Vec<_> = cf_names
@@ -57,6 +63,93 @@ impl Storage {
         Ok(storage)
     }
 
+    /// Caches this count of headers at the highest heights in memory.
+    ///
+    /// The cache is disabled when the size is 0, which is the default.
+    pub fn set_header_cache_size(&self, size: usize) -> Result<()> {
+        self.cache
+            .headers
+            .write()
+            .map_err(Error::storage)?
+            .set_capacity(size);
+        Ok(())
+    }
+
+    /// Returns the count of cached headers, the count of hits and the count of misses.
+    pub fn header_cache_stats(&self) -> Result<(usize, u64, u64)> {
+        let stats = self.cache.headers.read().map_err(Error::storage)?.stats();
+        Ok(stats)
+    }
+
+    /// Drops all cached headers, the capacity is kept.
+    pub fn clear_header_cache(&self) -> Result<()> {
+        self.cache.headers.write().map_err(Error::storage)?.clear();
+        Ok(())
+    }
+
+    /// Compacts the whole database, to reclaim the disk space.
+    pub fn compact(&self) -> Result<()> {
+        self.db.compact_range(None::<&[u8]>, None::<&[u8]>)?;
+        for col in [
+            columns::COLUMN_BITCOIN_HEADER_MMR,
+            columns::COLUMN_BITCOIN_HEADERS,
+            columns::COLUMN_BITCOIN_BLOCK_HASH_TO_HEIGHT,
+        ] {
+            let cf = cf_handle(&self.db, col)?;
+            self.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes all data in memory into the disk.
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        for col in [
+            columns::COLUMN_BITCOIN_HEADER_MMR,
+            columns::COLUMN_BITCOIN_HEADERS,
+            columns::COLUMN_BITCOIN_BLOCK_HASH_TO_HEIGHT,
+        ] {
+            let cf = cf_handle(&self.db, col)?;
+            self.db.flush_cf(cf)?;
+        }
+        Ok(())
+    }
+
+    /// Iterates the Bitcoin headers with their heights, from the base height
+    /// to the tip height, without loading all of them into memory.
+    ///
+    /// The headers above the tip height, which are left by rollbacks, are skipped.
+    pub fn iter_bitcoin_headers(&self) -> Result<impl Iterator<Item = Result<(u32, Header)>> + '_> {
+        let base_height = self
+            .get_base_bitcoin_height()?
+            .ok_or_else(|| Error::not_found("base bitcoin height"))?;
+        let tip_height = self.get_tip_bitcoin_height()?;
+        let cf = cf_handle(&self.db, columns::COLUMN_BITCOIN_HEADERS)?;
+        let start_key = base_height.to_be_bytes();
+        let mode = IteratorMode::From(&start_key, Direction::Forward);
+        let headers = self
+            .db
+            .iterator_cf(cf, mode)?
+            .map(|(key, value)| {
+                let height = <[u8; 4]>::try_from(&key[..])
+                    .map(u32::from_be_bytes)
+                    .map_err(|_| Error::data("the key of a header is not a height"))?;
+                let header = deserialize(&value).map_err(|err| {
+                    let msg =
+                        format!("failed to decode the header#{height} from storage since {err}");
+                    Error::data(msg)
+                })?;
+                Ok((height, header))
+            })
+            .take_while(move |result| {
+                result
+                    .as_ref()
+                    .map(|(height, _)| *height <= tip_height)
+                    .unwrap_or(true)
+            });
+        Ok(headers)
+    }
+
     pub(crate) fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<DBPinnableSlice>> {
         self.db.get_pinned(key.as_ref()).map_err(Into::into)
     }
@@ -87,6 +180,11 @@ impl Storage {
             .put_cf(cf, key.as_ref(), value.as_ref())
             .map_err(Into::into)
     }
+
+    pub(crate) fn delete_cf<K: AsRef<[u8]>>(&self, col: Column, key: K) -> Result<()> {
+        let cf = cf_handle(&self.db, col)?;
+        self.db.delete_cf(cf, key.as_ref()).map_err(Into::into)
+    }
 }
 
 pub(crate) fn cf_handle(db: &DB, col: Column) -> Result<&ColumnFamily> {
@@ -1,11 +1,18 @@
 //! Implement reading data from the storage.
 
-use bitcoin::consensus::deserialize;
-use ckb_bitcoin_spv_verifier::types::{core::Header, packed, prelude::*};
-use ckb_types::packed::{CellDep, CellDepReader, Script, ScriptReader};
+use bitcoin::{consensus::deserialize, hashes::Hash as _};
+use ckb_bitcoin_spv_verifier::types::{
+    core::{Hash, Header},
+    packed,
+    prelude::*,
+};
+use ckb_types::{
+    packed::{CellDep, CellDepReader, Script, ScriptReader},
+    H256,
+};
 
 use crate::components::storage::{
-    prelude::StorageReader,
+    prelude::{BitcoinSpvStorage as _, StorageReader},
     result::{Error, Result},
     schemas::{columns, keys},
     Storage,
@@ -44,9 +51,24 @@ impl StorageReader for Storage {
             .and_then(|opt| opt.ok_or_else(|| Error::not_found("tip bitcoin height")))
     }
 
+    fn get_pruned_bitcoin_height(&self) -> Result<Option<u32>> {
+        self.get(keys::PRUNED_BITCOIN_HEIGHT)?
+            .map(|raw| packed::Uint32Reader::from_slice(&raw).map(|reader| reader.unpack()))
+            .transpose()
+            .map_err(Into::into)
+    }
+
     fn get_bitcoin_header(&self, height: u32) -> Result<Header> {
+        let generation = {
+            let cache = self.cache.headers.read().map_err(Error::storage)?;
+            if let Some(header) = cache.get(height) {
+                return Ok(header);
+            }
+            cache.generation()
+        };
         let key = height.to_be_bytes();
-        self.get_cf(columns::COLUMN_BITCOIN_HEADERS, key)?
+        let header_opt = self
+            .get_cf(columns::COLUMN_BITCOIN_HEADERS, key)?
             .map(|raw| {
                 deserialize(&raw).map_err(|err| {
                     let msg =
@@ -54,8 +76,37 @@ impl StorageReader for Storage {
                     Error::data(msg)
                 })
             })
-            .transpose()
-            .and_then(|opt| opt.ok_or_else(|| Error::not_found(format!("header#{height}"))))
+            .transpose()?;
+        let header = match header_opt {
+            Some(header) => header,
+            None if self.is_bitcoin_header_pruned(height)? => {
+                let msg = format!("header#{height} is pruned from the storage");
+                return Err(Error::data(msg));
+            }
+            None => return Err(Error::not_found(format!("header#{height}"))),
+        };
+        self.cache
+            .headers
+            .write()
+            .map_err(Error::storage)?
+            .insert_if_unchanged(height, header, generation);
+        Ok(header)
+    }
+
+    fn get_height_by_hash(&self, hash: Hash) -> Result<Option<u32>> {
+        self.get_cf(
+            columns::COLUMN_BITCOIN_BLOCK_HASH_TO_HEIGHT,
+            hash.as_byte_array(),
+        )?
+        .map(|raw| {
+            <[u8; 4]>::try_from(&raw[..])
+                .map(u32::from_be_bytes)
+                .map_err(|err| {
+                    let msg = format!("failed to decode the height of {hash:#x} since {err}");
+                    Error::data(msg)
+                })
+        })
+        .transpose()
     }
 
     fn get_bitcoin_header_digest(&self, position: u64) -> Result<Option<packed::HeaderDigest>> {
@@ -84,4 +135,85 @@ impl StorageReader for Storage {
             .map_err(Into::into)
             .and_then(|opt| opt.ok_or_else(|| Error::not_found("the SPV script cell dep")))
     }
+
+    fn get_spv_owner_lock_script(&self) -> Result<Option<Script>> {
+        self.get(keys::SPV_OWNER_LOCK_SCRIPT)?
+            .map(|raw| ScriptReader::from_slice(&raw).map(|reader| reader.to_entity()))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn get_last_spv_tx_hash(&self) -> Result<Option<H256>> {
+        self.get(keys::LAST_SPV_TX_HASH)?
+            .map(|raw| {
+                H256::from_slice(&raw).map_err(|err| {
+                    let msg = format!("failed to decode the last SPV tx hash since {err}");
+                    Error::data(msg)
+                })
+            })
+            .transpose()
+    }
+
+    fn get_last_sync_timestamp(&self) -> Result<Option<u64>> {
+        self.get(keys::LAST_SYNC_TIMESTAMP)?
+            .map(|raw| {
+                <[u8; 8]>::try_from(&raw[..])
+                    .map(u64::from_be_bytes)
+                    .map_err(|err| {
+                        let msg = format!("failed to decode the last sync timestamp since {err}");
+                        Error::data(msg)
+                    })
+            })
+            .transpose()
+    }
+
+    fn get_last_ckb_balance(&self) -> Result<Option<u64>> {
+        self.get(keys::LAST_CKB_BALANCE)?
+            .map(|raw| {
+                <[u8; 8]>::try_from(&raw[..])
+                    .map(u64::from_be_bytes)
+                    .map_err(|err| {
+                        let msg = format!("failed to decode the last CKB balance since {err}");
+                        Error::data(msg)
+                    })
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ckb_types::{bytes::Bytes, core::ScriptHashType, h256, packed::Script, prelude::*};
+
+    use crate::{
+        components::storage::{
+            prelude::{StorageReader as _, StorageWriter as _},
+            Storage,
+        },
+        utilities::TempDir,
+    };
+
+    #[test]
+    fn test_spv_owner_lock_script_round_trip() {
+        let dir = TempDir::new("owner-lock");
+        let storage = Storage::new(dir.path()).unwrap();
+
+        assert!(storage.get_spv_owner_lock_script().unwrap().is_none());
+
+        let lock_script = Script::new_builder()
+            .code_hash(
+                h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8").pack(),
+            )
+            .hash_type(ScriptHashType::Type.into())
+            .args(Bytes::from(vec![0x42; 20]).pack())
+            .build();
+        storage
+            .put_spv_owner_lock_script(lock_script.clone())
+            .unwrap();
+        let loaded = storage.get_spv_owner_lock_script().unwrap();
+        assert_eq!(
+            loaded.as_ref().map(Script::as_slice),
+            Some(lock_script.as_slice())
+        );
+    }
 }
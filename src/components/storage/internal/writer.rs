@@ -1,8 +1,15 @@
 //! Implement writing data into the storage.
 
-use bitcoin::consensus::serialize;
-use ckb_bitcoin_spv_verifier::types::{core::Header, packed, prelude::*};
-use ckb_types::packed::{CellDep, Script};
+use bitcoin::{consensus::serialize, hashes::Hash as _};
+use ckb_bitcoin_spv_verifier::types::{
+    core::{Hash, Header},
+    packed,
+    prelude::*,
+};
+use ckb_types::{
+    packed::{CellDep, Script},
+    H256,
+};
 
 use crate::components::storage::{
     prelude::StorageWriter,
@@ -26,13 +33,44 @@ impl StorageWriter for Storage {
 
     fn put_tip_bitcoin_height(&self, height: u32) -> Result<()> {
         let value: packed::Uint32 = height.pack();
-        self.put(keys::TIP_BITCOIN_HEIGHT, value.as_slice())
+        // The headers above the tip are left by rollbacks, they will be overwritten.
+        let mut headers = self.cache.headers.write().map_err(Error::storage)?;
+        self.put(keys::TIP_BITCOIN_HEIGHT, value.as_slice())?;
+        headers.remove_above(height);
+        Ok(())
+    }
+
+    fn put_pruned_bitcoin_height(&self, height: u32) -> Result<()> {
+        let value: packed::Uint32 = height.pack();
+        self.put(keys::PRUNED_BITCOIN_HEIGHT, value.as_slice())
     }
 
     fn put_bitcoin_header(&self, height: u32, header: &Header) -> Result<()> {
         let key = height.to_be_bytes();
         let value = serialize(header);
-        self.put_cf(columns::COLUMN_BITCOIN_HEADERS, key, value)
+        let mut headers = self.cache.headers.write().map_err(Error::storage)?;
+        self.put_cf(columns::COLUMN_BITCOIN_HEADERS, key, value)?;
+        headers.update(height, *header);
+        self.put_cf(
+            columns::COLUMN_BITCOIN_BLOCK_HASH_TO_HEIGHT,
+            header.block_hash().as_byte_array(),
+            key,
+        )
+    }
+
+    fn delete_bitcoin_header(&self, height: u32) -> Result<()> {
+        let key = height.to_be_bytes();
+        let mut headers = self.cache.headers.write().map_err(Error::storage)?;
+        self.delete_cf(columns::COLUMN_BITCOIN_HEADERS, key)?;
+        headers.remove(height);
+        Ok(())
+    }
+
+    fn delete_height_by_hash(&self, hash: Hash) -> Result<()> {
+        self.delete_cf(
+            columns::COLUMN_BITCOIN_BLOCK_HASH_TO_HEIGHT,
+            hash.as_byte_array(),
+        )
     }
 
     fn put_bitcoin_header_digest(
@@ -51,4 +89,20 @@ impl StorageWriter for Storage {
     fn put_spv_contract_cell_dep(&self, cell_dep: CellDep) -> Result<()> {
         self.put(keys::SPV_CONTRACT_CELL_DEP, cell_dep.as_slice())
     }
+
+    fn put_spv_owner_lock_script(&self, lock_script: Script) -> Result<()> {
+        self.put(keys::SPV_OWNER_LOCK_SCRIPT, lock_script.as_slice())
+    }
+
+    fn put_last_spv_tx_hash(&self, tx_hash: &H256) -> Result<()> {
+        self.put(keys::LAST_SPV_TX_HASH, tx_hash.as_bytes())
+    }
+
+    fn put_last_sync_timestamp(&self, timestamp: u64) -> Result<()> {
+        self.put(keys::LAST_SYNC_TIMESTAMP, timestamp.to_be_bytes())
+    }
+
+    fn put_last_ckb_balance(&self, balance: u64) -> Result<()> {
+        self.put(keys::LAST_CKB_BALANCE, balance.to_be_bytes())
+    }
 }
@@ -12,7 +12,10 @@ use ckb_bitcoin_spv_verifier::{
         mmr::{self, ClientRootMMR},
     },
 };
-use ckb_types::packed::{CellDep, Script};
+use ckb_types::{
+    packed::{CellDep, Script},
+    H256,
+};
 
 use crate::components::storage::{
     result::{Error, Result},
@@ -24,12 +27,19 @@ pub(crate) trait StorageReader: Send + Sync + Sized {
     fn get_base_bitcoin_height(&self) -> Result<Option<u32>>;
     // Store Bitcoin state
     fn get_tip_bitcoin_height(&self) -> Result<u32>;
+    fn get_pruned_bitcoin_height(&self) -> Result<Option<u32>>;
     fn get_bitcoin_header(&self, height: u32) -> Result<Header>;
+    fn get_height_by_hash(&self, hash: Hash) -> Result<Option<u32>>;
     // For MMR
     fn get_bitcoin_header_digest(&self, position: u64) -> Result<Option<packed::HeaderDigest>>;
     // For CKB transactions
     fn get_spv_contract_type_script(&self) -> Result<Script>;
     fn get_spv_contract_cell_dep(&self) -> Result<CellDep>;
+    fn get_spv_owner_lock_script(&self) -> Result<Option<Script>>;
+    // For observability
+    fn get_last_spv_tx_hash(&self) -> Result<Option<H256>>;
+    fn get_last_sync_timestamp(&self) -> Result<Option<u64>>;
+    fn get_last_ckb_balance(&self) -> Result<Option<u64>>;
 }
 
 pub(crate) trait StorageWriter: Send + Sync + Sized {
@@ -37,13 +47,21 @@ pub(crate) trait StorageWriter: Send + Sync + Sized {
     fn put_base_bitcoin_height(&self, height: u32) -> Result<()>;
     // Store Bitcoin state
     fn put_tip_bitcoin_height(&self, height: u32) -> Result<()>;
+    fn put_pruned_bitcoin_height(&self, height: u32) -> Result<()>;
     fn put_bitcoin_header(&self, height: u32, header: &Header) -> Result<()>;
+    fn delete_bitcoin_header(&self, height: u32) -> Result<()>;
+    fn delete_height_by_hash(&self, hash: Hash) -> Result<()>;
     // For MMR
     fn put_bitcoin_header_digest(&self, position: u64, digest: &packed::HeaderDigest)
         -> Result<()>;
     // For CKB transactions
     fn put_spv_contract_type_script(&self, type_script: Script) -> Result<()>;
     fn put_spv_contract_cell_dep(&self, cell_dep: CellDep) -> Result<()>;
+    fn put_spv_owner_lock_script(&self, lock_script: Script) -> Result<()>;
+    // For observability
+    fn put_last_spv_tx_hash(&self, tx_hash: &H256) -> Result<()>;
+    fn put_last_sync_timestamp(&self, timestamp: u64) -> Result<()>;
+    fn put_last_ckb_balance(&self, balance: u64) -> Result<()>;
 }
 
 // Private APIs: for internal use.
@@ -232,6 +250,12 @@ pub(crate) trait BitcoinSpvStorage: InternalBitcoinSpvStorage {
         Ok(mmr_root)
     }
     fn generate_headers_proof(&self, tip_height: u32, heights: Vec<u32>) -> Result<MmrProof> {
+        for height in &heights {
+            if self.is_bitcoin_header_pruned(*height)? {
+                let msg = format!("header#{height} is pruned from the storage");
+                return Err(Error::data(msg));
+            }
+        }
         let (base_height, mmr) = self.chain_root_mmr(tip_height)?;
         let positions = heights
             .into_iter()
@@ -250,14 +274,25 @@ pub(crate) trait BitcoinSpvStorage: InternalBitcoinSpvStorage {
     }
 
     fn rollback_to(&self, height_opt: Option<u32>) -> Result<()> {
-        if let Some(height) = height_opt {
-            self.put_tip_bitcoin_height(height)?;
+        let height = if let Some(height) = height_opt {
+            height
         } else if let Some(height) = self.get_base_bitcoin_height()? {
-            self.put_tip_bitcoin_height(height)?;
+            height
         } else {
             return Err(Error::data("don't rollback on an empty storage"));
+        };
+        if self.is_bitcoin_header_pruned(height)? {
+            let msg = format!("don't rollback to header#{height}, which is pruned");
+            return Err(Error::data(msg));
         }
-        Ok(())
+        // The rolled back headers are left in the storage until they are
+        // overwritten, but their hashes should not be found any more.
+        let tip_height = self.get_tip_bitcoin_height()?;
+        for rolled_back_height in (height + 1)..=tip_height {
+            let hash = self.bitcoin_header_hash(rolled_back_height)?;
+            self.delete_height_by_hash(hash)?;
+        }
+        self.put_tip_bitcoin_height(height)
     }
 
     fn base_state(&self) -> Result<(u32, Header)> {
@@ -276,11 +311,112 @@ pub(crate) trait BitcoinSpvStorage: InternalBitcoinSpvStorage {
         })
     }
 
+    fn bitcoin_header(&self, height: u32) -> Result<Header> {
+        self.get_bitcoin_header(height)
+    }
+
     fn bitcoin_header_hash(&self, height: u32) -> Result<Hash> {
         self.get_bitcoin_header(height)
             .map(|header| header.block_hash().into())
     }
 
+    /// Checks that the tip header chains to its parent header.
+    fn assert_tip_header_chained(&self) -> Result<()> {
+        let (base_height, _) = self.base_state()?;
+        let (tip_height, tip_header) = self.tip_state()?;
+        if tip_height == base_height {
+            return Ok(());
+        }
+        let parent_height = tip_height - 1;
+        let parent_hash = self.get_bitcoin_header(parent_height)?.block_hash();
+        if tip_header.prev_blockhash != parent_hash {
+            let msg = format!(
+                "the header chain in storage is broken, the parent of header#{tip_height} \
+                is {:#x}, but header#{parent_height} is {parent_hash:#x}",
+                tip_header.prev_blockhash
+            );
+            return Err(Error::data(msg));
+        }
+        Ok(())
+    }
+
+    /// Checks whether the header at the height is pruned.
+    ///
+    /// The base header is never pruned.
+    fn is_bitcoin_header_pruned(&self, height: u32) -> Result<bool> {
+        let base_height = self
+            .get_base_bitcoin_height()
+            .and_then(|opt| opt.ok_or_else(|| Error::not_found("base bitcoin height")))?;
+        let is_pruned = self
+            .get_pruned_bitcoin_height()?
+            .map(|pruned_height| height > base_height && height < pruned_height)
+            .unwrap_or(false);
+        Ok(is_pruned)
+    }
+
+    /// Prunes the headers below the height, except the base header, to bound
+    /// the growth of the storage.
+    ///
+    /// The MMR is kept intact, since the roots and the proofs of the kept
+    /// headers depend on its nodes.
+    ///
+    /// Returns the count of pruned headers.
+    fn prune_headers_below(&self, height: u32) -> Result<u32> {
+        let base_height = self
+            .get_base_bitcoin_height()
+            .and_then(|opt| opt.ok_or_else(|| Error::not_found("base bitcoin height")))?;
+        let tip_height = self.get_tip_bitcoin_height()?;
+        let start_height = self.get_pruned_bitcoin_height()?.unwrap_or(base_height + 1);
+        // The tip header is never pruned.
+        let end_height = height.min(tip_height);
+        if end_height <= start_height {
+            return Ok(0);
+        }
+        // Mark the headers as pruned at first, an interrupted pruning only
+        // leaves some of them in the storage.
+        self.put_pruned_bitcoin_height(end_height)?;
+        for height in start_height..end_height {
+            let hash = self.bitcoin_header_hash(height)?;
+            self.delete_height_by_hash(hash)?;
+            self.delete_bitcoin_header(height)?;
+        }
+        Ok(end_height - start_height)
+    }
+
+    /// Returns the height of a header in the storage by its hash.
+    fn bitcoin_height(&self, hash: &Hash) -> Result<Option<u32>> {
+        let tip_height = self.get_tip_bitcoin_height()?;
+        match self.get_height_by_hash(*hash)? {
+            // Check the header, in case the index is left by an interrupted rollback.
+            Some(height) if height <= tip_height && self.bitcoin_header_hash(height)? == *hash => {
+                Ok(Some(height))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Finds the height of a header by its hash.
+    ///
+    /// Storages which are initialized by old versions don't index all headers,
+    /// so the recent headers, which are not more than the limit below the tip,
+    /// are also looked up one by one.
+    fn find_bitcoin_height(&self, hash: &Hash, limit: u32) -> Result<Option<u32>> {
+        if let Some(height) = self.bitcoin_height(hash)? {
+            return Ok(Some(height));
+        }
+        let base_height = self
+            .get_base_bitcoin_height()
+            .and_then(|opt| opt.ok_or_else(|| Error::not_found("base bitcoin height")))?;
+        let tip_height = self.get_tip_bitcoin_height()?;
+        let start_height = tip_height.saturating_sub(limit).max(base_height);
+        for height in (start_height..=tip_height).rev() {
+            if self.bitcoin_header_hash(height)? == *hash {
+                return Ok(Some(height));
+            }
+        }
+        Ok(None)
+    }
+
     fn spv_contract_type_script(&self) -> Result<Script> {
         self.get_spv_contract_type_script()
     }
@@ -288,7 +424,171 @@ pub(crate) trait BitcoinSpvStorage: InternalBitcoinSpvStorage {
     fn spv_contract_cell_dep(&self) -> Result<CellDep> {
         self.get_spv_contract_cell_dep()
     }
+
+    fn save_spv_owner_lock(&self, lock_script: Script) -> Result<()> {
+        self.put_spv_owner_lock_script(lock_script)
+    }
+
+    /// Returns the lock script of SPV cells which is saved when initializing.
+    ///
+    /// Storages which are initialized by old versions don't have it.
+    fn spv_owner_lock(&self) -> Result<Option<Script>> {
+        self.get_spv_owner_lock_script()
+    }
+
+    fn save_last_spv_tx_hash(&self, tx_hash: &H256) -> Result<()> {
+        self.put_last_spv_tx_hash(tx_hash)
+    }
+
+    fn last_spv_tx_hash(&self) -> Result<Option<H256>> {
+        self.get_last_spv_tx_hash()
+    }
+
+    fn save_last_sync_timestamp(&self, timestamp: u64) -> Result<()> {
+        self.put_last_sync_timestamp(timestamp)
+    }
+
+    fn last_sync_timestamp(&self) -> Result<Option<u64>> {
+        self.get_last_sync_timestamp()
+    }
+
+    fn save_last_ckb_balance(&self, balance: u64) -> Result<()> {
+        self.put_last_ckb_balance(balance)
+    }
+
+    fn last_ckb_balance(&self) -> Result<Option<u64>> {
+        self.get_last_ckb_balance()
+    }
 }
 
 impl InternalBitcoinSpvStorage for Storage {}
 impl BitcoinSpvStorage for Storage {}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{constants::genesis_block, Network};
+    use ckb_bitcoin_spv_verifier::types::core::{Hash, Header};
+
+    use super::{BitcoinSpvStorage as _, StorageWriter as _};
+    use crate::{components::storage::Storage, utilities::TempDir};
+
+    // Only the continuity of headers is checked by the storage, so the headers
+    // are derived from the provided header, with different nonces.
+    fn derive_headers(mut header: Header, nonce_base: u32, count: u32) -> Vec<Header> {
+        let mut headers = Vec::new();
+        for nonce in nonce_base..nonce_base + count {
+            header.prev_blockhash = header.block_hash();
+            header.nonce = nonce;
+            headers.push(header);
+        }
+        headers
+    }
+
+    // Initializes the storage at the base height, then appends `count` headers.
+    //
+    // Returns the hashes of all headers, starting from the base header.
+    fn init_storage(storage: &Storage, base_height: u32, count: u32) -> Vec<Hash> {
+        let base_header = genesis_block(Network::Bitcoin).header;
+        storage.initialize_with(base_height, base_header).unwrap();
+        let headers = derive_headers(base_header, 0, count);
+        storage.append_headers(headers.clone()).unwrap();
+        [base_header]
+            .iter()
+            .chain(&headers)
+            .map(|header| header.block_hash().into())
+            .collect()
+    }
+
+    #[test]
+    fn test_prune_headers_below() {
+        let dir = TempDir::new("storage-prune");
+        let storage = Storage::new(dir.path()).unwrap();
+        let hashes = init_storage(&storage, 100, 10);
+
+        assert_eq!(storage.prune_headers_below(105).unwrap(), 4);
+        // The base header is never pruned.
+        assert!(!storage.is_bitcoin_header_pruned(100).unwrap());
+        assert_eq!(storage.bitcoin_height(&hashes[0]).unwrap(), Some(100));
+        for height in 101..105 {
+            assert!(storage.is_bitcoin_header_pruned(height).unwrap());
+            assert!(storage.bitcoin_header(height).is_err());
+            let hash = &hashes[(height - 100) as usize];
+            assert_eq!(storage.bitcoin_height(hash).unwrap(), None);
+        }
+        assert!(!storage.is_bitcoin_header_pruned(105).unwrap());
+        assert_eq!(storage.bitcoin_height(&hashes[5]).unwrap(), Some(105));
+
+        // Nothing more to prune.
+        assert_eq!(storage.prune_headers_below(105).unwrap(), 0);
+        assert_eq!(storage.prune_headers_below(103).unwrap(), 0);
+        // The tip header is never pruned.
+        assert_eq!(storage.prune_headers_below(200).unwrap(), 5);
+        assert_eq!(storage.tip_state().unwrap().0, 110);
+
+        // Don't rollback to a pruned header.
+        assert!(storage.rollback_to(Some(103)).is_err());
+    }
+
+    #[test]
+    fn test_rollback_to() {
+        let dir = TempDir::new("storage-rollback");
+        let storage = Storage::new(dir.path()).unwrap();
+        let hashes = init_storage(&storage, 100, 10);
+
+        storage.rollback_to(Some(105)).unwrap();
+        assert_eq!(storage.tip_state().unwrap().0, 105);
+        assert!(storage.assert_tip_header_chained().is_ok());
+        assert_eq!(storage.bitcoin_height(&hashes[5]).unwrap(), Some(105));
+        for hash in &hashes[6..] {
+            assert_eq!(storage.bitcoin_height(hash).unwrap(), None);
+        }
+
+        // Append a fork, the rolled back headers are overwritten.
+        let tip_header = storage.bitcoin_header(105).unwrap();
+        let fork = derive_headers(tip_header, 1000, 3);
+        let (tip_height, _) = storage.append_headers(fork.clone()).unwrap();
+        assert_eq!(tip_height, 108);
+        assert!(storage.assert_tip_header_chained().is_ok());
+        for (height, header) in (106..).zip(&fork) {
+            let hash = header.block_hash().into();
+            assert_eq!(storage.bitcoin_height(&hash).unwrap(), Some(height));
+        }
+        assert_eq!(storage.bitcoin_height(&hashes[7]).unwrap(), None);
+
+        // Rollback to the base header by default.
+        storage.rollback_to(None).unwrap();
+        assert_eq!(storage.tip_state().unwrap().0, 100);
+        assert_eq!(storage.bitcoin_height(&hashes[0]).unwrap(), Some(100));
+    }
+
+    #[test]
+    fn test_find_bitcoin_height() {
+        let dir = TempDir::new("storage-index");
+        let storage = Storage::new(dir.path()).unwrap();
+        let hashes = init_storage(&storage, 100, 10);
+
+        for (height, hash) in (100..).zip(&hashes) {
+            assert_eq!(storage.bitcoin_height(hash).unwrap(), Some(height));
+        }
+        let unknown_hash = genesis_block(Network::Testnet).block_hash().into();
+        assert_eq!(storage.bitcoin_height(&unknown_hash).unwrap(), None);
+        assert_eq!(
+            storage.find_bitcoin_height(&unknown_hash, 10).unwrap(),
+            None
+        );
+
+        // The headers which are not indexed, are looked up near the tip.
+        storage.delete_height_by_hash(hashes[8]).unwrap();
+        assert_eq!(storage.bitcoin_height(&hashes[8]).unwrap(), None);
+        assert_eq!(
+            storage.find_bitcoin_height(&hashes[8], 5).unwrap(),
+            Some(108)
+        );
+        assert_eq!(storage.find_bitcoin_height(&hashes[8], 1).unwrap(), None);
+
+        // The index which is left by an interrupted rollback is ignored.
+        storage.put_tip_bitcoin_height(105).unwrap();
+        assert_eq!(storage.bitcoin_height(&hashes[7]).unwrap(), None);
+        assert_eq!(storage.bitcoin_height(&hashes[5]).unwrap(), Some(105));
+    }
+}
@@ -4,10 +4,19 @@
 pub type Column = &'static str;
 
 /// Total column number
-pub const COUNT: usize = 2;
+pub const COUNT: usize = 3;
 
 /// Column to store MMR for Bitcoin headers
 pub const COLUMN_BITCOIN_HEADER_MMR: Column = "bitcoin-header-mmr";
 
 /// Column to store Bitcoin headers
+///
+/// The keys are heights in big-endian, and the values are headers in their
+/// canonical 80-byte consensus encoding, without any extra framing.
 pub const COLUMN_BITCOIN_HEADERS: Column = "bitcoin-headers";
+
+/// Column to index the heights of Bitcoin headers by their block hashes
+///
+/// The keys are block hashes in their internal byte order, and the values are
+/// heights in big-endian.
+pub const COLUMN_BITCOIN_BLOCK_HASH_TO_HEIGHT: Column = "bitcoin-block-hash-to-height";
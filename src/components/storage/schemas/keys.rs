@@ -7,8 +7,19 @@
 pub const BASE_BITCOIN_HEIGHT: &[u8] = b"base-bitcoin-height";
 /// The height of the tip Bitcoin header.
 pub const TIP_BITCOIN_HEIGHT: &[u8] = b"tip-bitcoin-height";
+/// The Bitcoin headers between the base height and this height (exclusive) are pruned.
+pub const PRUNED_BITCOIN_HEIGHT: &[u8] = b"pruned-bitcoin-height";
 
 /// The type script of the Bitcoin SPV contract.
 pub const SPV_CONTRACT_TYPE_SCRIPT: &[u8] = b"spv-contract-type-script";
 /// The cell dep of the Bitcoin SPV contract.
 pub const SPV_CONTRACT_CELL_DEP: &[u8] = b"spv-contract-cell-dep";
+/// The lock script of the Bitcoin SPV cells.
+pub const SPV_OWNER_LOCK_SCRIPT: &[u8] = b"spv-owner-lock-script";
+
+/// The hash of the last transaction which updated the Bitcoin SPV cells.
+pub const LAST_SPV_TX_HASH: &[u8] = b"last-spv-tx-hash";
+/// The timestamp (in seconds) when the storage was synced with Bitcoin at last.
+pub const LAST_SYNC_TIMESTAMP: &[u8] = b"last-sync-timestamp";
+/// The total capacity (in shannons) of the deployer, which was checked at last.
+pub const LAST_CKB_BALANCE: &[u8] = b"last-ckb-balance";
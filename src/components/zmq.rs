@@ -0,0 +1,179 @@
+//! Subscribe the notifications of new blocks from a Bitcoin node through ZeroMQ.
+//!
+//! Only the minimal part of ZMTP 3.0 is implemented, which is enough to
+//! receive the "hashblock" notifications from Bitcoin Core, without the
+//! library of ZeroMQ.
+//!
+//! Ref: <https://github.com/bitcoin/bitcoin/blob/master/doc/zmq.md>
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::result::{Error, Result};
+
+const TOPIC_HASHBLOCK: &[u8] = b"hashblock";
+// Wait for a while before reconnecting, when the connection is lost.
+const RECONNECT_INTERVAL_SECS: u64 = 5;
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+/// Wakes up the waiters when a new block is notified by the Bitcoin node.
+#[derive(Clone, Default)]
+pub struct BlockNotifier {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl BlockNotifier {
+    /// Subscribes the "hashblock" notifications from the endpoint in background,
+    /// such as "tcp://127.0.0.1:28332".
+    pub fn subscribe(endpoint: &str) -> Result<Self> {
+        let address = endpoint
+            .strip_prefix("tcp://")
+            .ok_or_else(|| {
+                let msg = format!("only TCP is supported for ZMQ, but got \"{endpoint}\"");
+                Error::other(msg)
+            })?
+            .to_owned();
+        let notifier = Self::default();
+        let notifier_cloned = notifier.clone();
+        thread::spawn(move || loop {
+            if let Err(err) = notifier_cloned.receive_blocks(&address) {
+                log::warn!(
+                    "[zmq] failed to receive new blocks from {address} since {err}, \
+                    reconnect after {RECONNECT_INTERVAL_SECS} seconds"
+                );
+            }
+            thread::sleep(Duration::from_secs(RECONNECT_INTERVAL_SECS));
+        });
+        Ok(notifier)
+    }
+
+    /// Waits until a new block is notified, or the timeout elapsed.
+    ///
+    /// Returns `true` if a new block is notified.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (lock, condvar) = &*self.inner;
+        let notified = lock.lock().unwrap_or_else(|err| err.into_inner());
+        let (mut notified, _) = condvar
+            .wait_timeout_while(notified, timeout, |notified| !*notified)
+            .unwrap_or_else(|err| err.into_inner());
+        let is_notified = *notified;
+        *notified = false;
+        is_notified
+    }
+
+    fn notify(&self) {
+        let (lock, condvar) = &*self.inner;
+        let mut notified = lock.lock().unwrap_or_else(|err| err.into_inner());
+        *notified = true;
+        condvar.notify_all();
+    }
+
+    fn receive_blocks(&self, address: &str) -> Result<()> {
+        let mut stream = TcpStream::connect(address).map_err(Error::other)?;
+        handshake(&mut stream)?;
+        log::info!("[zmq] subscribed new blocks from {address}");
+        loop {
+            let message = read_message(&mut stream)?;
+            if message.first().map(Vec::as_slice) == Some(TOPIC_HASHBLOCK) {
+                if let Some(hash) = message.get(1) {
+                    log::debug!("[zmq] new block {}", faster_hex::hex_string(hash));
+                }
+                self.notify();
+            }
+        }
+    }
+}
+
+// Exchanges the greetings and the READY commands, then subscribes the topic.
+fn handshake(stream: &mut TcpStream) -> Result<()> {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    greeting[10] = 3; // Major version.
+    greeting[11] = 0; // Minor version.
+    greeting[12..16].copy_from_slice(b"NULL");
+    stream.write_all(&greeting).map_err(Error::other)?;
+
+    let mut peer_greeting = [0u8; 64];
+    stream
+        .read_exact(&mut peer_greeting)
+        .map_err(Error::other)?;
+    if peer_greeting[0] != 0xff || peer_greeting[9] != 0x7f || peer_greeting[10] < 3 {
+        return Err(Error::other("the peer doesn't speak ZMTP 3"));
+    }
+
+    let mut ready = vec![5];
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&3u32.to_be_bytes());
+    ready.extend_from_slice(b"SUB");
+    write_frame(stream, FLAG_COMMAND, &ready)?;
+    // Skip the READY command from the peer.
+    let _ = read_frame(stream)?;
+
+    let mut subscription = vec![1];
+    subscription.extend_from_slice(TOPIC_HASHBLOCK);
+    write_frame(stream, 0, &subscription)
+}
+
+fn write_frame(stream: &mut TcpStream, flags: u8, body: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(body.len() + 9);
+    if let Ok(size) = u8::try_from(body.len()) {
+        frame.push(flags);
+        frame.push(size);
+    } else {
+        frame.push(flags | FLAG_LONG);
+        frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(body);
+    stream.write_all(&frame).map_err(Error::other)
+}
+
+// Returns the flags and the body of a frame.
+fn read_frame(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    let mut flags = [0u8; 1];
+    stream.read_exact(&mut flags).map_err(Error::other)?;
+    let flags = flags[0];
+    let size = if flags & FLAG_LONG == FLAG_LONG {
+        let mut size = [0u8; 8];
+        stream.read_exact(&mut size).map_err(Error::other)?;
+        u64::from_be_bytes(size)
+    } else {
+        let mut size = [0u8; 1];
+        stream.read_exact(&mut size).map_err(Error::other)?;
+        u64::from(size[0])
+    };
+    let mut body = Vec::new();
+    stream
+        .take(size)
+        .read_to_end(&mut body)
+        .map_err(Error::other)?;
+    if body.len() as u64 != size {
+        return Err(Error::other("the connection is closed in a frame"));
+    }
+    Ok((flags, body))
+}
+
+// Reads all frames of a message, commands from the peer are skipped.
+fn read_message(stream: &mut TcpStream) -> Result<Vec<Vec<u8>>> {
+    let mut message = Vec::new();
+    loop {
+        let (flags, body) = read_frame(stream)?;
+        if flags & FLAG_COMMAND == FLAG_COMMAND {
+            continue;
+        }
+        message.push(body);
+        if flags & FLAG_MORE == 0 {
+            return Ok(message);
+        }
+    }
+}
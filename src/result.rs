@@ -22,6 +22,9 @@ pub enum Error {
     #[error("storage error: {0}")]
     Storage(#[from] StorageError),
 
+    #[error("the count of SPV cells is incorrect, expect {expected} but got {actual}")]
+    SpvCellsCountMismatch { expected: usize, actual: usize },
+
     #[error("error: {0}")]
     Other(String),
 }
@@ -51,3 +54,14 @@ pub enum BtcRpcError {
 }
 
 pub type BtcRpcResult<T> = ::std::result::Result<T, BtcRpcError>;
+
+impl BtcRpcError {
+    /// Returns the JSON-RPC error code which is returned by the Bitcoin node.
+    pub fn rpc_code(&self) -> Option<i64> {
+        if let Self::Rpc(err) = self {
+            Some(err.code.code())
+        } else {
+            None
+        }
+    }
+}
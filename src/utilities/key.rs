@@ -1,6 +1,6 @@
 //! Structs for sensitive data.
 
-use std::{ffi::OsStr, fmt};
+use std::{env, ffi::OsStr, fmt};
 
 use clap::{
     builder::{TypedValueParser, ValueParserFactory},
@@ -73,3 +73,56 @@ impl TypedValueParser for Key256BitsValueParser {
         Ok(Key256Bits(arr))
     }
 }
+
+/// Reads a 256 bits key from an environment variable, whose name is the input
+/// value, and the key is hex-encoded in it, with or without the prefix "0x".
+#[derive(Clone, Debug)]
+pub struct Key256BitsEnvValueParser;
+
+impl TypedValueParser for Key256BitsEnvValueParser {
+    type Value = Key256Bits;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        let mut err = clap::Error::new(ErrorKind::InvalidValue).with_cmd(cmd);
+        if let Some(arg) = arg {
+            err.insert(
+                ContextKind::InvalidArg,
+                ContextValue::String(arg.to_string()),
+            );
+        }
+        let mut hex_str = match env::var(value) {
+            Ok(hex_str) => hex_str,
+            Err(env_err) => {
+                let msg = format!(
+                    "failed to read environment variable \"{}\" since {env_err}",
+                    value.to_string_lossy()
+                );
+                err.insert(ContextKind::InvalidValue, ContextValue::String(msg));
+                return Err(err);
+            }
+        };
+        let mut arr = [0u8; 32];
+        let hex_bytes = hex_str.trim().trim_start_matches("0x").as_bytes();
+        let result = if hex_bytes.len() == 64 {
+            faster_hex::hex_decode(hex_bytes, &mut arr).is_ok()
+        } else {
+            false
+        };
+        hex_str.zeroize();
+        if !result {
+            arr.zeroize();
+            let msg = format!(
+                "environment variable \"{}\" doesn't contain a hex-encoded 256 bits key",
+                value.to_string_lossy()
+            );
+            err.insert(ContextKind::InvalidValue, ContextValue::String(msg));
+            return Err(err);
+        }
+        Ok(Key256Bits(arr))
+    }
+}
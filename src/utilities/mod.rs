@@ -2,9 +2,13 @@
 
 mod key;
 mod platform;
+#[cfg(test)]
+mod temp_dir;
 mod type_id;
 pub(crate) mod value_parsers;
 
-pub(crate) use key::Key256Bits;
-pub(crate) use platform::try_raise_fd_limit;
+pub(crate) use key::{Key256Bits, Key256BitsEnvValueParser};
+pub(crate) use platform::{listen_shutdown_signal, try_raise_fd_limit, wait_unless_shutdown};
+#[cfg(test)]
+pub(crate) use temp_dir::TempDir;
 pub(crate) use type_id::calculate_type_id;
@@ -1,5 +1,15 @@
 //! Enhance the platform environment for continuously running services.
 
+use std::{
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
 use fdlimit::{raise_fd_limit, Outcome};
 
 pub fn try_raise_fd_limit() {
@@ -15,3 +25,86 @@ pub fn try_raise_fd_limit() {
         }
     }
 }
+
+// How long to wait in each step, so the shutdown signal is handled in time.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Waits for the duration in short steps, stops early once the shutdown flag
+/// is set or a step returns `true`.
+///
+/// Returns `true` if a step returned `true`.
+pub fn wait_unless_shutdown<F>(shutdown: &AtomicBool, duration: Duration, mut wait_step: F) -> bool
+where
+    F: FnMut(Duration) -> bool,
+{
+    let deadline = Instant::now() + duration;
+    while !shutdown.load(Ordering::SeqCst) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        if wait_step(remaining.min(SHUTDOWN_CHECK_INTERVAL)) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Listens Ctrl-C (and SIGTERM on Unix) in background, and sets the returned
+/// flag when it's received, so services could stop at a safe point.
+///
+/// A second signal exits the process immediately.
+pub fn listen_shutdown_signal() -> Arc<AtomicBool> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_cloned = Arc::clone(&shutdown);
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(err) => {
+                log::error!("failed to listen the shutdown signal, since {err}");
+                return;
+            }
+        };
+        runtime.block_on(async {
+            wait_for_shutdown_signal().await;
+            log::info!("Received the shutdown signal, stop at the next safe point");
+            shutdown_cloned.store(true, Ordering::SeqCst);
+            wait_for_shutdown_signal().await;
+            log::warn!("Received the shutdown signal again, exit immediately");
+            process::exit(130);
+        });
+    });
+    shutdown
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = wait_for_ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(err) => {
+            log::warn!("failed to listen SIGTERM, since {err}");
+            wait_for_ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    wait_for_ctrl_c().await;
+}
+
+async fn wait_for_ctrl_c() {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        log::warn!("failed to listen Ctrl-C, since {err}");
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,35 @@
+//! A temporary directory for tests.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// A temporary directory, which is removed when it's dropped, even if the
+/// test panics.
+///
+/// Drop it after everything which opens the directory, such as the storage.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!(
+            "ckb-bitcoin-spv-service-test-{name}-{}",
+            process::id()
+        ));
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            log::warn!("failed to remove \"{}\" since {err}", self.0.display());
+        }
+    }
+}